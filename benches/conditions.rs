@@ -0,0 +1,52 @@
+extern crate pp_rs;
+
+use pp_rs::pp::{Preprocessor, PreprocessorOptions};
+use std::time::{Duration, Instant};
+
+const ITERATIONS: u32 = 50;
+
+const PROLOGUE: &str = "#define FEATURE_A 1
+#define FEATURE_B 0
+#define QUALITY 3
+#define ENABLED(x, level) ((x) && QUALITY >= (level))
+";
+
+const BLOCK: &str = "#if ENABLED(FEATURE_A, 2) && !ENABLED(FEATURE_B, 1) || defined(FORCE_ALL)
+color += texture(u_tex, v_uv) * 0.5;
+#endif
+";
+
+fn time(mut f: impl FnMut()) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    start.elapsed() / ITERATIONS
+}
+
+fn preprocess(input: &str, cache_conditions: bool) -> usize {
+    let options = PreprocessorOptions {
+        cache_conditions,
+        ..Default::default()
+    };
+    let mut count = 0;
+    for item in Preprocessor::with_options(input, options) {
+        item.unwrap();
+        count += 1;
+    }
+    count
+}
+
+fn main() {
+    let input = format!("{}{}", PROLOGUE, BLOCK.repeat(2000));
+
+    let uncached = time(|| {
+        assert!(preprocess(&input, false) > 1);
+    });
+    let cached = time(|| {
+        assert!(preprocess(&input, true) > 1);
+    });
+
+    println!("uncached: {:?} per iteration", uncached);
+    println!("cached: {:?} per iteration", cached);
+}
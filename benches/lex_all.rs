@@ -0,0 +1,48 @@
+extern crate pp_rs;
+
+use pp_rs::lexer::Lexer;
+use std::time::{Duration, Instant};
+
+const ITERATIONS: u32 = 200;
+
+const SHADER: &str = "#version 450
+layout(location = 0) in vec3 a_position;
+layout(location = 1) in vec2 a_uv;
+layout(location = 0) out vec2 v_uv;
+
+layout(set = 0, binding = 0) uniform Globals {
+    mat4 u_view_proj;
+    float u_time;
+};
+
+void main() {
+    // Wobble the vertices a bit
+    vec3 pos = a_position + vec3(0.0, sin(u_time + a_position.x) * .25, 0.0);
+    v_uv = a_uv * 2.0 - 1.0;
+    gl_Position = u_view_proj * vec4(pos, 1.0);
+}
+";
+
+fn time(mut f: impl FnMut()) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    start.elapsed() / ITERATIONS
+}
+
+fn main() {
+    let input = SHADER.repeat(100);
+
+    let collect = time(|| {
+        let tokens: Result<Vec<_>, _> = Lexer::new(&input).collect();
+        assert!(tokens.unwrap().len() > 1);
+    });
+    let lex_all = time(|| {
+        let tokens = Lexer::lex_all(&input);
+        assert!(tokens.unwrap().len() > 1);
+    });
+
+    println!("collect: {:?} per iteration", collect);
+    println!("lex_all: {:?} per iteration", lex_all);
+}
@@ -1,5 +1,12 @@
-use crate::token::{Float, Integer, Location, PreprocessorError, Punct};
-use std::iter::Peekable;
+use crate::token::{self, Float, Integer, Location, PreprocessorError, Punct, Span};
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
+use alloc::rc::Rc;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::cell::{Ref, RefCell};
+use core::iter::{FusedIterator, Peekable};
+use core::str::{Chars, FromStr};
 
 type CharAndLocation = (char, Location);
 
@@ -111,6 +118,206 @@ impl<'a> Iterator for SkipBackslashNewline<'a> {
     }
 }
 
+/// Splits the input in logical lines, that is with the backslash-newline continuations joined,
+/// returning each line with the location of its first character. Newlines are not included.
+pub fn logical_lines<'a>(input: &'a str) -> impl Iterator<Item = (Location, String)> + 'a {
+    let mut chars = SkipBackslashNewline::new(input).peekable();
+    core::iter::from_fn(move || {
+        let &(_, start) = chars.peek()?;
+        let line = chars
+            .by_ref()
+            .map(|(c, _)| c)
+            .take_while(|&c| c != '\n')
+            .collect();
+        Some((start, line))
+    })
+}
+
+/// The escape sequences of the host language string literal a shader is embedded in, see
+/// [`unescape_embedded`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EscapeStyle {
+    /// C and C++ string literals: `\n`, `\t`, `\r`, `\0`, `\"`, `\'` and `\\`.
+    C,
+    /// JSON strings: `\n`, `\t`, `\r`, `\b`, `\f`, `\"`, `\/`, `\\` and `\uXXXX`, including
+    /// surrogate pairs.
+    Json,
+}
+
+/// Replaces the escape sequences of a shader extracted from a host language string literal, so
+/// that it can be lexed. Unknown or invalid escape sequences are kept as written. The input is
+/// returned borrowed when it doesn't contain any backslash.
+pub fn unescape_embedded<'a>(input: &'a str, style: EscapeStyle) -> Cow<'a, str> {
+    if !input.contains('\\') {
+        return Cow::Borrowed(input);
+    }
+
+    let mut output = String::with_capacity(input.len());
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            output.push(c);
+            continue;
+        }
+
+        let rest = chars.as_str();
+        match unescape_sequence(&mut chars, style) {
+            Some(c) => output.push(c),
+            None => {
+                output.push('\\');
+                chars = rest.chars();
+            }
+        }
+    }
+    Cow::Owned(output)
+}
+
+// Reads the escape sequence after a backslash, None if it isn't a valid one for the style.
+fn unescape_sequence(chars: &mut Chars, style: EscapeStyle) -> Option<char> {
+    let c = match (chars.next()?, style) {
+        ('n', _) => '\n',
+        ('t', _) => '\t',
+        ('r', _) => '\r',
+        ('"', _) => '"',
+        ('\\', _) => '\\',
+        ('0', EscapeStyle::C) => '\0',
+        ('\'', EscapeStyle::C) => '\'',
+        ('b', EscapeStyle::Json) => '\u{8}',
+        ('f', EscapeStyle::Json) => '\u{c}',
+        ('/', EscapeStyle::Json) => '/',
+        ('u', EscapeStyle::Json) => {
+            let unit = json_code_unit(chars)?;
+            if (0xD800..0xDC00).contains(&unit) {
+                if chars.next()? != '\\' || chars.next()? != 'u' {
+                    return None;
+                }
+                let low = json_code_unit(chars)?;
+                if !(0xDC00..0xE000).contains(&low) {
+                    return None;
+                }
+                return char::from_u32(0x10000 + ((unit - 0xD800) << 10) + (low - 0xDC00));
+            }
+            return char::from_u32(unit);
+        }
+        _ => return None,
+    };
+    Some(c)
+}
+
+// Reads the four hexadecimal digits of a JSON `\u` escape.
+fn json_code_unit(chars: &mut Chars) -> Option<u32> {
+    let rest = chars.as_str();
+    let digits = rest.get(..4)?;
+    if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    *chars = rest[4..].chars();
+    u32::from_str_radix(digits, 16).ok()
+}
+
+/// Several sources concatenated in a single input, so that they can be lexed or preprocessed
+/// together, that maps the locations in the concatenation back to the fragment they come from.
+///
+/// Each fragment starts on a new line: a newline is added after fragments that don't end with
+/// one, and a `\r` or `\n` ending a fragment is completed into a `\r\n` or `\n\r` pair when the
+/// next fragment starts with the other one, so that they aren't read as a single newline. The
+/// fragments aren't lexed separately, so for example a comment left open at the end of a
+/// fragment continues in the next one.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct ConcatSource {
+    text: String,
+    // The line of the concatenation each fragment starts at.
+    start_lines: Vec<u32>,
+    line_count: u32,
+    // The newline character ending the text if it isn't part of a \r\n or \n\r pair.
+    lone_newline: Option<char>,
+}
+
+// Returns the newline character ending the text if it isn't part of a \r\n or \n\r pair, with
+// the same pairing as CharsAndLocation.
+fn lone_trailing_newline(text: &str) -> Option<char> {
+    let mut chars = text.chars().peekable();
+    let mut lone = None;
+    while let Some(c) = chars.next() {
+        lone = None;
+        let other = match c {
+            '\n' => '\r',
+            '\r' => '\n',
+            _ => continue,
+        };
+        if chars.peek() == Some(&other) {
+            chars.next();
+        } else {
+            lone = Some(c);
+        }
+    }
+    lone
+}
+
+impl ConcatSource {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Appends a fragment, returning its index.
+    pub fn push(&mut self, fragment: &str) -> usize {
+        self.start_lines.push(self.line_count + 1);
+        if fragment.starts_with(['\n', '\r']) {
+            match self.lone_newline {
+                Some('\r') => self.text.push('\n'),
+                Some(_) => self.text.push('\r'),
+                None => {}
+            }
+        }
+        self.text.push_str(fragment);
+        if !fragment.is_empty() {
+            self.lone_newline = lone_trailing_newline(fragment);
+            if !fragment.ends_with('\n') && !fragment.ends_with('\r') {
+                self.text.push('\n');
+                self.line_count += 1;
+                self.lone_newline = Some('\n');
+            }
+        }
+        self.line_count += CharsAndLocation::new(fragment)
+            .filter(|&(c, _)| c == '\n')
+            .count() as u32;
+        self.start_lines.len() - 1
+    }
+
+    /// Returns the concatenation of the fragments, to give to the lexer.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Returns the number of fragments.
+    pub fn len(&self) -> usize {
+        self.start_lines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start_lines.is_empty()
+    }
+
+    /// Returns the index of the fragment containing a location of the concatenation, along with
+    /// the corresponding location in that fragment. `None` if the location is past the end.
+    pub fn locate(&self, location: Location) -> Option<(usize, Location)> {
+        if location.line > self.line_count {
+            return None;
+        }
+        let index = self
+            .start_lines
+            .partition_point(|&start| start <= location.line)
+            .checked_sub(1)?;
+        Some((
+            index,
+            Location {
+                line: location.line - self.start_lines[index] + 1,
+                pos: location.pos,
+            },
+        ))
+    }
+}
+
 // An iterator that adds stage 7 on top of SkipBackslashNewline:
 //
 //   7. All comments are replaced with a single space. All (non-zero) characters and invalid UTF-8
@@ -120,6 +327,11 @@ impl<'a> Iterator for SkipBackslashNewline<'a> {
 #[derive(Clone, Copy)]
 pub struct ReplaceComments<'a> {
     inner: SkipBackslashNewline<'a>,
+    preserve_newlines: bool,
+    allow_line_comments: bool,
+    in_block_comment: bool,
+    // The location of the second '/' of a disallowed // comment, output on the next call.
+    pending_slash: Option<Location>,
 }
 
 // The lexer wants to know when whitespace is a comment to know if a comment was ever processed.
@@ -131,14 +343,67 @@ impl<'a> ReplaceComments<'a> {
     pub fn new(input: &'a str) -> Self {
         ReplaceComments {
             inner: SkipBackslashNewline::new(input),
+            preserve_newlines: false,
+            allow_line_comments: true,
+            in_block_comment: false,
+            pending_slash: None,
         }
     }
+
+    /// Like [`ReplaceComments::new`] but the newlines inside of `/* */` comments are output after
+    /// the comment's sentinel, so that the output has the same number of lines as the input.
+    ///
+    /// This is meant for tools re-emitting the source, the [`Lexer`] doesn't use it as the extra
+    /// newlines would end directives.
+    pub fn with_preserved_newlines(input: &'a str) -> Self {
+        ReplaceComments {
+            preserve_newlines: true,
+            ..Self::new(input)
+        }
+    }
+
+    /// Like [`ReplaceComments::new`] but `//` comments are not replaced: their `//` marker is
+    /// output as two `/` characters and the rest of the line is skipped. Two consecutive `/`
+    /// can't otherwise be output so the [`Lexer`] reports them as an error.
+    pub fn without_line_comments(input: &'a str) -> Self {
+        ReplaceComments {
+            allow_line_comments: false,
+            ..Self::new(input)
+        }
+    }
+
+    // Consumes the rest of a /* comment, stopping early at a newline if they are preserved, in
+    // which case it is returned.
+    fn consume_block_comment(&mut self) -> Option<CharAndLocation> {
+        let mut was_star = false;
+        for (next, location) in self.inner.by_ref() {
+            if was_star && next == '/' {
+                break;
+            }
+            if next == '\n' && self.preserve_newlines {
+                return Some((next, location));
+            }
+            was_star = next == '*';
+        }
+        self.in_block_comment = false;
+        None
+    }
 }
 
 impl<'a> Iterator for ReplaceComments<'a> {
     type Item = CharAndLocation;
 
     fn next(&mut self) -> Option<Self::Item> {
+        if self.in_block_comment {
+            if let Some(newline) = self.consume_block_comment() {
+                return Some(newline);
+            }
+        }
+
+        if let Some(location) = self.pending_slash.take() {
+            return Some(('/', location));
+        }
+
         let current = self.inner.next()?;
 
         if current.0 != '/' {
@@ -147,9 +412,9 @@ impl<'a> Iterator for ReplaceComments<'a> {
         }
 
         let mut save_point = self.inner;
-        match self.next() {
+        match self.inner.next() {
             // The // case, consume until but not including the next \n
-            Some(('/', _)) => {
+            Some(('/', second)) => {
                 save_point = self.inner;
                 while let Some((next, _)) = self.inner.next() {
                     if next == '\n' {
@@ -158,18 +423,18 @@ impl<'a> Iterator for ReplaceComments<'a> {
                     save_point = self.inner
                 }
                 self.inner = save_point;
-                Some((COMMENT_SENTINEL_VALUE, current.1))
+                if self.allow_line_comments {
+                    Some((COMMENT_SENTINEL_VALUE, current.1))
+                } else {
+                    self.pending_slash = Some(second);
+                    Some(current)
+                }
             }
 
-            // The /* case, consume until the next */
+            // The /* case, the comment is consumed on the next call so that preserved newlines
+            // come after the sentinel.
             Some(('*', _)) => {
-                let mut was_star = false;
-                while let Some((next, _)) = self.inner.next() {
-                    if was_star && next == '/' {
-                        break;
-                    }
-                    was_star = next == '*';
-                }
+                self.in_block_comment = true;
                 Some((COMMENT_SENTINEL_VALUE, current.1))
             }
 
@@ -182,6 +447,132 @@ impl<'a> Iterator for ReplaceComments<'a> {
     }
 }
 
+/// Returns whether the input contains a preprocessor directive, that is a `#` that's the first
+/// non-whitespace character of a line, ignoring comments and line continuations. This is cheaper
+/// than lexing the input and stops at the first directive found.
+pub fn contains_directives(input: &str) -> bool {
+    let mut start_of_line = true;
+    for (c, _) in ReplaceComments::new(input) {
+        match c {
+            '#' if start_of_line => return true,
+            '\n' => start_of_line = true,
+            ' ' | '\t' | '\x0b' | '\x0c' | COMMENT_SENTINEL_VALUE => {}
+            _ => start_of_line = false,
+        }
+    }
+    false
+}
+
+/// A documentation comment, see [`doc_comments`].
+#[derive(Clone, PartialEq, Debug)]
+pub struct DocComment {
+    /// The text of the comment without the comment markers and surrounding whitespace, including
+    /// the `*` starting the lines of block comments. The text of consecutive `///` comments is
+    /// joined with newlines.
+    pub text: String,
+    /// The location of the start of the comment.
+    pub location: Location,
+}
+
+/// Returns the documentation comments of the input, `///` and `/** */` comments, each with the
+/// token it documents.
+///
+/// A comment documents the next token if it is an identifier, like the type or qualifier
+/// starting a declaration, and only whitespace separates them without a blank line. Consecutive
+/// `///` comments without a blank line in between are a single comment.
+pub fn doc_comments(input: &str) -> Vec<(DocComment, Token)> {
+    // The doc comments with the location of their last character.
+    let mut comments: Vec<(DocComment, Location)> = Vec::new();
+    // Whether the last doc comment is a `///` comment that can be continued on the next line.
+    let mut can_continue = false;
+
+    let mut chars = SkipBackslashNewline::new(input).peekable();
+    while let Some((c, location)) = chars.next() {
+        let is_line_comment = match (c, chars.peek()) {
+            ('/', Some(('/', _))) => true,
+            ('/', Some(('*', _))) => false,
+            (' ', _) | ('\t', _) | ('\n', _) | ('\x0b', _) | ('\x0c', _) => continue,
+            _ => {
+                can_continue = false;
+                continue;
+            }
+        };
+
+        let (_, mut end) = chars.next().unwrap();
+        let mut text = String::new();
+        if is_line_comment {
+            while let Some(&(c, next_location)) = chars.peek() {
+                if c == '\n' {
+                    break;
+                }
+                text.push(c);
+                end = next_location;
+                chars.next();
+            }
+        } else {
+            for (c, next_location) in chars.by_ref() {
+                end = next_location;
+                if c == '/' && text.ends_with('*') {
+                    text.pop();
+                    break;
+                }
+                text.push(c);
+            }
+        }
+
+        // Only `///` and `/**` comments are doc comments, but not `////` or `/**/`.
+        let text = match text.strip_prefix(if is_line_comment { '/' } else { '*' }) {
+            Some(text) if is_line_comment && !text.starts_with('/') => text.trim().to_string(),
+            // The lines of block comments often start with a `*` that isn't part of the text.
+            Some(text) if !is_line_comment => text
+                .lines()
+                .map(|line| {
+                    let line = line.trim();
+                    line.strip_prefix('*').unwrap_or(line).trim()
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+                .trim()
+                .to_string(),
+            _ => continue,
+        };
+
+        match comments.last_mut() {
+            Some((previous, previous_end))
+                if can_continue && is_line_comment && previous_end.line + 1 == location.line =>
+            {
+                previous.text.push('\n');
+                previous.text += &text;
+                *previous_end = end;
+            }
+            _ => comments.push((DocComment { text, location }, end)),
+        }
+        can_continue = is_line_comment;
+    }
+
+    let mut tokens = Lexer::new(input)
+        .filter_map(Result::ok)
+        .filter(|token| token.value != TokenValue::NewLine)
+        .peekable();
+    let mut result = Vec::new();
+    for (comment, end) in comments {
+        while let Some(token) = tokens.peek() {
+            if (token.location.line, token.location.pos) > (end.line, end.pos) {
+                break;
+            }
+            tokens.next();
+        }
+
+        if let Some(token) = tokens.peek() {
+            let is_ident = matches!(token.value, TokenValue::Ident(_));
+            if is_ident && token.location.line <= end.line + 1 {
+                result.push((comment, tokens.next().unwrap()));
+            }
+        }
+    }
+    result
+}
+
 // A lexer for GLSL tokens that also emits a couple extra tokens that are useful to the
 // preprocessor: # and newlines. It also include metadata for the token for whether it is at the
 // start of the line, or if it has leading whitespace.
@@ -195,48 +586,410 @@ pub enum TokenValue {
 
     // Regular token values
     Ident(String),
+    // Literals never include a sign, `-1` is a Minus followed by the Integer 1 (see
+    // token::fold_signed_literal).
     Integer(Integer),
     Float(Float),
     Punct(Punct),
 }
 
+/// The kind of a [`TokenValue`], without its payload.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum TokenKind {
+    Hash,
+    NewLine,
+    Ident,
+    Integer,
+    Float,
+    Punct,
+}
+
+impl TokenValue {
+    pub fn kind(&self) -> TokenKind {
+        match self {
+            TokenValue::Hash => TokenKind::Hash,
+            TokenValue::NewLine => TokenKind::NewLine,
+            TokenValue::Ident(_) => TokenKind::Ident,
+            TokenValue::Integer(_) => TokenKind::Integer,
+            TokenValue::Float(_) => TokenKind::Float,
+            TokenValue::Punct(_) => TokenKind::Punct,
+        }
+    }
+}
+
 impl From<Punct> for TokenValue {
     fn from(punct: Punct) -> Self {
         TokenValue::Punct(punct)
     }
 }
 
+/// Lexes exactly one token, which can be surrounded by whitespace and comments and followed by a
+/// newline. Other tokens produce the error for an unexpected token at their location, and empty
+/// input an `UnexpectedEndOfInput`.
+impl FromStr for TokenValue {
+    type Err = (PreprocessorError, Location);
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let mut lexer = Lexer::new(input);
+        let value = match lexer.next() {
+            Some(item) => item?.value,
+            None => return Err((PreprocessorError::UnexpectedEndOfInput, Location::default())),
+        };
+
+        // Allow the newline that ends the token's line, which the lexer adds if it is missing.
+        let mut had_newline = value == TokenValue::NewLine;
+        for item in lexer {
+            let extra = item?;
+            if extra.value == TokenValue::NewLine && !had_newline {
+                had_newline = true;
+            } else {
+                return Err(extra.into_unexpected_error());
+            }
+        }
+        Ok(value)
+    }
+}
+
 #[derive(Clone, PartialEq, Debug)]
 pub struct Token {
     pub value: TokenValue,
     pub location: Location,
     pub leading_whitespace: bool,
     pub start_of_line: bool,
+    /// The text of integer and float literals as written in the source (without line
+    /// continuations), only present with [`LexerOptions::keep_raw_literals`].
+    pub raw: Option<String>,
+}
+
+impl Token {
+    /// Returns the error for this token when it isn't expected: `UnexpectedNewLine`,
+    /// `UnexpectedHash` or `UnexpectedToken`, at its location.
+    pub fn into_unexpected_error(self) -> (PreprocessorError, Location) {
+        let value = match self.value {
+            TokenValue::NewLine => return (PreprocessorError::UnexpectedNewLine, self.location),
+            TokenValue::Hash => return (PreprocessorError::UnexpectedHash, self.location),
+            TokenValue::Ident(name) => token::TokenValue::Ident(name),
+            TokenValue::Integer(int) => token::TokenValue::Integer(int),
+            TokenValue::Float(float) => token::TokenValue::Float(float),
+            TokenValue::Punct(punct) => token::TokenValue::Punct(punct),
+        };
+        (PreprocessorError::UnexpectedToken(value), self.location)
+    }
+
+    /// Replaces the value of the token, keeping its location and spacing flags.
+    pub fn map_value(self, f: impl FnOnce(TokenValue) -> TokenValue) -> Token {
+        Token {
+            value: f(self.value),
+            ..self
+        }
+    }
+}
+
+/// Calls `f` on the value of each token, for rewriting tokens in place.
+pub fn visit_tokens(tokens: &mut [Token], mut f: impl FnMut(&mut TokenValue)) {
+    for token in tokens {
+        f(&mut token.value);
+    }
 }
 
 pub type LexerItem = Result<Token, (PreprocessorError, Location)>;
+
+/// Options controlling which literals the [`Lexer`] accepts and how they are interpreted.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct LexerOptions {
+    /// Accept 16-bit literals (`1s`, `1.0hf`) instead of producing `NotSupported16BitLiteral`.
+    pub allow_16bit_literals: bool,
+    /// Accept 64-bit literals (`1l`, `1.0lf`) instead of producing `NotSupported64BitLiteral`.
+    pub allow_64bit_literals: bool,
+    /// The width of integer literals without a width suffix, 32 by default. A width of 16 or 64
+    /// is checked like the suffixes, so it needs `allow_16bit_literals` or `allow_64bit_literals`.
+    /// Other widths than 16, 32 and 64 make suffixless literals produce `InvalidIntegerWidth`.
+    pub default_integer_width: i32,
+    /// Whether integer literals without the `u` suffix are signed, true by default.
+    pub default_integer_signed: bool,
+    /// Keep the text of integer and float literals in [`Token::raw`].
+    pub keep_raw_literals: bool,
+    /// Produce a `FloatOverflow` error for float literals too large for a 32-bit float, instead of
+    /// giving them an infinite value. 64-bit literals are not checked as their value is only
+    /// rounded to 32 bits in [`Float`].
+    pub error_on_float_overflow: bool,
+    /// Accept `//` comments, true by default. When false, as in old GLSL versions, each `//`
+    /// comment produces a `NotSupportedLineComment` error at the location of its `//`. Block
+    /// comments are always accepted.
+    pub allow_line_comments: bool,
+    /// Put the `NewLine` added at the end of input that doesn't end with one at the end of the
+    /// last token, where its span from [`Lexer::next_spanned`] ends, instead of one character
+    /// after the start of that token.
+    pub eof_newline_at_token_end: bool,
+    /// Produce an `InvalidNumberSuffix` error for literals directly followed by identifier
+    /// characters after their valid suffixes, like `123abc`, as required by the specification.
+    /// By default they are lexed as a literal followed by an identifier.
+    pub strict_number_suffixes: bool,
+}
+
+impl Default for LexerOptions {
+    fn default() -> Self {
+        LexerOptions {
+            allow_16bit_literals: false,
+            allow_64bit_literals: false,
+            default_integer_width: 32,
+            default_integer_signed: true,
+            keep_raw_literals: false,
+            error_on_float_overflow: false,
+            allow_line_comments: true,
+            eof_newline_at_token_end: false,
+            strict_number_suffixes: false,
+        }
+    }
+}
+
+/// The errors of a [`Lexer`] split with [`Lexer::into_parts`], shared with its token iterator.
+#[derive(Clone, Debug, Default)]
+pub struct ErrorSink {
+    errors: Rc<RefCell<Vec<(PreprocessorError, Location)>>>,
+}
+
+impl ErrorSink {
+    /// Returns the errors encountered so far by the token iterator. The token iterator can't be
+    /// advanced while the returned borrow is alive.
+    pub fn errors(&self) -> Ref<'_, [(PreprocessorError, Location)]> {
+        Ref::map(self.errors.borrow(), |errors| errors.as_slice())
+    }
+
+    /// Removes and returns the errors encountered so far by the token iterator.
+    pub fn take_errors(&self) -> Vec<(PreprocessorError, Location)> {
+        core::mem::take(&mut self.errors.borrow_mut())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.borrow().is_empty()
+    }
+}
+
+// Rough average of the number of bytes of source per token (including newlines) in typical
+// shaders, used to pre-size the output of Lexer::lex_all.
+const BYTES_PER_TOKEN_ESTIMATE: usize = 4;
+
+type LeadingWhitespaceHandler<'a> = Box<dyn FnMut(Location, &str) + 'a>;
+
 pub struct Lexer<'a> {
     inner: Peekable<ReplaceComments<'a>>,
+    options: LexerOptions,
     leading_whitespace: bool,
     start_of_line: bool,
     last_location: Location,
-    had_comments: bool,
+    // The location right after the last token, only tracked with eof_newline_at_token_end.
+    last_token_end: Location,
+    comment_count: usize,
+    line_count: usize,
+    // Whether the last character lexed was a newline.
+    trailing_newline: bool,
+    leading_whitespace_handler: Option<LeadingWhitespaceHandler<'a>>,
+    // The indentation of the current line and the location of its start, collected while the
+    // handler is set and the first token of the line hasn't been reached.
+    indentation: String,
+    indentation_start: Option<Location>,
+    indentation_ended: bool,
 }
 
 impl<'a> Lexer<'a> {
     pub fn new(input: &'a str) -> Self {
+        Self::with_options(input, Default::default())
+    }
+
+    pub fn with_options(input: &'a str, options: LexerOptions) -> Self {
         // TODO bail out on source that is too large.
+        let inner = if options.allow_line_comments {
+            ReplaceComments::new(input)
+        } else {
+            ReplaceComments::without_line_comments(input)
+        };
         Lexer {
-            inner: ReplaceComments::new(input).peekable(),
+            inner: inner.peekable(),
+            options,
             leading_whitespace: true,
             start_of_line: true,
-            last_location: Location { line: 0, pos: 0 },
-            had_comments: false,
+            last_location: Location::default(),
+            last_token_end: Location::default(),
+            comment_count: 0,
+            line_count: 0,
+            trailing_newline: false,
+            leading_whitespace_handler: None,
+            indentation: String::new(),
+            indentation_start: None,
+            indentation_ended: false,
+        }
+    }
+
+    /// Registers a callback that gets called once for each line that has a token (including the
+    /// newline of blank lines) with the location of the start of the line and the raw whitespace
+    /// indenting it, for example to lint mixed tabs and spaces. Indentation stops at the first
+    /// comment. The tokens produced aren't affected.
+    pub fn on_leading_whitespace(&mut self, handler: impl FnMut(Location, &str) + 'a) {
+        self.leading_whitespace_handler = Some(Box::new(handler));
+    }
+
+    // Called before lexing a character at the start of a line, collects the indentation and
+    // reports it when reaching the first token of the line.
+    fn track_indentation(&mut self, c: char, location: Location) {
+        let handler = match &mut self.leading_whitespace_handler {
+            Some(handler) => handler,
+            None => return,
+        };
+
+        let start = *self.indentation_start.get_or_insert(location);
+        match c {
+            ' ' | '\t' | '\x0b' | '\x0c' => {
+                if !self.indentation_ended {
+                    self.indentation.push(c);
+                }
+            }
+            // Whitespace after a comment is no longer indentation, but comments don't start the
+            // line's tokens either.
+            COMMENT_SENTINEL_VALUE => self.indentation_ended = true,
+            _ => {
+                handler(start, &self.indentation);
+                self.indentation.clear();
+                self.indentation_start = None;
+                self.indentation_ended = false;
+            }
         }
     }
 
+    /// Lexes the whole input, stopping at the first error like the iterator does.
+    ///
+    /// The output vector is reserved upfront using an estimate of the number of tokens based on
+    /// the size of the input to avoid repeated reallocations.
+    pub fn lex_all(input: &'a str) -> Result<Vec<Token>, (PreprocessorError, Location)> {
+        let mut tokens = Vec::with_capacity(input.len() / BYTES_PER_TOKEN_ESTIMATE + 1);
+        for item in Lexer::new(input) {
+            tokens.push(item?);
+        }
+        Ok(tokens)
+    }
+
+    /// Lexes the first logical line of the input, following line continuations, and stops at its
+    /// newline which isn't included. The rest of the input isn't lexed.
+    ///
+    /// `start` is the location of the first character of the input in the document it comes from,
+    /// so that the locations of the tokens and errors are in that document.
+    pub fn tokenize_line(
+        input: &'a str,
+        start: Location,
+    ) -> Result<Vec<Token>, (PreprocessorError, Location)> {
+        // Lines continued with a backslash start at position 0.
+        let offset = |location: Location| Location {
+            line: start.line + location.line - 1,
+            pos: if location.line == 1 {
+                start.pos + location.pos
+            } else {
+                location.pos
+            },
+        };
+
+        let mut tokens = Vec::new();
+        for item in Lexer::new(input) {
+            let mut token = item.map_err(|(error, location)| (error, offset(location)))?;
+            if token.value == TokenValue::NewLine {
+                break;
+            }
+            token.location = offset(token.location);
+            tokens.push(token);
+        }
+        Ok(tokens)
+    }
+
+    /// Splits the lexer in an iterator over the values and locations of the tokens, and an
+    /// [`ErrorSink`] collecting the errors as the iterator skips over them.
+    pub fn into_parts(self) -> (impl Iterator<Item = (TokenValue, Location)> + 'a, ErrorSink) {
+        let sink = ErrorSink::default();
+        let iterator_sink = sink.clone();
+        let tokens = self.filter_map(move |item| match item {
+            Ok(token) => Some((token.value, token.location)),
+            Err(err) => {
+                iterator_sink.errors.borrow_mut().push(err);
+                None
+            }
+        });
+        (tokens, sink)
+    }
+
+    /// Groups the tokens by logical line, lines joined with line continuations being a single
+    /// line, without the `NewLine` tokens. Empty lines give empty groups.
+    ///
+    /// The first error of a line is returned in place of its group, the rest of the line is
+    /// skipped.
+    pub fn lines(
+        mut self,
+    ) -> impl Iterator<Item = Result<Vec<Token>, (PreprocessorError, Location)>> + 'a {
+        core::iter::from_fn(move || {
+            let mut line = Vec::new();
+            loop {
+                match self.next() {
+                    Some(Ok(token)) if token.value == TokenValue::NewLine => return Some(Ok(line)),
+                    Some(Ok(token)) => line.push(token),
+                    Some(Err(err)) => {
+                        let rest_of_line = self.by_ref().filter_map(Result::ok);
+                        for token in rest_of_line {
+                            if token.value == TokenValue::NewLine {
+                                break;
+                            }
+                        }
+                        return Some(Err(err));
+                    }
+                    // Non-empty inputs always end with a NewLine so there is no partial line left.
+                    None => return None,
+                }
+            }
+        })
+    }
+
+    /// Like [`Iterator::next`] but also returns the span of the token, or of the characters
+    /// consumed by the error, like a whole literal for an `IntegerOverflow`. The span of the
+    /// `NewLine` added at the end of the input is empty.
+    pub fn next_spanned(&mut self) -> Option<Result<(Token, Span), (PreprocessorError, Span)>> {
+        let start_point = self.inner.clone();
+        let item = self.next()?;
+
+        let next_location = self.inner.peek().map(|&(_, location)| location);
+        let start = match &item {
+            Ok(token) => token.location,
+            Err((_, location)) => *location,
+        };
+        let end = start_point
+            .take_while(|&(_, location)| Some(location) != next_location)
+            .last()
+            .filter(|&(_, location)| (location.line, location.pos) >= (start.line, start.pos))
+            .map_or(start, |(_, location)| Location {
+                line: location.line,
+                pos: location.pos + 1,
+            });
+
+        let span = Span { start, end };
+        Some(match item {
+            Ok(token) => Ok((token, span)),
+            Err((err, _)) => Err((err, span)),
+        })
+    }
+
     pub fn had_comments(&self) -> bool {
-        self.had_comments
+        self.comment_count != 0
+    }
+
+    /// Returns the number of comments lexed so far.
+    pub fn comment_count(&self) -> usize {
+        self.comment_count
+    }
+
+    /// Returns whether the last character of the input is a newline, unlike when the lexer adds
+    /// a `NewLine` at the end of the input. Only meaningful once the whole input is lexed.
+    pub fn had_trailing_newline(&self) -> bool {
+        self.trailing_newline
+    }
+
+    /// Returns the number of lines lexed so far, that is the number of `NewLine` tokens produced.
+    pub fn line_count(&self) -> usize {
+        self.line_count
     }
 
     #[allow(clippy::unnecessary_wraps)]
@@ -259,34 +1012,131 @@ impl<'a> Lexer<'a> {
         Ok(TokenValue::Ident(identifier))
     }
 
-    fn parse_integer_signedness_suffix(&mut self) -> bool {
-        match self.inner.peek() {
-            Some(('u', _)) | Some(('U', _)) => {
-                self.inner.next();
-                false
+    fn check_width_supported(&self, width: i32) -> Result<i32, PreprocessorError> {
+        match width {
+            16 if !self.options.allow_16bit_literals => {
+                Err(PreprocessorError::NotSupported16BitLiteral)
+            }
+            64 if !self.options.allow_64bit_literals => {
+                Err(PreprocessorError::NotSupported64BitLiteral)
             }
-            _ => true,
+            16 | 32 | 64 => Ok(width),
+            _ => Err(PreprocessorError::InvalidIntegerWidth(width)),
         }
     }
 
-    fn parse_integer_width_suffix(&mut self) -> Result<i32, PreprocessorError> {
-        match self.inner.peek() {
-            Some(('l', _)) | Some(('L', _)) => Err(PreprocessorError::NotSupported64BitLiteral),
-            Some(('s', _)) | Some(('S', _)) => Err(PreprocessorError::NotSupported16BitLiteral),
-            _ => Ok(32),
+    // Parses the unsigned and width suffixes of integers, that can be in any order but each at
+    // most once. Returns the signedness and the width of the literal.
+    fn parse_integer_suffix(&mut self) -> Result<(bool, i32), PreprocessorError> {
+        let mut unsigned = false;
+        let mut width = None;
+
+        loop {
+            let duplicate = match self.inner.peek() {
+                Some(('u', _)) | Some(('U', _)) => core::mem::replace(&mut unsigned, true),
+                Some(('l', _)) | Some(('L', _)) => width.replace(64).is_some(),
+                Some(('s', _)) | Some(('S', _)) => width.replace(16).is_some(),
+                _ => break,
+            };
+
+            self.inner.next();
+            if duplicate {
+                return Err(PreprocessorError::DuplicateIntegerSuffix);
+            }
         }
+
+        let width = match width {
+            Some(width) => self.check_width_supported(width)?,
+            None => self.check_width_supported(self.options.default_integer_width)?,
+        };
+        Ok((!unsigned && self.options.default_integer_signed, width))
     }
 
     fn parse_float_width_suffix(&mut self) -> Result<i32, PreprocessorError> {
-        match self.inner.peek() {
-            Some(('l', _)) | Some(('L', _)) => Err(PreprocessorError::NotSupported64BitLiteral),
-            Some(('h', _)) | Some(('H', _)) => Err(PreprocessorError::NotSupported16BitLiteral),
+        let width = match self.inner.peek() {
+            Some(('l', _)) | Some(('L', _)) => 64,
+            Some(('h', _)) | Some(('H', _)) => 16,
             Some(('f', _)) | Some(('F', _)) => {
                 self.inner.next();
-                Ok(32)
+                return Ok(32);
+            }
+            _ => return Ok(32),
+        };
+
+        self.check_width_supported(width)?;
+
+        // The 16 and 64-bit suffixes are `hf` and `lf`, if the `f` is missing the letter isn't
+        // part of the literal.
+        let mut save_point = self.inner.clone();
+        save_point.next();
+        if let Some(('f', _)) | Some(('F', _)) = save_point.next() {
+            self.inner = save_point;
+            Ok(width)
+        } else {
+            Ok(32)
+        }
+    }
+
+    // With strict_number_suffixes, checks that the literal isn't followed by identifier characters,
+    // which are consumed with it if it is.
+    fn check_number_end(&mut self) -> Result<(), PreprocessorError> {
+        let is_ident_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+        match self.inner.peek() {
+            Some(&(c, _)) if self.options.strict_number_suffixes && is_ident_char(c) => {
+                self.consume_chars(is_ident_char);
+                Err(PreprocessorError::InvalidNumberSuffix)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Returns the characters between a save point of the input and the current position.
+    fn text_since(&mut self, start: Peekable<ReplaceComments<'a>>) -> String {
+        let end = self.inner.peek().map(|&(_, location)| location);
+        start
+            .take_while(|&(_, location)| Some(location) != end)
+            .map(|(c, _)| c)
+            .collect()
+    }
+
+    // Returns the location right after the last character consumed since `start`, or `location`
+    // if none were.
+    fn end_since(&mut self, start: Peekable<ReplaceComments<'a>>, location: Location) -> Location {
+        let end = self.inner.peek().map(|&(_, location)| location);
+        start
+            .take_while(|&(_, location)| Some(location) != end)
+            .last()
+            .map_or(location, |(_, last)| Location {
+                line: last.line,
+                pos: last.pos + 1,
+            })
+    }
+
+    // Parses the exponent of a float like `e-10` if there is one, adding it to raw.
+    fn parse_float_exponent(&mut self, raw: &mut String) -> bool {
+        let mut save_point = self.inner.clone();
+        let mut exponent: String = match save_point.next() {
+            Some(('e', _)) | Some(('E', _)) => "e".into(),
+            _ => return false,
+        };
+
+        if let Some(&(sign, _)) = save_point.peek() {
+            if sign == '+' || sign == '-' {
+                save_point.next();
+                exponent.push(sign);
             }
-            _ => Ok(32),
         }
+
+        // Without digits the e isn't part of the literal, like in `1.0e` or `1e+`.
+        match save_point.peek() {
+            Some(('0'..='9', _)) => {}
+            _ => return false,
+        }
+
+        self.inner = save_point;
+        *raw += &exponent;
+        *raw += &self.consume_chars(|c| c.is_ascii_digit());
+        true
     }
 
     fn consume_chars(&mut self, filter: impl Fn(char) -> bool) -> String {
@@ -306,6 +1156,7 @@ impl<'a> Lexer<'a> {
 
     fn parse_number(&mut self, first_char: char) -> Result<TokenValue, PreprocessorError> {
         let mut is_float = false;
+        let mut has_exponent = false;
         let mut integer_radix = 10;
         let mut raw: String = Default::default();
         raw.push(first_char);
@@ -335,12 +1186,16 @@ impl<'a> Lexer<'a> {
 
         if first_char != '.' {
             // Parse any digits at the end of integers, or for the non-fractional part of floats.
-            raw += &self.consume_chars(|c| ('0'..='9').contains(&c));
+            raw += &self.consume_chars(|c| c.is_ascii_digit());
 
             if let Some(('.', _)) = self.inner.peek() {
                 self.inner.next();
                 raw.push('.');
                 is_float = true;
+            } else if integer_radix != 16 {
+                // Integers followed by an exponent are floats, like `1e10`.
+                has_exponent = self.parse_float_exponent(&mut raw);
+                is_float = has_exponent;
             }
         } else {
             is_float = true;
@@ -350,21 +1205,26 @@ impl<'a> Lexer<'a> {
         // up to the . consumed.
 
         if is_float {
-            raw += &self.consume_chars(|c| ('0'..='9').contains(&c));
+            if !has_exponent {
+                raw += &self.consume_chars(|c| c.is_ascii_digit());
+                self.parse_float_exponent(&mut raw);
+            }
             let width = self.parse_float_width_suffix()?;
+            self.check_number_end()?;
 
             // TODO: Depending on the GLSL version make it an error to not have the suffix.
-            // TODO: Handle scientific notation.
 
-            Ok(TokenValue::Float(Float {
-                value: raw
-                    .parse::<f32>()
-                    .map_err(|_| PreprocessorError::FloatParsingError)?,
-                width,
-            }))
+            let value = raw
+                .parse::<f32>()
+                .map_err(|_| PreprocessorError::FloatParsingError)?;
+            if value.is_infinite() && width != 64 && self.options.error_on_float_overflow {
+                return Err(PreprocessorError::FloatOverflow);
+            }
+
+            Ok(TokenValue::Float(Float { value, width }))
         } else {
-            let signed = self.parse_integer_signedness_suffix();
-            let width = self.parse_integer_width_suffix()?;
+            let (signed, width) = self.parse_integer_suffix()?;
+            self.check_number_end()?;
 
             // Skip the initial 0 in hexa or octal (in hexa we never added the 'x').
             if integer_radix != 10 {
@@ -426,6 +1286,7 @@ impl<'a> Lexer<'a> {
             ('*', '=', _) => Some((Punct::MulAssign, 2)),
             ('*', _, _) => Some((Punct::Star, 1)),
             ('/', '=', _) => Some((Punct::DivAssign, 2)),
+            ('/', '/', _) => None,
             ('/', _, _) => Some((Punct::Slash, 1)),
             ('%', '=', _) => Some((Punct::ModAssign, 2)),
             ('%', _, _) => Some((Punct::Percent, 1)),
@@ -457,8 +1318,17 @@ impl<'a> Lexer<'a> {
             self.inner = save_point;
             self.inner.next();
             Ok(TokenValue::Hash)
+        } else if (char0, char1) == ('/', '/') {
+            // Only produced by ReplaceComments when // comments are not allowed.
+            self.inner = save_point;
+            self.inner.next();
+            self.inner.next();
+            Err(PreprocessorError::NotSupportedLineComment)
         } else {
-            Err(PreprocessorError::UnexpectedCharacter)
+            // Only skip the unexpected character so that lexing can resume after it.
+            self.inner = save_point;
+            self.inner.next();
+            Err(PreprocessorError::UnexpectedCharacter(char0))
         }
     }
 }
@@ -468,16 +1338,28 @@ impl<'a> Iterator for Lexer<'a> {
 
     fn next(&mut self) -> Option<Self::Item> {
         while let Some(&(current_char, current_loc)) = self.inner.peek() {
+            self.trailing_newline = current_char == '\n';
             let had_leading_whitespace = self.leading_whitespace;
             self.leading_whitespace = false;
 
             let was_start_of_line = self.start_of_line;
             self.start_of_line = false;
 
+            if was_start_of_line {
+                self.track_indentation(current_char, current_loc);
+            }
+
+            let token_start =
+                if self.options.keep_raw_literals || self.options.eof_newline_at_token_end {
+                    Some(self.inner.clone())
+                } else {
+                    None
+                };
+
             let value = match current_char {
                 ' ' | '\t' | '\x0b' | '\x0c' | COMMENT_SENTINEL_VALUE => {
                     if current_char == COMMENT_SENTINEL_VALUE {
-                        self.had_comments = true;
+                        self.comment_count += 1;
                     }
                     self.start_of_line = was_start_of_line;
                     self.leading_whitespace = true;
@@ -485,12 +1367,19 @@ impl<'a> Iterator for Lexer<'a> {
                     continue;
                 }
                 '\n' => {
+                    self.line_count += 1;
                     self.leading_whitespace = true;
                     self.start_of_line = true;
                     self.inner.next();
                     Ok(TokenValue::NewLine)
                 }
 
+                // NUL characters are only allowed in comments.
+                '\0' => {
+                    self.inner.next();
+                    Err(PreprocessorError::UnexpectedNulCharacter)
+                }
+
                 'a'..='z' | 'A'..='Z' | '_' => self.parse_identifier(),
                 c @ '0'..='9' => {
                     self.inner.next();
@@ -512,27 +1401,91 @@ impl<'a> Iterator for Lexer<'a> {
 
             self.last_location = current_loc;
 
+            if let (true, Some(start)) = (self.options.eof_newline_at_token_end, &token_start) {
+                self.last_token_end = self.end_since(start.clone(), current_loc);
+            }
+
+            let raw = match (&value, token_start) {
+                (Ok(TokenValue::Integer(_)), Some(start))
+                | (Ok(TokenValue::Float(_)), Some(start))
+                    if self.options.keep_raw_literals =>
+                {
+                    Some(self.text_since(start))
+                }
+                _ => None,
+            };
+
             return Some(value.map_err(|e| (e, current_loc)).map(|t| Token {
                 value: t,
                 location: current_loc,
                 leading_whitespace: had_leading_whitespace,
                 start_of_line: was_start_of_line,
+                raw,
             }));
         }
 
         // Do the C hack of always ending with a newline so that preprocessor directives are ended.
         if !self.start_of_line {
             self.start_of_line = true;
+            self.line_count += 1;
 
-            self.last_location.pos += 1;
+            if self.options.eof_newline_at_token_end {
+                self.last_location = self.last_token_end;
+            } else {
+                self.last_location.pos += 1;
+            }
             Some(Ok(Token {
                 value: TokenValue::NewLine,
                 location: self.last_location,
                 leading_whitespace: self.leading_whitespace,
                 start_of_line: false,
+                raw: None,
             }))
         } else {
             None
         }
     }
 }
+
+// The synthetic newline sets start_of_line, so it is produced only once and all the following
+// calls return None, which makes wrapping the lexer in a Peekable safe too.
+impl<'a> FusedIterator for Lexer<'a> {}
+
+/// An iterator adapter over [`LexerItem`]s that removes the `NewLine` tokens, for users that don't
+/// care about lines but still want to know about spacing. Comments are already whitespace in the
+/// [`Lexer`]'s output, and the removed newlines are carried over to the `leading_whitespace` of the
+/// next token. Errors are passed through.
+pub struct WhitespaceStripper<I> {
+    inner: I,
+    pending_whitespace: bool,
+}
+
+impl<I: Iterator<Item = LexerItem>> WhitespaceStripper<I> {
+    pub fn new(inner: I) -> Self {
+        WhitespaceStripper {
+            inner,
+            pending_whitespace: false,
+        }
+    }
+}
+
+impl<I: Iterator<Item = LexerItem>> Iterator for WhitespaceStripper<I> {
+    type Item = LexerItem;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.inner.next()? {
+                Ok(Token {
+                    value: TokenValue::NewLine,
+                    ..
+                }) => self.pending_whitespace = true,
+                Ok(mut token) => {
+                    token.leading_whitespace |= self.pending_whitespace;
+                    self.pending_whitespace = false;
+                    return Some(Ok(token));
+                }
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
@@ -1,8 +1,9 @@
 use super::lexer::{
-    CharsAndLocation, Lexer, LexerItem, ReplaceComments, SkipBackslashNewline, Token, TokenValue,
-    COMMENT_SENTINEL_VALUE,
+    contains_directives, doc_comments, logical_lines, unescape_embedded, visit_tokens,
+    CharsAndLocation, ConcatSource, EscapeStyle, Lexer, LexerItem, LexerOptions, ReplaceComments,
+    SkipBackslashNewline, Token, TokenValue, WhitespaceStripper, COMMENT_SENTINEL_VALUE,
 };
-use super::token::{Float, Integer, Location, PreprocessorError, Punct};
+use super::token::{Float, Integer, Location, PreprocessorError, Punct, Span};
 
 fn c(line: u32, pos: u32, c: char) -> Option<(char, Location)> {
     Some((c, Location { line, pos }))
@@ -123,6 +124,28 @@ fn skip_backslash_newline() {
     assert_eq!(it.next(), None);
 }
 
+#[test]
+fn logical_lines_splitting() {
+    let lines = |input| -> Vec<(Location, String)> { logical_lines(input).collect() };
+    let line = |line, pos, text: &str| (Location { line, pos }, text.to_string());
+
+    // Test that continued lines are joined and keep the location of the first physical line
+    assert_eq!(
+        lines("#define A \\\n  1\nb"),
+        vec![line(1, 0, "#define A   1"), line(3, 0, "b")]
+    );
+
+    // Test empty lines and the trailing newline
+    assert_eq!(
+        lines("a\r\n\nb\n"),
+        vec![line(1, 0, "a"), line(2, 0, ""), line(3, 0, "b")]
+    );
+    assert_eq!(lines(""), vec![]);
+
+    // Test a continuation at the start of a line
+    assert_eq!(lines("\\\na"), vec![line(2, 0, "a")]);
+}
+
 #[test]
 fn replace_comments() {
     // Test a slash that's not a comment
@@ -166,6 +189,13 @@ fn replace_comments() {
     assert_eq!(it.next(), c(1, 0, COMMENT_SENTINEL_VALUE));
     assert_eq!(it.next(), None);
 
+    // Test a single-line comment starting with more than two slashes
+    let mut it = ReplaceComments::new("/// a\nb");
+    assert_eq!(it.next(), c(1, 0, COMMENT_SENTINEL_VALUE));
+    assert_eq!(it.next(), c(1, 5, '\n'));
+    assert_eq!(it.next(), c(2, 0, 'b'));
+    assert_eq!(it.next(), None);
+
     // Test a multi-line comment
     let mut it = ReplaceComments::new("a/*fo\n\no*/b");
     assert_eq!(it.next(), c(1, 0, 'a'));
@@ -185,6 +215,12 @@ fn replace_comments() {
     assert_eq!(it.next(), c(1, 1, COMMENT_SENTINEL_VALUE));
     assert_eq!(it.next(), None);
 
+    // Test a multi-line comment with \r\n and \n\r line endings
+    let mut it = ReplaceComments::new("/* a\r\nb\n\r\r\n*/x");
+    assert_eq!(it.next(), c(1, 0, COMMENT_SENTINEL_VALUE));
+    assert_eq!(it.next(), c(4, 2, 'x'));
+    assert_eq!(it.next(), None);
+
     // Test a multi-line comment, or /*/ not being a complete one
     let mut it = ReplaceComments::new("a/*/b");
     assert_eq!(it.next(), c(1, 0, 'a'));
@@ -192,6 +228,25 @@ fn replace_comments() {
     assert_eq!(it.next(), None);
 }
 
+#[test]
+fn replace_comments_preserved_newlines() {
+    // Test that newlines in multi-line comments are output after the sentinel
+    let mut it = ReplaceComments::with_preserved_newlines("a/*fo\n\no*/b");
+    assert_eq!(it.next(), c(1, 0, 'a'));
+    assert_eq!(it.next(), c(1, 1, COMMENT_SENTINEL_VALUE));
+    assert_eq!(it.next(), c(1, 5, '\n'));
+    assert_eq!(it.next(), c(2, 0, '\n'));
+    assert_eq!(it.next(), c(3, 3, 'b'));
+    assert_eq!(it.next(), None);
+
+    // Test that the output has as many lines as the input
+    let input = "a /* b\r\nc\n*/ d\n// e\n/*\n\n";
+    let newlines = ReplaceComments::with_preserved_newlines(input)
+        .filter(|&(c, _)| c == '\n')
+        .count();
+    assert_eq!(newlines, 6);
+}
+
 #[test]
 fn lex_whitespace() {
     // Empty input gives nothing.
@@ -248,7 +303,8 @@ fn lex_metadata() {
             value: 1.into(),
             location: Location { line: 1, pos: 0 },
             leading_whitespace: true,
-            start_of_line: true
+            start_of_line: true,
+            raw: None
         }
     );
     expect_lexer_end(&mut it);
@@ -263,7 +319,8 @@ fn lex_metadata() {
             value: 1.into(),
             location: Location { line: 1, pos: 1 },
             leading_whitespace: true,
-            start_of_line: true
+            start_of_line: true,
+            raw: None
         }
     );
     // 2 is not at the start of the line because the \n in the /**/ doesn't count, however its
@@ -274,7 +331,8 @@ fn lex_metadata() {
             value: 2.into(),
             location: Location { line: 2, pos: 2 },
             leading_whitespace: true,
-            start_of_line: false
+            start_of_line: false,
+            raw: None
         }
     );
     assert_eq!(
@@ -283,7 +341,8 @@ fn lex_metadata() {
             value: 3.into(),
             location: Location { line: 2, pos: 4 },
             leading_whitespace: true,
-            start_of_line: false
+            start_of_line: false,
+            raw: None
         }
     );
     // + doesn't have a leading whitespace
@@ -293,7 +352,8 @@ fn lex_metadata() {
             value: Punct::Plus.into(),
             location: Location { line: 2, pos: 5 },
             leading_whitespace: false,
-            start_of_line: false
+            start_of_line: false,
+            raw: None
         }
     );
     // The newline is correctly tagged on the preceeding line
@@ -303,7 +363,8 @@ fn lex_metadata() {
             value: TokenValue::NewLine,
             location: Location { line: 2, pos: 6 },
             leading_whitespace: false,
-            start_of_line: false
+            start_of_line: false,
+            raw: None
         }
     );
     // 4 is after a newline that correctly sets start_of_line
@@ -313,7 +374,8 @@ fn lex_metadata() {
             value: 4.into(),
             location: Location { line: 3, pos: 0 },
             leading_whitespace: true,
-            start_of_line: true
+            start_of_line: true,
+            raw: None
         }
     );
     // The final newline added by the lexer is at the correct position
@@ -323,10 +385,36 @@ fn lex_metadata() {
             value: TokenValue::NewLine,
             location: Location { line: 3, pos: 1 },
             leading_whitespace: false,
-            start_of_line: false
+            start_of_line: false,
+            raw: None
         }
     );
     assert_eq!(it.next(), None);
+
+    // Test that the token after a multi-line comment with \r\n has the correct line.
+    let mut it = Lexer::new("/* a\r\nb */ x\r\ny");
+    assert_eq!(
+        unwrap_token(it.next()),
+        Token {
+            value: TokenValue::Ident("x".to_string()),
+            location: Location { line: 2, pos: 5 },
+            leading_whitespace: true,
+            start_of_line: true,
+            raw: None
+        }
+    );
+    assert_eq!(unwrap_token_value(it.next()), TokenValue::NewLine);
+    assert_eq!(
+        unwrap_token(it.next()),
+        Token {
+            value: TokenValue::Ident("y".to_string()),
+            location: Location { line: 3, pos: 0 },
+            leading_whitespace: true,
+            start_of_line: true,
+            raw: None
+        }
+    );
+    expect_lexer_end(&mut it);
 }
 
 #[test]
@@ -625,6 +713,36 @@ fn lex_punctuation() {
     assert_eq!(unwrap_token_value(it.next()), Punct::EqualEqual.into());
     expect_lexer_end(&mut it);
 
+    // Test \\\n doesn't split three character tokens wherever it lands.
+    let mut it = Lexer::new("<\\\n<=");
+    assert_eq!(unwrap_token_value(it.next()), Punct::LeftShiftAssign.into());
+    expect_lexer_end(&mut it);
+    let mut it = Lexer::new("<<\\\n=");
+    assert_eq!(unwrap_token_value(it.next()), Punct::LeftShiftAssign.into());
+    expect_lexer_end(&mut it);
+    let mut it = Lexer::new(">\\\n\\\n>\\\n= >");
+    assert_eq!(
+        unwrap_token(it.next()),
+        Token {
+            value: Punct::RightShiftAssign.into(),
+            location: Location { line: 1, pos: 0 },
+            leading_whitespace: true,
+            start_of_line: true,
+            raw: None
+        }
+    );
+    assert_eq!(
+        unwrap_token(it.next()),
+        Token {
+            value: Punct::RightAngle.into(),
+            location: Location { line: 4, pos: 2 },
+            leading_whitespace: true,
+            start_of_line: false,
+            raw: None
+        }
+    );
+    expect_lexer_end(&mut it);
+
     // Test whitespace splits the token
     let mut it = Lexer::new("+/**/=");
     assert_eq!(unwrap_token_value(it.next()), Punct::Plus.into());
@@ -664,8 +782,17 @@ fn lex_punctuation() {
     let mut it = Lexer::new("@");
     assert_eq!(
         unwrap_error(it.next()),
-        PreprocessorError::UnexpectedCharacter
+        PreprocessorError::UnexpectedCharacter('@')
+    );
+
+    // Test that only the invalid char is skipped, and not the characters after it
+    let mut it = Lexer::new("@+a");
+    assert_eq!(
+        unwrap_error(it.next()),
+        PreprocessorError::UnexpectedCharacter('@')
     );
+    assert_eq!(unwrap_token_value(it.next()), Punct::Plus.into());
+    assert_eq!(unwrap_token_value(it.next()), TokenValue::Ident("a".into()));
 }
 
 #[test]
@@ -712,4 +839,920 @@ fn lex_had_comments() {
     expect_lexer_end(&mut it);
 }
 
+#[test]
+fn lex_all() {
+    // Test that lex_all gives the same tokens as collecting the iterator.
+    let input = "#version 450\nvoid main() {\n    gl_Position = vec4(1.0, .5, 0x1Fu, 3);\n}";
+    let collected: Vec<Token> = Lexer::new(input).map(|item| item.unwrap()).collect();
+    assert_eq!(Lexer::lex_all(input).unwrap(), collected);
+
+    // Test that empty input gives no tokens.
+    assert_eq!(Lexer::lex_all("").unwrap(), vec![]);
+
+    // Test that lexing stops at the first error, like the iterator does.
+    assert_eq!(
+        Lexer::lex_all("a @ $").unwrap_err(),
+        (
+            PreprocessorError::UnexpectedCharacter('@'),
+            Location { line: 1, pos: 2 }
+        )
+    );
+}
+
+#[test]
+fn lex_nul_character() {
+    // Test that a NUL in code produces a specific error.
+    let mut it = Lexer::new("a\0b");
+    assert_eq!(
+        unwrap_token_value(it.next()),
+        TokenValue::Ident("a".to_string())
+    );
+    assert_eq!(
+        it.next().unwrap().unwrap_err(),
+        (
+            PreprocessorError::UnexpectedNulCharacter,
+            Location { line: 1, pos: 1 }
+        )
+    );
+    // Lexing can continue after the NUL.
+    assert_eq!(
+        unwrap_token_value(it.next()),
+        TokenValue::Ident("b".to_string())
+    );
+    expect_lexer_end(&mut it);
+
+    // Test that NUL characters are allowed in comments.
+    let mut it = Lexer::new("/* \0 */ x // \0\n");
+    assert_eq!(
+        unwrap_token_value(it.next()),
+        TokenValue::Ident("x".to_string())
+    );
+    expect_lexer_end(&mut it);
+
+    // Test that other control characters are still unexpected.
+    let mut it = Lexer::new("\x01");
+    assert_eq!(
+        unwrap_error(it.next()),
+        PreprocessorError::UnexpectedCharacter('\x01')
+    );
+}
+
+#[test]
+fn lex_literal_widths() {
+    let options = LexerOptions {
+        allow_16bit_literals: true,
+        allow_64bit_literals: true,
+        ..Default::default()
+    };
+
+    // Test the integer suffixes, with and without the unsigned suffix.
+    let mut it = Lexer::with_options("1s 2uS 3l 4UL", options);
+    for &(value, signed, width) in &[(1, true, 16), (2, false, 16), (3, true, 64), (4, false, 64)] {
+        assert_eq!(
+            unwrap_token_value(it.next()),
+            TokenValue::Integer(Integer {
+                value,
+                signed,
+                width
+            })
+        );
+    }
+    expect_lexer_end(&mut it);
+
+    // Test the float suffixes.
+    let mut it = Lexer::with_options("1.0hf 2.0HF 3.0lf 4.0LF", options);
+    for &(value, width) in &[(1.0, 16), (2.0, 16), (3.0, 64), (4.0, 64)] {
+        assert_eq!(
+            unwrap_token_value(it.next()),
+            TokenValue::Float(Float { value, width })
+        );
+    }
+    expect_lexer_end(&mut it);
+
+    // Test that a h or l not followed by f isn't part of a float literal.
+    let mut it = Lexer::with_options("1.0h", options);
+    assert_eq!(unwrap_token_value(it.next()), 1.0f32.into());
+    assert_eq!(
+        unwrap_token_value(it.next()),
+        TokenValue::Ident("h".to_string())
+    );
+    expect_lexer_end(&mut it);
+
+    // Test that the suffixes are errors by default.
+    let mut it = Lexer::new("1.0hf");
+    assert_eq!(
+        unwrap_error(it.next()),
+        PreprocessorError::NotSupported16BitLiteral
+    );
+    let mut it = Lexer::new("1.0lf");
+    assert_eq!(
+        unwrap_error(it.next()),
+        PreprocessorError::NotSupported64BitLiteral
+    );
+}
+
+#[test]
+fn whitespace_stripper() {
+    // Test that newlines are removed and comments are treated as whitespace
+    let mut it = WhitespaceStripper::new(Lexer::new("a/**/b\n\nc\n#d;"));
+    let token = unwrap_token(it.next());
+    assert_eq!(token.value, TokenValue::Ident("a".into()));
+    let token = unwrap_token(it.next());
+    assert_eq!(token.value, TokenValue::Ident("b".into()));
+    assert!(token.leading_whitespace);
+    let token = unwrap_token(it.next());
+    assert_eq!(token.value, TokenValue::Ident("c".into()));
+    assert!(token.leading_whitespace);
+    assert!(token.start_of_line);
+    let token = unwrap_token(it.next());
+    assert_eq!(token.value, TokenValue::Hash);
+    assert!(token.leading_whitespace);
+    let token = unwrap_token(it.next());
+    assert_eq!(token.value, TokenValue::Ident("d".into()));
+    assert!(!token.leading_whitespace);
+    let token = unwrap_token(it.next());
+    assert_eq!(token.value, Punct::Semicolon.into());
+    assert!(!token.leading_whitespace);
+    assert_eq!(it.next(), None);
+
+    // Test that the spacing is carried over the newline emitted at the end of input
+    let tokens = vec![
+        Ok(Token {
+            value: TokenValue::NewLine,
+            location: Location { line: 1, pos: 0 },
+            leading_whitespace: false,
+            start_of_line: true,
+            raw: None,
+        }),
+        Ok(Token {
+            value: TokenValue::Ident("a".into()),
+            location: Location { line: 2, pos: 0 },
+            leading_whitespace: false,
+            start_of_line: true,
+            raw: None,
+        }),
+    ];
+    let token = unwrap_token(WhitespaceStripper::new(tokens.into_iter()).next());
+    assert!(token.leading_whitespace);
+
+    // Test that errors are passed through
+    let mut it = WhitespaceStripper::new(Lexer::new("\n@"));
+    assert_eq!(
+        unwrap_error(it.next()),
+        PreprocessorError::UnexpectedCharacter('@')
+    );
+}
+
+#[test]
+fn into_parts() {
+    let (tokens, errors) = Lexer::new("a\n@\n1").into_parts();
+    assert!(errors.is_empty());
+
+    // Test that tokens and errors are partitioned
+    let tokens: Vec<_> = tokens.collect();
+    assert_eq!(
+        tokens,
+        vec![
+            (TokenValue::Ident("a".into()), Location { line: 1, pos: 0 }),
+            (TokenValue::NewLine, Location { line: 1, pos: 1 }),
+            (TokenValue::NewLine, Location { line: 2, pos: 1 }),
+            (1.into(), Location { line: 3, pos: 0 }),
+            (TokenValue::NewLine, Location { line: 3, pos: 1 }),
+        ]
+    );
+    assert_eq!(
+        *errors.errors(),
+        [(
+            PreprocessorError::UnexpectedCharacter('@'),
+            Location { line: 2, pos: 0 }
+        )]
+    );
+
+    // Test that take_errors empties the sink.
+    assert_eq!(errors.take_errors().len(), 1);
+    assert!(errors.is_empty());
+}
+
+#[test]
+fn token_rewriting() {
+    let uppercase = |value: &mut TokenValue| {
+        if let TokenValue::Ident(name) = value {
+            *name = name.to_uppercase();
+        }
+    };
+
+    // Test that visit_tokens rewrites values but keeps the rest of the tokens.
+    let original = Lexer::lex_all("a + b\n c").unwrap();
+    let mut tokens = original.clone();
+    visit_tokens(&mut tokens, uppercase);
+    assert_eq!(tokens.len(), original.len());
+    for (token, original) in tokens.iter().zip(&original) {
+        assert_eq!(token.location, original.location);
+        assert_eq!(token.leading_whitespace, original.leading_whitespace);
+        assert_eq!(token.start_of_line, original.start_of_line);
+    }
+    assert_eq!(tokens[0].value, TokenValue::Ident("A".into()));
+    assert_eq!(tokens[1].value, Punct::Plus.into());
+    assert_eq!(tokens[4].value, TokenValue::Ident("C".into()));
+
+    // Test map_value on a single token.
+    let token = original[4].clone().map_value(|mut value| {
+        uppercase(&mut value);
+        value
+    });
+    assert_eq!(token, tokens[4]);
+}
+
+#[test]
+fn lex_raw_literals() {
+    let options = LexerOptions {
+        keep_raw_literals: true,
+        allow_64bit_literals: true,
+        ..Default::default()
+    };
+
+    // Test that the raw text is the source text of each literal.
+    let input = "0x1F 017 42u 3l 1.0f .5 2.lf";
+    let raw: Vec<String> = Lexer::with_options(input, options)
+        .map(|item| item.unwrap())
+        .filter(|token| token.value != TokenValue::NewLine)
+        .map(|token| token.raw.unwrap())
+        .collect();
+    assert_eq!(raw, input.split(' ').collect::<Vec<_>>());
+
+    // Test that line continuations are not part of the raw text.
+    let mut it = Lexer::with_options("1\\\n2 a", options);
+    assert_eq!(unwrap_token(it.next()).raw.as_deref(), Some("12"));
+    assert_eq!(unwrap_token(it.next()).raw, None);
+
+    // Test that the raw text is not kept by default.
+    assert_eq!(unwrap_token(Lexer::new("1").next()).raw, None);
+}
+
+#[test]
+fn lex_fused() {
+    // Test that the lexer keeps returning None after the synthetic newline.
+    let mut it = Lexer::new("a");
+    assert_eq!(unwrap_token_value(it.next()), TokenValue::Ident("a".into()));
+    expect_lexer_end(&mut it);
+    assert_eq!(it.next(), None);
+    assert_eq!(it.next(), None);
+
+    // Test that peeking doesn't consume or duplicate the synthetic newline.
+    let mut it = Lexer::new("a").peekable();
+    assert_eq!(unwrap_token_value(it.next()), TokenValue::Ident("a".into()));
+    assert_eq!(
+        it.peek().cloned().unwrap().unwrap().value,
+        TokenValue::NewLine
+    );
+    assert_eq!(
+        it.peek().cloned().unwrap().unwrap().value,
+        TokenValue::NewLine
+    );
+    assert_eq!(unwrap_token_value(it.next()), TokenValue::NewLine);
+    assert_eq!(it.peek(), None);
+    assert_eq!(it.next(), None);
+    assert_eq!(it.next(), None);
+
+    // Test the same when the input already ends with a newline.
+    let mut it = Lexer::new("a\n").peekable();
+    assert_eq!(it.by_ref().count(), 2);
+    assert_eq!(it.peek(), None);
+    assert_eq!(it.next(), None);
+}
+
+#[test]
+fn lex_integer_suffix_order() {
+    let options = LexerOptions {
+        allow_16bit_literals: true,
+        allow_64bit_literals: true,
+        ..Default::default()
+    };
+
+    // Test that the unsigned and width suffixes are accepted in any order and case.
+    for unsigned in &["", "u", "U"] {
+        for &(width_suffix, width) in &[("", 32), ("l", 64), ("L", 64), ("s", 16), ("S", 16)] {
+            for input in &[
+                format!("7{}{}", unsigned, width_suffix),
+                format!("7{}{}", width_suffix, unsigned),
+            ] {
+                let mut it = Lexer::with_options(input, options);
+                assert_eq!(
+                    unwrap_token_value(it.next()),
+                    TokenValue::Integer(Integer {
+                        value: 7,
+                        signed: unsigned.is_empty(),
+                        width
+                    })
+                );
+                expect_lexer_end(&mut it);
+            }
+        }
+    }
+
+    // Test that suffixes can't be repeated, including two different widths.
+    for input in &["1uu", "1lL", "1ls", "1uLu", "0x1Uu"] {
+        let mut it = Lexer::with_options(input, options);
+        assert_eq!(
+            unwrap_error(it.next()),
+            PreprocessorError::DuplicateIntegerSuffix
+        );
+        expect_lexer_end(&mut it);
+    }
+}
+
+#[test]
+fn lex_contains_directives() {
+    // Test inputs with directives, including after whitespace, comments and continuations.
+    assert!(contains_directives("#define A 1"));
+    assert!(contains_directives("a\n  \t#version 450"));
+    assert!(contains_directives("a\n/* comment */ # pragma"));
+    assert!(contains_directives("a\n\\\n#endif"));
+
+    // Test that hashes inside of comments or in the middle of lines aren't directives.
+    assert!(!contains_directives(""));
+    assert!(!contains_directives("// #define A 1\nfoo"));
+    assert!(!contains_directives("/*\n#define A 1\n*/"));
+    assert!(!contains_directives("a # b"));
+    assert!(!contains_directives("a /* \n */ #"));
+    assert!(!contains_directives("// comment \\\n#define A"));
+}
+
+#[test]
+fn lex_dot_ambiguity() {
+    let values = |input| -> Vec<TokenValue> {
+        Lexer::new(input)
+            .map(|item| item.unwrap().value)
+            .filter(|value| *value != TokenValue::NewLine)
+            .collect()
+    };
+    let ident = |name: &str| TokenValue::Ident(name.into());
+    let dot = TokenValue::from(Punct::Dot);
+
+    // Test that a dot followed by a digit starts a float, otherwise it is punctuation.
+    assert_eq!(values(".5"), vec![0.5f32.into()]);
+    assert_eq!(values("x.y"), vec![ident("x"), dot.clone(), ident("y")]);
+    assert_eq!(values("v.x1"), vec![ident("v"), dot.clone(), ident("x1")]);
+    assert_eq!(values(". 5"), vec![dot.clone(), 5.into()]);
+
+    // Test that a float ends at its second dot, which can start another float.
+    assert_eq!(values("1.2.3"), vec![1.2f32.into(), 0.3f32.into()]);
+    assert_eq!(values("1..2"), vec![1.0f32.into(), 0.2f32.into()]);
+    assert_eq!(values("1.x"), vec![1.0f32.into(), ident("x")]);
+
+    // Test consecutive dots.
+    assert_eq!(values(".."), vec![dot.clone(), dot]);
+}
+
+#[test]
+fn lex_stray_backslash() {
+    // Test that a backslash that isn't a line continuation is reported as such.
+    let mut it = Lexer::new("a \\ b");
+    assert_eq!(unwrap_token_value(it.next()), TokenValue::Ident("a".into()));
+    let error = it.next().unwrap().unwrap_err();
+    assert_eq!(
+        error,
+        (
+            PreprocessorError::UnexpectedCharacter('\\'),
+            Location { line: 1, pos: 2 }
+        )
+    );
+    assert_eq!(
+        error.0.to_string(),
+        "unexpected character '\\\\', a line continuation must be followed by a newline"
+    );
+
+    // Test that lexing resumes after it.
+    assert_eq!(unwrap_token_value(it.next()), TokenValue::Ident("b".into()));
+    expect_lexer_end(&mut it);
+}
+
+#[test]
+fn lex_float_exponent() {
+    // Test exponents with and without a sign, fractional part or suffix.
+    let mut it = Lexer::new("1e3 2.5E-1 .5e+1 3.e2f 010e1 1e0x");
+    assert_eq!(unwrap_token_value(it.next()), 1000.0f32.into());
+    assert_eq!(unwrap_token_value(it.next()), 0.25f32.into());
+    assert_eq!(unwrap_token_value(it.next()), 5.0f32.into());
+    assert_eq!(unwrap_token_value(it.next()), 300.0f32.into());
+    assert_eq!(unwrap_token_value(it.next()), 100.0f32.into());
+    assert_eq!(unwrap_token_value(it.next()), 1.0f32.into());
+    assert_eq!(unwrap_token_value(it.next()), TokenValue::Ident("x".into()));
+    expect_lexer_end(&mut it);
+
+    // Test that an e without digits isn't part of the literal.
+    let mut it = Lexer::new("1e 2.0e+");
+    assert_eq!(unwrap_token_value(it.next()), 1.into());
+    assert_eq!(unwrap_token_value(it.next()), TokenValue::Ident("e".into()));
+    assert_eq!(unwrap_token_value(it.next()), 2.0f32.into());
+    assert_eq!(unwrap_token_value(it.next()), TokenValue::Ident("e".into()));
+    assert_eq!(unwrap_token_value(it.next()), Punct::Plus.into());
+    expect_lexer_end(&mut it);
+
+    // Test that hexadecimal literals don't have exponents.
+    let mut it = Lexer::new("0x1e2");
+    assert_eq!(unwrap_token_value(it.next()), 0x1e2.into());
+    expect_lexer_end(&mut it);
+}
+
+#[test]
+fn lex_float_overflow() {
+    // Test that overflowing floats are infinite by default.
+    let mut it = Lexer::new("1e40f");
+    assert_eq!(unwrap_token_value(it.next()), f32::INFINITY.into());
+    expect_lexer_end(&mut it);
+
+    // Test that they are errors with error_on_float_overflow, except for 64-bit literals.
+    let options = LexerOptions {
+        error_on_float_overflow: true,
+        allow_64bit_literals: true,
+        ..Default::default()
+    };
+    let mut it = Lexer::with_options("1e40f 1e38 1e40lf", options);
+    assert_eq!(unwrap_error(it.next()), PreprocessorError::FloatOverflow);
+    assert_eq!(unwrap_token_value(it.next()), 1e38f32.into());
+    assert_eq!(
+        unwrap_token_value(it.next()),
+        TokenValue::Float(Float {
+            value: f32::INFINITY,
+            width: 64
+        })
+    );
+    expect_lexer_end(&mut it);
+}
+
+#[test]
+fn lex_doc_comments() {
+    let docs = |input| -> Vec<(String, TokenValue, Location)> {
+        doc_comments(input)
+            .into_iter()
+            .map(|(comment, token)| (comment.text, token.value, comment.location))
+            .collect()
+    };
+    let ident = |name: &str| TokenValue::Ident(name.into());
+
+    // Test that a doc comment is associated with the following identifier.
+    assert_eq!(
+        docs("/** doc */ uniform"),
+        vec![("doc".into(), ident("uniform"), Location { line: 1, pos: 0 })]
+    );
+    assert_eq!(
+        docs("int a;\n/**\n * Multi\n * line\n */\nvec4 b;"),
+        vec![(
+            "Multi\nline".into(),
+            ident("vec4"),
+            Location { line: 2, pos: 0 }
+        )]
+    );
+
+    // Test that consecutive /// comments are joined.
+    assert_eq!(
+        docs("  /// first\n  /// second\n  float x;"),
+        vec![(
+            "first\nsecond".into(),
+            ident("float"),
+            Location { line: 1, pos: 2 }
+        )]
+    );
+    assert_eq!(
+        docs("/// a\nx /// b\ny"),
+        vec![
+            ("a".into(), ident("x"), Location { line: 1, pos: 0 }),
+            ("b".into(), ident("y"), Location { line: 2, pos: 2 }),
+        ]
+    );
+
+    // Test comments that aren't doc comments or don't document anything.
+    assert_eq!(docs("// a\n/* b */ /**/ //// c\nx"), vec![]);
+    assert_eq!(docs("/// blank line\n\nx"), vec![]);
+    assert_eq!(docs("/// not an identifier\n42"), vec![]);
+    assert_eq!(docs("/// end of input"), vec![]);
+}
+
+#[test]
+fn lex_spans() {
+    let span = |line, start, end| Span {
+        start: Location { line, pos: start },
+        end: Location { line, pos: end },
+    };
+
+    // Test that the span of an overflow error covers the whole literal, suffix included.
+    let mut it = Lexer::new("a 99999999999999999999u");
+    assert_eq!(it.next_spanned().unwrap().unwrap().1, span(1, 0, 1));
+    assert_eq!(
+        it.next_spanned().unwrap().unwrap_err(),
+        (PreprocessorError::IntegerOverflow, span(1, 2, 23))
+    );
+
+    // Test that the span of an unexpected character is only that character.
+    let mut it = Lexer::new("  @@");
+    assert_eq!(
+        it.next_spanned().unwrap().unwrap_err(),
+        (PreprocessorError::UnexpectedCharacter('@'), span(1, 2, 3))
+    );
+
+    assert_eq!(
+        it.next_spanned().unwrap().unwrap_err(),
+        (PreprocessorError::UnexpectedCharacter('@'), span(1, 3, 4))
+    );
+
+    // Test tokens spans, ignoring whitespace and comments, and that the final newline is empty.
+    let mut it = Lexer::new("/* c */ <<= \n");
+    assert_eq!(it.next_spanned().unwrap().unwrap().1, span(1, 8, 11));
+    assert_eq!(it.next_spanned().unwrap().unwrap().1, span(1, 12, 13));
+    assert_eq!(it.next_spanned(), None);
+    let mut it = Lexer::new("a");
+    it.next_spanned();
+    assert_eq!(it.next_spanned().unwrap().unwrap().1, span(1, 1, 1));
+}
+
+#[test]
+fn lex_line_comments() {
+    // Test that // comments are skipped by default.
+    let mut it = Lexer::new("a // x\nb");
+    assert_eq!(unwrap_token_value(it.next()), TokenValue::Ident("a".into()));
+    assert_eq!(unwrap_token_value(it.next()), TokenValue::NewLine);
+    assert_eq!(unwrap_token_value(it.next()), TokenValue::Ident("b".into()));
+    expect_lexer_end(&mut it);
+
+    // Test that they are errors at the location of the // when not allowed, and lexing resumes on
+    // the next line.
+    let options = LexerOptions {
+        allow_line_comments: false,
+        ..Default::default()
+    };
+    let mut it = Lexer::with_options("a // x\nb /* x */ c/d", options);
+    assert_eq!(unwrap_token_value(it.next()), TokenValue::Ident("a".into()));
+    assert_eq!(
+        it.next().unwrap().unwrap_err(),
+        (
+            PreprocessorError::NotSupportedLineComment,
+            Location { line: 1, pos: 2 }
+        )
+    );
+    assert_eq!(unwrap_token_value(it.next()), TokenValue::NewLine);
+    assert_eq!(unwrap_token_value(it.next()), TokenValue::Ident("b".into()));
+    assert_eq!(unwrap_token_value(it.next()), TokenValue::Ident("c".into()));
+    assert_eq!(unwrap_token_value(it.next()), Punct::Slash.into());
+    assert_eq!(unwrap_token_value(it.next()), TokenValue::Ident("d".into()));
+    expect_lexer_end(&mut it);
+}
+
+#[test]
+fn lex_tokenize_line() {
+    let start = Location { line: 10, pos: 4 };
+    let values_and_locations = |input| -> Vec<(TokenValue, Location)> {
+        Lexer::tokenize_line(input, start)
+            .unwrap()
+            .into_iter()
+            .map(|token| (token.value, token.location))
+            .collect()
+    };
+    let at = |line, pos| Location { line, pos };
+
+    // Test that a line continuation is part of the line, and that locations are relative to start.
+    assert_eq!(
+        values_and_locations("#define A (1 + \\\n 2)\nb"),
+        vec![
+            (TokenValue::Hash, at(10, 4)),
+            (TokenValue::Ident("define".into()), at(10, 5)),
+            (TokenValue::Ident("A".into()), at(10, 12)),
+            (Punct::LeftParen.into(), at(10, 14)),
+            (1.into(), at(10, 15)),
+            (Punct::Plus.into(), at(10, 17)),
+            (2.into(), at(11, 1)),
+            (Punct::RightParen.into(), at(11, 2)),
+        ]
+    );
+    assert_eq!(values_and_locations(""), vec![]);
+
+    // Test that errors on the line are reported, but not errors after it.
+    assert_eq!(
+        Lexer::tokenize_line("a @", start),
+        Err((PreprocessorError::UnexpectedCharacter('@'), at(10, 6)))
+    );
+    assert_eq!(Lexer::tokenize_line("a\n@", start).unwrap().len(), 1);
+}
+
+#[test]
+fn lex_signs() {
+    // Test that signs are never part of literals.
+    let values = |input| -> Vec<TokenValue> {
+        Lexer::new(input)
+            .map(|item| item.unwrap().value)
+            .filter(|value| *value != TokenValue::NewLine)
+            .collect()
+    };
+    assert_eq!(values("-1"), vec![Punct::Minus.into(), 1.into()]);
+    assert_eq!(values("+2.0"), vec![Punct::Plus.into(), 2.0f32.into()]);
+    assert_eq!(
+        values("- -3"),
+        vec![Punct::Minus.into(), Punct::Minus.into(), 3.into()]
+    );
+    assert_eq!(values("1e-2"), vec![0.01f32.into()]);
+}
+
+#[test]
+fn lex_lines() {
+    let lines = |input| -> Vec<Result<Vec<TokenValue>, PreprocessorError>> {
+        Lexer::new(input)
+            .lines()
+            .map(|line| {
+                line.map(|tokens| tokens.into_iter().map(|token| token.value).collect())
+                    .map_err(|(error, _)| error)
+            })
+            .collect()
+    };
+    let ident = |name: &str| TokenValue::Ident(name.into());
+
+    // Test that a continuation joins two physical lines in a single group.
+    assert_eq!(
+        lines("#define A \\\n  b c\nd"),
+        vec![
+            Ok(vec![
+                TokenValue::Hash,
+                ident("define"),
+                ident("A"),
+                ident("b"),
+                ident("c")
+            ]),
+            Ok(vec![ident("d")]),
+        ]
+    );
+
+    // Test empty lines, a newline at the end of the input and an empty input.
+    assert_eq!(
+        lines("a\n\nb\n"),
+        vec![Ok(vec![ident("a")]), Ok(vec![]), Ok(vec![ident("b")])]
+    );
+    assert_eq!(lines(""), vec![]);
+
+    // Test that an error replaces the group of its line.
+    assert_eq!(
+        lines("a @ b\nc"),
+        vec![
+            Err(PreprocessorError::UnexpectedCharacter('@')),
+            Ok(vec![ident("c")]),
+        ]
+    );
+}
+
+#[test]
+fn leading_whitespace_handler() {
+    // Test that the handler gets the raw indentation of each line, including blank ones, and that
+    // the tokens are the same as without it.
+    let input = "a\n  b\n\t c\n\n\t\t/* x */ d\n";
+    let mut lines = Vec::new();
+    let mut lexer = Lexer::new(input);
+    lexer.on_leading_whitespace(|location, text| lines.push((location, text.to_string())));
+    let tokens: Vec<_> = lexer.collect();
+    let expected: Vec<_> = Lexer::new(input).collect();
+    assert_eq!(tokens, expected);
+
+    let line_start = |line| Location { line, pos: 0 };
+    assert_eq!(
+        lines,
+        vec![
+            (line_start(1), "".to_string()),
+            (line_start(2), "  ".to_string()),
+            (line_start(3), "\t ".to_string()),
+            (line_start(4), "".to_string()),
+            (line_start(5), "\t\t".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn eof_newline_location() {
+    let options = LexerOptions {
+        eof_newline_at_token_end: true,
+        ..Default::default()
+    };
+    let eof_newline = |input, options| {
+        Lexer::with_options(input, options)
+            .map(Result::unwrap)
+            .last()
+            .map(|token| (token.value, token.location))
+    };
+    let newline_at = |line, pos| Some((TokenValue::NewLine, Location { line, pos }));
+
+    // Test that by default it is one character after the start of the last token.
+    assert_eq!(eof_newline("foo", Default::default()), newline_at(1, 1));
+
+    // Test that with the option it is at the end of the last token, ignoring trailing whitespace
+    // and comments, and following line continuations.
+    assert_eq!(eof_newline("foo", options), newline_at(1, 3));
+    assert_eq!(eof_newline("a\nfoo  /* c */ ", options), newline_at(2, 3));
+    assert_eq!(eof_newline("f\\\noo", options), newline_at(2, 2));
+
+    // Test that it is where the span of the last token ends.
+    let mut it = Lexer::with_options("a <<=", options);
+    it.next();
+    let (_, span) = it.next_spanned().unwrap().unwrap();
+    assert_eq!(it.next().unwrap().unwrap().location, span.end);
+
+    // Test that empty input, or input with only whitespace and comments, gives no tokens.
+    assert_eq!(eof_newline("", options), None);
+    assert_eq!(eof_newline(" /* c */ ", options), None);
+}
+
+#[test]
+fn trailing_newline() {
+    let had_trailing_newline = |input| {
+        let mut lexer = Lexer::new(input);
+        lexer.by_ref().for_each(drop);
+        lexer.had_trailing_newline()
+    };
+
+    // Test that only input whose last character is a newline has one, even though the lexer
+    // always ends with a NewLine token.
+    assert!(had_trailing_newline("foo\n"));
+    assert!(had_trailing_newline("foo // c\r\n"));
+    assert!(!had_trailing_newline("foo"));
+    assert!(!had_trailing_newline("foo\n  "));
+    assert!(!had_trailing_newline("foo /* c */"));
+    assert!(!had_trailing_newline(""));
+}
+
+#[test]
+fn lex_strict_number_suffixes() {
+    let unsigned = TokenValue::Integer(Integer {
+        value: 123,
+        signed: false,
+        width: 32,
+    });
+
+    // Test that by default a literal followed by identifier characters is two tokens.
+    let mut it = Lexer::new("123abc 123u");
+    assert_eq!(unwrap_token_value(it.next()), 123.into());
+    assert_eq!(
+        unwrap_token_value(it.next()),
+        TokenValue::Ident("abc".into())
+    );
+    assert_eq!(unwrap_token_value(it.next()), unsigned);
+    expect_lexer_end(&mut it);
+
+    // Test that it is an error with strict_number_suffixes, consuming the whole literal, but
+    // valid suffixes are still fine.
+    let options = LexerOptions {
+        strict_number_suffixes: true,
+        ..Default::default()
+    };
+    let mut it = Lexer::with_options("123abc 123u 1.5fx 0x1Fg 1e2_ 1.0f", options);
+    assert_eq!(
+        unwrap_error(it.next()),
+        PreprocessorError::InvalidNumberSuffix
+    );
+    assert_eq!(unwrap_token_value(it.next()), unsigned);
+    assert_eq!(
+        unwrap_error(it.next()),
+        PreprocessorError::InvalidNumberSuffix
+    );
+    assert_eq!(
+        unwrap_error(it.next()),
+        PreprocessorError::InvalidNumberSuffix
+    );
+    assert_eq!(
+        unwrap_error(it.next()),
+        PreprocessorError::InvalidNumberSuffix
+    );
+    assert_eq!(unwrap_token_value(it.next()), 1.0f32.into());
+    expect_lexer_end(&mut it);
+}
+
+#[test]
+fn concat_source() {
+    let mut source = ConcatSource::new();
+    assert!(source.is_empty());
+    assert_eq!(source.push("a\nb"), 0);
+    assert_eq!(source.push(""), 1);
+    assert_eq!(source.push("c\r\n  d\n"), 2);
+    assert_eq!(source.len(), 3);
+
+    // Test that a newline is only added after fragments that don't end with one.
+    assert_eq!(source.text(), "a\nb\nc\r\n  d\n");
+
+    // Test that the locations of tokens lexed in the concatenation map back to their fragment.
+    let locate = |name: &str| {
+        let token = Lexer::new(source.text())
+            .map(Result::unwrap)
+            .find(|token| token.value == TokenValue::Ident(name.into()))
+            .unwrap();
+        source.locate(token.location)
+    };
+    assert_eq!(locate("a"), Some((0, Location { line: 1, pos: 0 })));
+    assert_eq!(locate("b"), Some((0, Location { line: 2, pos: 0 })));
+    assert_eq!(locate("c"), Some((2, Location { line: 1, pos: 0 })));
+    assert_eq!(locate("d"), Some((2, Location { line: 2, pos: 2 })));
+
+    // Test that locations past the end aren't in any fragment.
+    assert_eq!(source.locate(Location { line: 5, pos: 0 }), None);
+    assert_eq!(source.locate(Location { line: 0, pos: 0 }), None);
+
+    // Test that a \r and a \n at the boundary of two fragments stay two newlines.
+    for (first, second) in &[("a\r", "\nb"), ("a\n", "\rb"), ("a", "\nb")] {
+        let mut source = ConcatSource::new();
+        source.push(first);
+        source.push(second);
+        source.push("c");
+        let lines: Vec<_> = Lexer::new(source.text())
+            .map(Result::unwrap)
+            .filter(|token| token.value != TokenValue::NewLine)
+            .map(|token| source.locate(token.location))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                Some((0, Location { line: 1, pos: 0 })),
+                Some((1, Location { line: 2, pos: 0 })),
+                Some((2, Location { line: 1, pos: 0 })),
+            ]
+        );
+    }
+}
+
+#[test]
+fn token_value_from_str() {
+    // Test parsing a single token of each kind.
+    assert_eq!("123u".parse(), Ok(TokenValue::from(123u32)));
+    assert_eq!("<<=".parse(), Ok(TokenValue::from(Punct::LeftShiftAssign)));
+    assert_eq!("foo".parse(), Ok(TokenValue::Ident("foo".to_string())));
+
+    // Test that surrounding whitespace, comments and the end of line are allowed.
+    assert_eq!(
+        " foo /* c */\n".parse(),
+        Ok(TokenValue::Ident("foo".to_string()))
+    );
+
+    // Test that more than one token is an error at the first extra token.
+    assert_eq!(
+        "a b".parse::<TokenValue>(),
+        Err((
+            PreprocessorError::UnexpectedToken(crate::token::TokenValue::Ident("b".to_string())),
+            Location { line: 1, pos: 2 }
+        ))
+    );
+    assert_eq!(
+        "a\nb".parse::<TokenValue>(),
+        Err((
+            PreprocessorError::UnexpectedToken(crate::token::TokenValue::Ident("b".to_string())),
+            Location { line: 2, pos: 0 }
+        ))
+    );
+
+    // Test that an empty string has no token.
+    assert_eq!(
+        "".parse::<TokenValue>().unwrap_err().0,
+        PreprocessorError::UnexpectedEndOfInput
+    );
+
+    // Test that lexing errors are reported.
+    assert_eq!(
+        "1s".parse::<TokenValue>(),
+        Err((
+            PreprocessorError::NotSupported16BitLiteral,
+            Location { line: 1, pos: 0 }
+        ))
+    );
+}
+
+#[test]
+fn unescape_embedded_shader() {
+    use std::borrow::Cow;
+
+    // Test that escaped newlines, tabs, quotes and backslashes are replaced.
+    let shader = unescape_embedded(
+        r#"#version 450\n#define S \"a\\b\"\n\tvoid main() {}\n"#,
+        EscapeStyle::C,
+    );
+    assert_eq!(
+        shader,
+        "#version 450\n#define S \"a\\b\"\n\tvoid main() {}\n"
+    );
+    let mut lexer = Lexer::new(&shader);
+    assert_eq!(unwrap_token_value(lexer.next()), TokenValue::Hash);
+    assert_eq!(
+        unwrap_token_value(lexer.next()),
+        TokenValue::Ident("version".to_string())
+    );
+    assert_eq!(unwrap_token_value(lexer.next()), 450.into());
+    assert_eq!(unwrap_token_value(lexer.next()), TokenValue::NewLine);
+
+    // Test that the input is borrowed when there are no escapes.
+    assert!(matches!(
+        unescape_embedded("void main() {}", EscapeStyle::C),
+        Cow::Borrowed("void main() {}")
+    ));
+
+    // Test that unknown escapes are kept as written, including a trailing backslash.
+    assert_eq!(unescape_embedded(r"a\qb\", EscapeStyle::C), r"a\qb\");
+    assert_eq!(unescape_embedded(r"\'\/", EscapeStyle::C), r"'\/");
+    assert_eq!(unescape_embedded(r"\'\/", EscapeStyle::Json), r"\'/");
+
+    // Test JSON unicode escapes, with surrogate pairs and invalid ones kept as written.
+    assert_eq!(unescape_embedded(r"é😀", EscapeStyle::Json), "é😀");
+    assert_eq!(unescape_embedded(r"\ud83dA", EscapeStyle::Json), r"\ud83dA");
+    assert_eq!(unescape_embedded(r"\u12", EscapeStyle::Json), r"\u12");
+}
+
 // TODO test has_whitespace
@@ -1,9 +1,19 @@
+// Tests always use std, CI checks the no_std build by building for a target without std.
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+#[cfg(any(feature = "std", test))]
+extern crate core;
+
 #[allow(clippy::match_like_matches_macro)]
-mod lexer;
+pub mod lexer;
+#[cfg(feature = "std")]
 pub mod pp;
 pub mod token;
 
 #[cfg(test)]
 mod lexer_tests;
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod pp_tests;
+#[cfg(all(test, feature = "std"))]
+mod token_tests;
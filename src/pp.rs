@@ -2,13 +2,15 @@ use crate::lexer::{self, Token as LexerToken, TokenValue as LexerTokenValue};
 use crate::token::*;
 use std::{
     cmp::Ordering,
-    collections::{HashMap, HashSet},
+    collections::{HashMap, HashSet, VecDeque},
     convert::TryFrom,
     rc::Rc,
 };
 
 mod if_parser;
 
+pub use self::if_parser::{parse_expr, Expr, PrecTable};
+
 #[derive(Clone, PartialEq, Debug)]
 struct Define {
     name: String,
@@ -23,6 +25,8 @@ struct DefineInvocation {
     define_position: usize,
 
     parameters: Vec<Vec<Token>>,
+    // For each token of the expanded parameters, its location in the invocation.
+    parameter_origins: Vec<Vec<Location>>,
     parameter_expanding: usize,
     parameter_position: usize,
 }
@@ -50,70 +54,592 @@ trait MELexer {
     fn step(&mut self) -> Step<Token>;
     fn get_define(&self, name: &str) -> Option<&Rc<Define>>;
     fn apply_line_offset(&self, line: u32, location: Location) -> Step<u32>;
+    fn source_string(&self) -> u32;
+}
+
+// Makes an object-like define from its name and the text of its content.
+fn make_define(name: &str, content: &str) -> Result<Define, (PreprocessorError, Location)> {
+    let mut define = Define {
+        name: name.to_string(),
+        function_like: false,
+        params: Default::default(),
+        tokens: Default::default(),
+    };
+
+    // Convert the content to tokens and add it to the define.
+    let mut lexer = lexer::Lexer::new(content);
+    loop {
+        match lexer.next() {
+            Some(Ok(lexer_token)) => {
+                // Skip over newlines (the lexer always adds a newline, which would cause an
+                // error in convert_lexer_token).
+                if lexer_token.value == LexerTokenValue::NewLine {
+                    continue;
+                }
+
+                define.tokens.push(convert_lexer_token(lexer_token)?);
+            }
+
+            Some(Err(err)) => return Err(err),
+            None => break,
+        }
+    }
+
+    Ok(define)
+}
+
+// Parses what follows the name of a #define until the end of the line: the parameters if there is
+// a ( right after the name, then the body.
+fn parse_define(name: String, next_token: &mut dyn FnMut() -> Step<LexerToken>) -> Step<Define> {
+    let mut define = Define {
+        name,
+        function_like: false,
+        params: Default::default(),
+        tokens: Default::default(),
+    };
+
+    let mut token = next_token()?;
+
+    // The is a function-like define, parse the argument list, leaving token as the next
+    // token.
+    if token.value == Punct::LeftParen.into() && !token.leading_whitespace {
+        define.function_like = true;
+        let mut first_param = true;
+        loop {
+            token = next_token()?;
+            match &token.value {
+                LexerTokenValue::Punct(Punct::RightParen) => {
+                    token = next_token()?;
+                    break;
+                }
+
+                LexerTokenValue::Ident(param_name) => {
+                    if !first_param {
+                        return Err(make_unexpected_error(token));
+                    }
+                    first_param = false;
+                    define
+                        .params
+                        .insert(param_name.clone(), define.params.len());
+                }
+
+                LexerTokenValue::Punct(Punct::Comma) => {
+                    if first_param {
+                        return Err(make_unexpected_error(token));
+                    }
+
+                    let param = next_token()?;
+                    let param_name = match param.value {
+                        LexerTokenValue::Ident(name) => name,
+                        _ => return Err(make_expected_error(ExpectedKind::Identifier, param)),
+                    };
+                    if define.params.contains_key(&param_name) {
+                        return Err(StepExit::Error((
+                            PreprocessorError::DuplicateParameter,
+                            param.location,
+                        )));
+                    }
+                    define.params.insert(param_name, define.params.len());
+                }
+                _ => {
+                    return Err(make_unexpected_error(token));
+                }
+            }
+        }
+    }
+
+    // Tokens until the newline are that define's tokens (including the current one)
+    loop {
+        if token.value == LexerTokenValue::NewLine {
+            break;
+        }
+        define.tokens.push(convert_lexer_token_to_step(token)?);
+        token = next_token()?;
+    }
+
+    Ok(define)
 }
 
 fn make_unexpected_error(token: LexerToken) -> StepExit {
-    let error = match token.value {
-        LexerTokenValue::Integer(i) => PreprocessorError::UnexpectedToken(TokenValue::Integer(i)),
-        LexerTokenValue::Float(f) => PreprocessorError::UnexpectedToken(TokenValue::Float(f)),
-        LexerTokenValue::Ident(s) => PreprocessorError::UnexpectedToken(TokenValue::Ident(s)),
-        LexerTokenValue::Punct(p) => PreprocessorError::UnexpectedToken(TokenValue::Punct(p)),
-        LexerTokenValue::NewLine => PreprocessorError::UnexpectedNewLine,
-        LexerTokenValue::Hash => PreprocessorError::UnexpectedHash,
+    StepExit::Error(token.into_unexpected_error())
+}
+
+// Like make_unexpected_error but also says what was expected, unless it is the end of the line.
+fn make_expected_error(expected: ExpectedKind, token: LexerToken) -> StepExit {
+    let found = match token.value {
+        LexerTokenValue::Integer(i) => TokenValue::Integer(i),
+        LexerTokenValue::Float(f) => TokenValue::Float(f),
+        LexerTokenValue::Ident(s) => TokenValue::Ident(s),
+        LexerTokenValue::Punct(p) => TokenValue::Punct(p),
+        LexerTokenValue::NewLine | LexerTokenValue::Hash => return make_unexpected_error(token),
     };
-    StepExit::Error((error, token.location))
+    StepExit::Error((
+        PreprocessorError::Expected { expected, found },
+        token.location,
+    ))
 }
 
 fn make_line_overflow_error(location: Location) -> StepExit {
     StepExit::Error((PreprocessorError::LineOverflow, location))
 }
 
+// Macros predefined by the GLSL specifications or extensions, that the source can't redefine. All
+// names starting with two underscores are reserved too.
+const BUILTIN_MACROS: &[&str] = &[
+    "GL_ES",
+    "GL_core_profile",
+    "GL_compatibility_profile",
+    "GL_SPIRV",
+    "VULKAN",
+];
+
+fn check_reserved_macro_name(name: &str, location: Location) -> Step<()> {
+    if name.starts_with("__") || BUILTIN_MACROS.contains(&name) {
+        Err(StepExit::Error((
+            PreprocessorError::ReservedMacroRedefinition(name.to_string()),
+            location,
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// The names of the directives the preprocessor understands, other directives are handled
+/// according to [`PreprocessorOptions::error_on_unknown_directive`].
+pub const DIRECTIVES: &[&str] = &[
+    "define",
+    "undef",
+    "error",
+    "line",
+    "if",
+    "ifdef",
+    "ifndef",
+    "elif",
+    "else",
+    "endif",
+    "version",
+    "extension",
+    "pragma",
+];
+
+pub fn is_known_directive(name: &str) -> bool {
+    DIRECTIVES.contains(&name)
+}
+
+#[derive(Clone, Debug)]
 struct DirectiveBlock {
     start_location: Location,
     had_valid_segment: bool,
     had_else: bool,
     outer_skipped: bool,
+    // Whether any branch contains a token or a directive other than #elif, #else and #endif.
+    had_content: bool,
+}
+
+/// Options controlling the behavior of the [`Preprocessor`].
+///
+/// The defaults are the same as [`Dialect::GlslEs`].
+#[derive(Clone, PartialEq, Debug)]
+pub struct PreprocessorOptions {
+    pub lexer: lexer::LexerOptions,
+    /// Whether unknown directives produce an `UnknownDirective` error or are ignored.
+    pub error_on_unknown_directive: bool,
+    /// The maximum number of tokens a single top-level macro invocation is allowed to produce,
+    /// including the expansion of nested macros. Exceeding it produces an `ExpansionTooLarge`
+    /// error at the invocation. `None` means there is no limit.
+    pub max_expansion_tokens: Option<usize>,
+    /// The maximum number of tokens the preprocessor outputs in total, after macro expansion.
+    /// Going over it produces a `TokenLimitExceeded` error at the first token past the limit, or
+    /// its top-level macro invocation, and stops preprocessing. `None` means there is no limit.
+    pub max_total_tokens: Option<usize>,
+    /// Whether tokens after the macro name of `#ifdef` and `#ifndef` are ignored instead of
+    /// producing an `UnexpectedToken` error.
+    pub allow_extra_ifdef_tokens: bool,
+    /// Only process `#define` and `#undef`, handling all other directives like unknown ones (see
+    /// `error_on_unknown_directive`). This is meant for using macros as simple text templating.
+    pub macros_only: bool,
+    /// Whether tokens after `#else` and `#endif` produce an `ExtraTokensAfterDirective` error, as
+    /// required by GLSL, instead of being ignored like most C preprocessors do. Off by default, and
+    /// on for all the [`Dialect`]s but [`Dialect::Permissive`].
+    pub strict_directive_trailing: bool,
+    /// Emit an [`PreprocessEvent::EmptyConditional`] event for conditional blocks that contain
+    /// neither tokens nor directives in any of their branches.
+    pub warn_empty_conditionals: bool,
+    /// Record the name and location of each macro invocation, see [`Preprocessor::macro_uses`].
+    pub record_macro_uses: bool,
+    /// Remember the value of `#if` and `#elif` conditions so that identical conditions aren't
+    /// expanded and evaluated again until a macro or extension changes. The output is the same as
+    /// without it.
+    pub cache_conditions: bool,
+    /// Output `#version`, `#extension` and `#pragma` as their tokens without interpreting them,
+    /// for compilers that handle these directives themselves: they produce no events or errors,
+    /// and don't change [`Preprocessor::shader_stage`] or the enabled extensions.
+    pub preserve_directives: bool,
+    /// Warn about the macros defined in the input that are never expanded or tested with
+    /// `defined`, `#ifdef` or `#ifndef`, at the end of the input with the location of their name.
+    /// Macros added with [`Preprocessor::add_define`] or in the prologue aren't reported.
+    pub warn_unused_macros: bool,
+    /// Set [`Token::invocation`] on the tokens produced by macro expansions to the name and
+    /// location of the macro invocation they come from.
+    pub record_invocations: bool,
+    /// Ignore a comma right before the `)` of a function-like macro invocation, like in
+    /// `FOO(a, b,)`, when it would otherwise be one argument too many, instead of producing a
+    /// `TooManyDefineArguments` error.
+    pub allow_trailing_argument_comma: bool,
+    /// Treat commas inside `[]` in the arguments of a function-like macro invocation as part of
+    /// the argument, so that `F(a[1, 2])` has a single argument. C and GLSL preprocessors only
+    /// group commas inside `()`, so this is off by default.
+    pub bracket_argument_commas: bool,
+    /// The input is a chunk of a larger source that continues in another preprocessor, see
+    /// [`Preprocessor::snapshot`]: conditional blocks can be left open at its end, and unused
+    /// macros aren't reported.
+    pub partial_input: bool,
 }
 
+impl Default for PreprocessorOptions {
+    fn default() -> Self {
+        PreprocessorOptions {
+            lexer: Default::default(),
+            error_on_unknown_directive: true,
+            max_expansion_tokens: None,
+            max_total_tokens: None,
+            allow_extra_ifdef_tokens: false,
+            macros_only: false,
+            strict_directive_trailing: false,
+            warn_empty_conditionals: false,
+            record_macro_uses: false,
+            cache_conditions: false,
+            preserve_directives: false,
+            warn_unused_macros: false,
+            record_invocations: false,
+            allow_trailing_argument_comma: false,
+            bracket_argument_commas: false,
+            partial_input: false,
+        }
+    }
+}
+
+/// The flavors of GLSL the [`Preprocessor`] can target, each providing sensible defaults for
+/// [`PreprocessorOptions`] that can then be individually overriden.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Dialect {
+    /// OpenGL ES Shading Language, only 32-bit literals are allowed.
+    GlslEs,
+    /// Desktop GLSL, which also allows 64-bit literals.
+    GlslCore,
+    /// GLSL for Vulkan, which allows 16 and 64-bit literals.
+    Vulkan,
+    /// Accepts everything it can, ignoring unknown directives and extra tokens after `#ifdef`.
+    Permissive,
+}
+
+impl Dialect {
+    pub fn options(self) -> PreprocessorOptions {
+        let mut options = PreprocessorOptions::default();
+        match self {
+            Dialect::GlslEs => {
+                options.strict_directive_trailing = true;
+            }
+            Dialect::GlslCore => {
+                options.lexer.allow_64bit_literals = true;
+                options.strict_directive_trailing = true;
+            }
+            Dialect::Vulkan => {
+                options.lexer.allow_16bit_literals = true;
+                options.lexer.allow_64bit_literals = true;
+                options.strict_directive_trailing = true;
+            }
+            Dialect::Permissive => {
+                options.lexer.allow_16bit_literals = true;
+                options.lexer.allow_64bit_literals = true;
+                options.error_on_unknown_directive = false;
+                options.allow_extra_ifdef_tokens = true;
+            }
+        }
+        options
+    }
+}
+
+impl From<Dialect> for PreprocessorOptions {
+    fn from(dialect: Dialect) -> Self {
+        dialect.options()
+    }
+}
+
+/// An event emitted by the [`Preprocessor`] when processing directives, see
+/// [`Preprocessor::on_event`].
+#[derive(Clone, PartialEq, Debug)]
+pub enum PreprocessEvent {
+    Define {
+        name: String,
+    },
+    Undef {
+        name: String,
+    },
+    /// `number` is `None` if the `#version` directive doesn't start with an integer.
+    Version {
+        number: Option<u64>,
+    },
+    /// The condition of an `#if` or `#elif` that was evaluated to `value`.
+    Condition {
+        expression: Expr,
+        value: bool,
+    },
+    /// A `#pragma STDGL` directive, reserved by the GLSL specification, with the tokens after
+    /// `STDGL`. The directive is still output as a `Pragma` token.
+    StdglPragma {
+        tokens: Vec<Token>,
+    },
+    /// A `#pragma optimize` or `#pragma debug` directive. The directive is still output as a
+    /// `Pragma` token.
+    StdPragma {
+        pragma: StdPragma,
+    },
+    /// A warning for a conditional block starting at `location` whose branches are all empty,
+    /// emitted at its `#endif` if [`PreprocessorOptions::warn_empty_conditionals`] is set.
+    EmptyConditional {
+        location: Location,
+    },
+}
+
+/// Receives the diagnostics of a [`Preprocessor`], see [`Preprocessor::process_into`].
+pub trait DiagnosticSink {
+    fn error(&mut self, error: PreprocessorError, location: Location);
+
+    /// Warnings are ignored by default.
+    fn warning(&mut self, warning: PreprocessorWarning, location: Location) {
+        let _ = (warning, location);
+    }
+}
+
+/// A [`DiagnosticSink`] that collects the diagnostics.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Diagnostics {
+    pub errors: Vec<(PreprocessorError, Location)>,
+    pub warnings: Vec<(PreprocessorWarning, Location)>,
+}
+
+impl DiagnosticSink for Diagnostics {
+    fn error(&mut self, error: PreprocessorError, location: Location) {
+        self.errors.push((error, location));
+    }
+
+    fn warning(&mut self, warning: PreprocessorWarning, location: Location) {
+        self.warnings.push((warning, location));
+    }
+}
+
+type IdentifierResolver<'a> = Box<dyn Fn(&str) -> Option<i64> + 'a>;
+
 struct DirectiveProcessor<'a> {
     lexer: lexer::Lexer<'a>,
+    // The lexer of the prologue, processed before the input, see Preprocessor::set_prologue.
+    prologue: Option<lexer::Lexer<'a>>,
+    options: PreprocessorOptions,
+    event_handler: Option<Box<dyn FnMut(PreprocessEvent) + 'a>>,
+    identifier_resolver: Option<IdentifierResolver<'a>>,
     defines: HashMap<String, Rc<Define>>,
     skipping: bool,
     blocks: Vec<DirectiveBlock>,
+    // The start of the current excluded region (when skipping) and all the previous ones.
+    excluded_region_start: Location,
+    excluded_regions: Vec<(Location, Location)>,
     line_offset: i64,
+    // The location of the last token of the input. The next chunk continues on its line with
+    // Preprocessor::restore, and an excluded region still open at the end of the input ends there.
+    last_location: Location,
+    // Whether the lexer reached the end of the input.
+    input_finished: bool,
+    // Whether the line numbers set by #line went past u32::MAX, which is only reported once.
+    line_overflowed: bool,
+    // A token that was read before reporting the line overflow, returned by the next read.
+    pending_token: Option<LexerToken>,
+    // The rest of a directive line read ahead to find its end, returned by the next reads.
+    lookahead: VecDeque<lexer::LexerItem>,
+    // The location of the last lexer token or error read, and whether it was a NewLine.
+    last_read_location: Location,
+    last_read_newline: bool,
+    // The span of the directive that produced the last error, with the location of that error,
+    // see Preprocessor::next_spanned.
+    directive_error_span: Option<(Location, Span)>,
+    source_string: u32,
+    shader_stage: Option<ShaderStage>,
+    enabled_extensions: HashSet<String>,
+    // Incremented each time the macros or the enabled extensions change, so that the cached
+    // conditions computed with older ones aren't used.
+    macro_generation: u64,
+    // The values of #if conditions keyed by condition_cache_key, for the generation
+    // condition_cache_generation. Only used with PreprocessorOptions::cache_conditions.
+    condition_cache: HashMap<Vec<u8>, bool>,
+    condition_cache_generation: u64,
     had_directive: bool,
     had_non_directive_token: bool,
+    // The warnings not yet given to a DiagnosticSink or taken with Preprocessor::take_warnings.
+    warnings: Vec<(PreprocessorWarning, Location)>,
+    // With warn_unused_macros, the macros defined in the input and where, and the macros used by
+    // directives. The ones used by tokens are in the MacroProcessor.
+    defined_macros: Vec<(String, Location)>,
+    used_macros: HashSet<String>,
 }
 
-pub fn convert_lexer_token(token: LexerToken) -> Result<Token, (PreprocessorError, Location)> {
-    let location = token.location;
-    match token.value {
-        LexerTokenValue::Integer(i) => Ok(Token {
-            value: TokenValue::Integer(i),
-            location,
-        }),
-        LexerTokenValue::Float(f) => Ok(Token {
-            value: TokenValue::Float(f),
-            location,
-        }),
-        LexerTokenValue::Ident(s) => Ok(Token {
-            value: TokenValue::Ident(s),
-            location,
-        }),
-        LexerTokenValue::Punct(p) => Ok(Token {
-            value: TokenValue::Punct(p),
-            location,
-        }),
+fn unexpected_pragma_token(token: &Token) -> StepExit {
+    StepExit::Error((
+        PreprocessorError::UnexpectedToken(token.value.clone()),
+        token.location,
+    ))
+}
+
+// Parses the tokens of a `#pragma name(argument)` directive, returning the argument identifier and
+// its token.
+fn parse_pragma_argument(tokens: &[Token]) -> Step<(&str, &Token)> {
+    match &tokens[1..] {
+        [open, argument, close] => {
+            if open.value != TokenValue::Punct(Punct::LeftParen) {
+                return Err(unexpected_pragma_token(open));
+            }
+            if close.value != TokenValue::Punct(Punct::RightParen) {
+                return Err(unexpected_pragma_token(close));
+            }
+            match &argument.value {
+                TokenValue::Ident(name) => Ok((name, argument)),
+                _ => Err(unexpected_pragma_token(argument)),
+            }
+        }
+        [_, _, _, extra, ..] => Err(unexpected_pragma_token(extra)),
+        // The line ended too early, report it at its last token.
+        _ => Err(StepExit::Error((
+            PreprocessorError::UnexpectedNewLine,
+            tokens[tokens.len() - 1].location,
+        ))),
+    }
+}
+
+// Parses the tokens of a `#pragma shader_stage(stage)` directive.
+fn parse_shader_stage(tokens: &[Token]) -> Step<ShaderStage> {
+    let (name, token) = parse_pragma_argument(tokens)?;
+    ShaderStage::from_name(name).ok_or_else(|| unexpected_pragma_token(token))
+}
 
-        LexerTokenValue::NewLine => Err((PreprocessorError::UnexpectedNewLine, location)),
-        LexerTokenValue::Hash => Err((PreprocessorError::UnexpectedHash, location)),
+// Parses the `(on)` or `(off)` of a `#pragma optimize` or `#pragma debug` directive.
+fn parse_pragma_switch(tokens: &[Token]) -> Step<bool> {
+    match parse_pragma_argument(tokens)? {
+        ("on", _) => Ok(true),
+        ("off", _) => Ok(false),
+        (_, token) => Err(unexpected_pragma_token(token)),
     }
 }
 
+pub fn convert_lexer_token(token: LexerToken) -> Result<Token, (PreprocessorError, Location)> {
+    let location = token.location;
+    let value = match token.value {
+        LexerTokenValue::Integer(i) => TokenValue::Integer(i),
+        LexerTokenValue::Float(f) => TokenValue::Float(f),
+        LexerTokenValue::Ident(s) => TokenValue::Ident(s),
+        LexerTokenValue::Punct(p) => TokenValue::Punct(p),
+
+        LexerTokenValue::NewLine => return Err((PreprocessorError::UnexpectedNewLine, location)),
+        LexerTokenValue::Hash => return Err((PreprocessorError::UnexpectedHash, location)),
+    };
+    Ok(Token {
+        value,
+        location,
+        raw: token.raw,
+        invocation: None,
+    })
+}
+
 pub fn convert_lexer_token_to_step(token: LexerToken) -> Step<Token> {
     convert_lexer_token(token).map_err(StepExit::Error)
 }
 
+/// Checks that `params` and `body` would be accepted as the parameters and body of a
+/// function-like `#define`, without defining anything. This gives the same errors as `#define`,
+/// for example the `#` and `##` operators aren't supported and are errors, and the body must fit
+/// on a single line.
+///
+/// Errors in the body are located in `body`. Errors in the parameters are located on line 0, in
+/// the parameter list written as `(a, b)`.
+pub fn validate_macro_body(
+    params: &[String],
+    body: &str,
+) -> Result<(), (PreprocessorError, Location)> {
+    let param_list = format!("({})", params.join(", "));
+    // The parameter list directly follows the (empty) macro name, so it has no leading
+    // whitespace, unlike the start of the input.
+    let mut param_lexer = lexer::Lexer::new(&param_list).map(|item| {
+        item.map(|token| LexerToken {
+            location: Location {
+                line: 0,
+                pos: token.location.pos,
+            },
+            leading_whitespace: token.leading_whitespace && token.location.pos != 0,
+            ..token
+        })
+    });
+    let mut body_lexer = lexer::Lexer::new(body);
+    // The location of the newline that ends the first line of the body.
+    let mut newline_location = None;
+
+    let mut next_token = || -> Step<LexerToken> {
+        for item in param_lexer.by_ref() {
+            match item {
+                // Skip the newline the lexer adds at the end of the parameter list.
+                Ok(token) if token.value == LexerTokenValue::NewLine => {}
+                item => return item.map_err(StepExit::Error),
+            }
+        }
+
+        let token = match body_lexer.next() {
+            Some(item) => item.map_err(StepExit::Error)?,
+            // An empty body has no newline.
+            None => LexerToken {
+                value: LexerTokenValue::NewLine,
+                location: Location { line: 1, pos: 0 },
+                leading_whitespace: false,
+                start_of_line: true,
+                raw: None,
+            },
+        };
+        if token.value == LexerTokenValue::NewLine {
+            newline_location = Some(token.location);
+        }
+        Ok(token)
+    };
+
+    parse_define(String::new(), &mut next_token).map_err(|exit| match exit {
+        StepExit::Error(error) => error,
+        // The tokens always end with a newline, where parse_define stops.
+        _ => unreachable!(),
+    })?;
+
+    // Only the newline added at the end of the body is allowed.
+    match body_lexer.next() {
+        Some(Ok(_)) => Err((
+            PreprocessorError::UnexpectedNewLine,
+            newline_location.unwrap_or_default(),
+        )),
+        Some(Err(err)) => Err(err),
+        None => Ok(()),
+    }
+}
+
+// The key of a condition in DirectiveProcessor::condition_cache: the values of its tokens, and
+// the source string that __FILE__ expands to.
+fn condition_cache_key(tokens: &[Token], source_string: u32) -> Vec<u8> {
+    let values: Vec<Token> = tokens
+        .iter()
+        .map(|token| Token {
+            value: token.value.clone(),
+            location: Location::default(),
+            raw: None,
+            invocation: None,
+        })
+        .collect();
+    let mut key = source_string.to_le_bytes().to_vec();
+    key.extend(encode_tokens(&values));
+    key
+}
+
 fn legal_redefinition(a: &Define, b: &Define) -> bool {
     assert!(a.name == b.name);
     a.function_like == b.function_like
@@ -126,26 +652,170 @@ fn legal_redefinition(a: &Define, b: &Define) -> bool {
 }
 
 impl<'a> DirectiveProcessor<'a> {
-    pub fn new(input: &'a str) -> DirectiveProcessor {
+    pub fn new(input: &'a str, options: PreprocessorOptions) -> DirectiveProcessor<'a> {
         DirectiveProcessor {
-            lexer: lexer::Lexer::new(input),
+            lexer: lexer::Lexer::with_options(input, options.lexer),
+            prologue: None,
+            options,
+            event_handler: None,
+            identifier_resolver: None,
             defines: Default::default(),
             skipping: false,
             blocks: Default::default(),
+            excluded_region_start: Location::default(),
+            excluded_regions: Default::default(),
             line_offset: 0,
+            last_location: Location::default(),
+            input_finished: false,
+            line_overflowed: false,
+            pending_token: None,
+            lookahead: VecDeque::new(),
+            last_read_location: Location::default(),
+            last_read_newline: false,
+            directive_error_span: None,
+            source_string: 0,
+            shader_stage: None,
+            enabled_extensions: Default::default(),
+            macro_generation: 0,
+            condition_cache: Default::default(),
+            condition_cache_generation: 0,
             had_directive: false,
             had_non_directive_token: false,
+            warnings: Vec::new(),
+            defined_macros: Vec::new(),
+            used_macros: Default::default(),
+        }
+    }
+
+    fn emit_event(&mut self, event: PreprocessEvent) {
+        if let Some(handler) = &mut self.event_handler {
+            handler(event);
+        }
+    }
+
+    // Changes whether tokens are skipped, recording the excluded regions as a side effect.
+    fn set_skipping(&mut self, skipping: bool, directive_location: Location) {
+        if skipping && !self.skipping {
+            self.excluded_region_start = directive_location;
+        } else if !skipping && self.skipping {
+            self.excluded_regions
+                .push((self.excluded_region_start, directive_location));
+        }
+        self.skipping = skipping;
+    }
+
+    // Records that the innermost conditional block, if any, isn't empty.
+    fn mark_block_content(&mut self) {
+        if let Some(block) = self.blocks.last_mut() {
+            block.had_content = true;
         }
     }
 
     fn get_lexer_token(&mut self) -> Step<LexerToken> {
+        let step = self.read_lexer_token();
+        match &step {
+            Ok(token) => {
+                self.last_read_location = token.location;
+                self.last_read_newline = token.value == LexerTokenValue::NewLine;
+            }
+            Err(StepExit::Error((_, location))) => {
+                self.last_read_location = *location;
+                self.last_read_newline = false;
+            }
+            Err(_) => {}
+        }
+        step
+    }
+
+    fn read_lexer_token(&mut self) -> Step<LexerToken> {
+        if let Some(token) = self.pending_token.take() {
+            return Ok(token);
+        }
+        if let Some(item) = self.lookahead.pop_front() {
+            if let (Ok(token), None) = (&item, &self.prologue) {
+                self.last_location = token.location;
+            }
+            return item.map_err(StepExit::Error);
+        }
+        if let Some(prologue) = &mut self.prologue {
+            match prologue.next() {
+                None => self.end_prologue()?,
+                Some(Ok(tok)) => return Ok(tok),
+                Some(Err(err)) => return Err(StepExit::Error(err)),
+            }
+        }
         match self.lexer.next() {
-            None => Finished.into(),
-            Some(Ok(tok)) => Ok(tok),
+            None => {
+                // With partial input the region continues in the next chunk.
+                if !self.input_finished && self.skipping && !self.options.partial_input {
+                    self.excluded_regions
+                        .push((self.excluded_region_start, self.last_location));
+                }
+                self.input_finished = true;
+                Finished.into()
+            }
+            Some(Ok(tok)) => {
+                self.last_location = tok.location;
+                Ok(tok)
+            }
             Some(Err(err)) => Err(StepExit::Error(err)),
         }
     }
 
+    // Returns the location of the NewLine that ends the current directive line, reading the rest of
+    // the line ahead if it wasn't read yet. It is returned by the next reads like it was never read.
+    fn directive_line_end(&mut self) -> Location {
+        if self.last_read_newline {
+            return self.last_read_location;
+        }
+
+        let mut end = self.last_read_location;
+        for token in self.lookahead.iter().flatten() {
+            end = token.location;
+            if token.value == LexerTokenValue::NewLine {
+                return end;
+            }
+        }
+
+        let lexer = match &mut self.prologue {
+            Some(prologue) => prologue,
+            None => &mut self.lexer,
+        };
+        for item in lexer {
+            let mut newline = false;
+            if let Ok(token) = &item {
+                end = token.location;
+                newline = token.value == LexerTokenValue::NewLine;
+            }
+            self.lookahead.push_back(item);
+            if newline {
+                break;
+            }
+        }
+        end
+    }
+
+    // Switches from the prologue to the input, which starts with its own line numbering and is
+    // considered to have nothing before it for #version and #extension.
+    fn end_prologue(&mut self) -> Step<()> {
+        self.prologue = None;
+        self.line_offset = 0;
+        self.line_overflowed = false;
+        self.had_directive = false;
+        self.had_non_directive_token = false;
+
+        // Conditional blocks can't continue in the input.
+        let outermost = self.blocks.drain(..).next();
+        if let Some(outermost) = outermost {
+            self.skipping = false;
+            return Err(StepExit::Error((
+                PreprocessorError::UnfinishedBlock,
+                outermost.start_location,
+            )));
+        }
+        Ok(())
+    }
+
     fn expect_a_lexer_token(&mut self, current_location: Location) -> Step<LexerToken> {
         match self.get_lexer_token() {
             Ok(token) => Ok(token),
@@ -165,6 +835,8 @@ impl<'a> DirectiveProcessor<'a> {
         let token = self.expect_a_lexer_token(current_location)?;
         if token.value == expected {
             Ok(token.location)
+        } else if expected == LexerTokenValue::NewLine {
+            Err(make_expected_error(ExpectedKind::EndOfLine, token))
         } else {
             Err(make_unexpected_error(token))
         }
@@ -175,7 +847,26 @@ impl<'a> DirectiveProcessor<'a> {
         if let LexerTokenValue::Ident(name) = token.value {
             Ok((name, token.location))
         } else {
-            Err(make_unexpected_error(token))
+            Err(make_expected_error(ExpectedKind::Identifier, token))
+        }
+    }
+
+    // Handles what follows `#else` and `#endif`, see `strict_directive_trailing`. The rest of the
+    // line is always consumed.
+    fn finish_directive_line(&mut self, directive_location: Location) -> Step<()> {
+        if !self.options.strict_directive_trailing {
+            return self.consume_until_newline();
+        }
+
+        let token = self.expect_a_lexer_token(directive_location)?;
+        if token.value == LexerTokenValue::NewLine {
+            Ok(())
+        } else {
+            self.consume_until_newline()?;
+            Err(StepExit::Error((
+                PreprocessorError::ExtraTokensAfterDirective,
+                token.location,
+            )))
         }
     }
 
@@ -205,86 +896,30 @@ impl<'a> DirectiveProcessor<'a> {
         }
 
         let (name, name_location) = self.expect_lexer_ident(directive_location)?;
-
-        // TODO validate the name?
-        let mut define = Define {
-            name,
-            function_like: false,
-            params: Default::default(),
-            tokens: Default::default(),
-        };
+        check_reserved_macro_name(&name, name_location)?;
 
         // TODO what if token is none? EOF but still need to check it is not a redefinition?
-        let mut token = self.get_lexer_token()?;
-
-        // The is a function-like define, parse the argument list, leaving token as the next
-        // token.
-        if token.value == Punct::LeftParen.into() && !token.leading_whitespace {
-            define.function_like = true;
-            let mut first_param = true;
-            loop {
-                token = self.get_lexer_token()?;
-                match &token.value {
-                    LexerTokenValue::Punct(Punct::RightParen) => {
-                        token = self.get_lexer_token()?;
-                        break;
-                    }
-
-                    LexerTokenValue::Ident(param_name) => {
-                        if !first_param {
-                            return Err(make_unexpected_error(token));
-                        }
-                        first_param = false;
-                        define
-                            .params
-                            .insert(param_name.clone(), define.params.len());
-                    }
-
-                    LexerTokenValue::Punct(Punct::Comma) => {
-                        if first_param {
-                            return Err(make_unexpected_error(token));
-                        }
-
-                        let (param_name, param_location) =
-                            self.expect_lexer_ident(token.location)?;
-                        if define.params.contains_key(&param_name) {
-                            return Err(StepExit::Error((
-                                PreprocessorError::DuplicateParameter,
-                                param_location,
-                            )));
-                        }
-                        define.params.insert(param_name, define.params.len());
-                    }
-                    _ => {
-                        return Err(make_unexpected_error(token));
-                    }
-                }
-            }
-        }
-
-        // Tokens until the newline are that define's tokens (including the current one)
-        loop {
-            if token.value == LexerTokenValue::NewLine {
-                break;
-            }
-            define.tokens.push(convert_lexer_token_to_step(token)?);
-            token = self.get_lexer_token()?;
-        }
+        let define = parse_define(name, &mut || self.get_lexer_token())?;
+        let name = define.name.clone();
 
         // Defines are allowed to be redefined if they are exactly the same up to token locations.
-        if let Some(previous_define) = self.defines.get(&define.name) {
-            if legal_redefinition(&*previous_define, &define) {
-                Ok(())
-            } else {
-                Err(StepExit::Error((
+        if let Some(previous_define) = self.defines.get(&name) {
+            if !legal_redefinition(previous_define, &define) {
+                return Err(StepExit::Error((
                     PreprocessorError::DefineRedefined,
                     name_location,
-                )))
+                )));
             }
         } else {
+            if self.options.warn_unused_macros && self.prologue.is_none() {
+                self.defined_macros.push((name.clone(), name_location));
+            }
             self.defines.insert(define.name.clone(), Rc::new(define));
-            Ok(())
+            self.macro_generation += 1;
         }
+
+        self.emit_event(PreprocessEvent::Define { name });
+        Ok(())
     }
 
     fn add_define(
@@ -292,35 +927,12 @@ impl<'a> DirectiveProcessor<'a> {
         name: &str,
         content: &str,
     ) -> Result<(), (PreprocessorError, Location)> {
-        let mut define = Define {
-            name: name.to_string(),
-            function_like: false,
-            params: Default::default(),
-            tokens: Default::default(),
-        };
-
-        // Convert the content to tokens and add it to the define.
-        let mut lexer = lexer::Lexer::new(content);
-        loop {
-            match lexer.next() {
-                Some(Ok(lexer_token)) => {
-                    // Skip over newlines (the lexer always adds a newline, which would cause an
-                    // error in convert_lexer_token).
-                    if lexer_token.value == LexerTokenValue::NewLine {
-                        continue;
-                    }
-
-                    define.tokens.push(convert_lexer_token(lexer_token)?);
-                }
-
-                Some(Err(err)) => return Err(err),
-                None => break,
-            }
-        }
+        let define = make_define(name, content)?;
 
         // Note this overwrites existing defines, we might want to add an option to make this
         // an error in the future.
         self.defines.insert(define.name.clone(), Rc::new(define));
+        self.macro_generation += 1;
 
         Ok(())
     }
@@ -331,11 +943,16 @@ impl<'a> DirectiveProcessor<'a> {
         }
 
         let (name, name_location) = self.expect_lexer_ident(directive_location)?;
-        // TODO check predefine
+        check_reserved_macro_name(&name, name_location)?;
         // It is valid to undef a name that is not defined.
-        self.defines.remove(&name);
+        if self.defines.remove(&name).is_some() {
+            self.macro_generation += 1;
+            // Only the definition that is still there when the input ends can be reported.
+            self.defined_macros.retain(|(defined, _)| *defined != name);
+        }
 
         self.expect_lexer_token(LexerTokenValue::NewLine, name_location)?;
+        self.emit_event(PreprocessEvent::Undef { name });
         Ok(())
     }
 
@@ -358,7 +975,15 @@ impl<'a> DirectiveProcessor<'a> {
 
         let line = self.gather_until_newline()?;
 
-        let mut parser = if_parser::IfParser::new(line, &self.defines, directive_location, false);
+        let mut parser = if_parser::IfParser::new(
+            line,
+            &self.defines,
+            &self.enabled_extensions,
+            directive_location,
+            false,
+            self.options.max_expansion_tokens,
+            self.source_string,
+        );
         let line = parser.evaluate_expression()?;
 
         // Validates that the line is between 0 and 2^31 as per the C standard.
@@ -366,10 +991,14 @@ impl<'a> DirectiveProcessor<'a> {
             return Err(make_line_overflow_error(directive_location));
         }
         self.line_offset = line - directive_location.line as i64;
+        self.line_overflowed = false;
 
+        // The optional second argument is the source string number that __FILE__ expands to.
         if parser.peek()?.is_some() {
-            // TODO figure out what to do with the file, either number or string?
-            let _source_id = parser.evaluate_expression()?;
+            let source_string = parser.evaluate_expression()?;
+            self.source_string = u32::try_from(source_string).map_err(|_| {
+                StepExit::Error((PreprocessorError::IntegerOverflow, directive_location))
+            })?;
         }
 
         if let Some(token) = parser.peek()? {
@@ -383,17 +1012,64 @@ impl<'a> DirectiveProcessor<'a> {
     }
 
     fn evaluate_if_expression(&mut self, location: Location, line: Vec<Token>) -> Step<bool> {
-        let mut parser = if_parser::IfParser::new(line, &self.defines, location, true);
-        let res = parser.evaluate_expression()?;
+        // The expression given to the event handler contains locations, so it can't be reused, and
+        // the identifier resolver could give different values each time.
+        let cache_key = if self.options.cache_conditions
+            && self.event_handler.is_none()
+            && self.identifier_resolver.is_none()
+        {
+            if self.condition_cache_generation != self.macro_generation {
+                self.condition_cache.clear();
+                self.condition_cache_generation = self.macro_generation;
+            }
+            let key = condition_cache_key(&line, self.source_string);
+            if let Some(&value) = self.condition_cache.get(&key) {
+                return Ok(value);
+            }
+            Some(key)
+        } else {
+            None
+        };
+
+        let mut parser = if_parser::IfParser::new(
+            line,
+            &self.defines,
+            &self.enabled_extensions,
+            location,
+            true,
+            self.options.max_expansion_tokens,
+            self.source_string,
+        );
+        parser.resolve_identifier = self.identifier_resolver.as_deref();
+        if self.options.warn_unused_macros {
+            parser.track_used_macros();
+        }
+        if self.options.allow_trailing_argument_comma {
+            parser.allow_trailing_argument_comma();
+        }
+        if self.options.bracket_argument_commas {
+            parser.bracket_argument_commas();
+        }
+        let expression = parser.parse_expression()?;
 
         if let Some(token) = parser.peek()? {
-            Err(StepExit::Error((
+            return Err(StepExit::Error((
                 PreprocessorError::UnexpectedToken(token.value),
                 token.location,
-            )))
-        } else {
-            Ok(res != 0)
+            )));
         }
+
+        let value = expression.evaluate().map_err(StepExit::Error)? != 0;
+        let used_macros = parser.take_used_macros();
+        self.used_macros.extend(used_macros);
+        if let Some(key) = cache_key {
+            // __LINE__ makes the value depend on where the condition is.
+            if !parser.used_line() {
+                self.condition_cache.insert(key, value);
+            }
+        }
+        self.emit_event(PreprocessEvent::Condition { expression, value });
+        Ok(value)
     }
 
     fn parse_if_directive(&mut self, directive_location: Location) -> Step<()> {
@@ -403,24 +1079,35 @@ impl<'a> DirectiveProcessor<'a> {
         })
     }
 
+    // Parses the rest of a #ifdef or #ifndef, returning whether the macro is defined.
+    fn parse_ifdef_condition(&mut self, directive_location: Location) -> Step<bool> {
+        let (name, name_location) = self.expect_lexer_ident(directive_location)?;
+        if self.options.allow_extra_ifdef_tokens {
+            self.consume_until_newline()?;
+        } else {
+            self.expect_lexer_token(LexerTokenValue::NewLine, name_location)?;
+        }
+        let defined = self.defines.contains_key(&name);
+        if self.options.warn_unused_macros {
+            self.used_macros.insert(name);
+        }
+        Ok(defined)
+    }
+
     fn parse_ifdef_directive(&mut self, directive_location: Location) -> Step<()> {
         self.parse_if_like_directive(directive_location, |this, location| {
-            let (name, name_location) = this.expect_lexer_ident(location)?;
-            this.expect_lexer_token(LexerTokenValue::NewLine, name_location)?;
-            Ok(this.defines.contains_key(&name))
+            this.parse_ifdef_condition(location)
         })
     }
 
     fn parse_ifndef_directive(&mut self, directive_location: Location) -> Step<()> {
         self.parse_if_like_directive(directive_location, |this, location| {
-            let (name, name_location) = this.expect_lexer_ident(location)?;
-            this.expect_lexer_token(LexerTokenValue::NewLine, name_location)?;
-            Ok(!this.defines.contains_key(&name))
+            Ok(!this.parse_ifdef_condition(location)?)
         })
     }
 
     fn parse_elif_directive(&mut self, directive_location: Location) -> Step<()> {
-        self.skipping = true;
+        self.set_skipping(true, directive_location);
 
         // Do checks that the #elif block is well structured even if skipping.
         let block = self.blocks.last().ok_or(StepExit::Error((
@@ -442,7 +1129,7 @@ impl<'a> DirectiveProcessor<'a> {
 
         let line = self.gather_until_newline()?;
         if self.evaluate_if_expression(directive_location, line)? {
-            self.skipping = false;
+            self.set_skipping(false, directive_location);
             self.blocks.last_mut().unwrap().had_valid_segment = true;
         }
 
@@ -450,8 +1137,6 @@ impl<'a> DirectiveProcessor<'a> {
     }
 
     fn parse_else_directive(&mut self, directive_location: Location) -> Step<()> {
-        self.expect_lexer_token(LexerTokenValue::NewLine, directive_location)?;
-
         let block = self.blocks.last_mut().ok_or(StepExit::Error((
             PreprocessorError::ElseOutsideOfBlock,
             directive_location,
@@ -464,9 +1149,10 @@ impl<'a> DirectiveProcessor<'a> {
                 directive_location,
             )))
         } else {
-            self.skipping = block.outer_skipped || block.had_valid_segment;
+            let skipping = block.outer_skipped || block.had_valid_segment;
             block.had_else = true;
-            Ok(())
+            self.set_skipping(skipping, directive_location);
+            self.finish_directive_line(directive_location)
         }
     }
 
@@ -476,14 +1162,21 @@ impl<'a> DirectiveProcessor<'a> {
             directive_location,
         )))?;
 
+        if self.options.warn_empty_conditionals && !block.had_content {
+            self.warnings
+                .push((PreprocessorWarning::EmptyConditional, block.start_location));
+            self.emit_event(PreprocessEvent::EmptyConditional {
+                location: block.start_location,
+            });
+        }
+
         // After #endif we start processing tokens iff the block was not skipped.
-        self.skipping = block.outer_skipped;
+        self.set_skipping(block.outer_skipped, directive_location);
 
         if self.skipping {
             self.consume_until_newline()
         } else {
-            self.expect_lexer_token(LexerTokenValue::NewLine, directive_location)?;
-            Ok(())
+            self.finish_directive_line(directive_location)
         }
     }
 
@@ -498,34 +1191,57 @@ impl<'a> DirectiveProcessor<'a> {
                 had_valid_segment: false,
                 had_else: false,
                 outer_skipped: true,
+                had_content: false,
             });
             self.consume_until_newline()
         } else {
             let result = parse(self, directive_location)?;
-            self.skipping = !result;
+            self.set_skipping(!result, directive_location);
 
             self.blocks.push(DirectiveBlock {
                 start_location: directive_location,
                 had_valid_segment: !self.skipping,
                 had_else: false,
                 outer_skipped: false,
+                had_content: false,
             });
             Ok(())
         }
     }
 
-    fn parse_version_directive(&mut self, directive_location: Location) -> Step<Token> {
+    fn parse_version_directive(
+        &mut self,
+        hash_location: Location,
+        directive_location: Location,
+    ) -> Step<Token> {
         if self.skipping {
             self.consume_until_newline()?;
             Continue.into()
         } else {
+            let tokens = self.gather_until_newline()?;
+
+            let number = match tokens.first() {
+                Some(Token {
+                    value: TokenValue::Integer(Integer { value, .. }),
+                    ..
+                }) => Some(*value),
+                _ => None,
+            };
+            if !self.options.preserve_directives {
+                self.emit_event(PreprocessEvent::Version { number });
+            }
+
             Ok(Token {
                 location: directive_location,
                 value: TokenValue::Version(Version {
-                    tokens: self.gather_until_newline()?,
+                    tokens,
                     is_first_directive: !(self.had_directive || self.had_non_directive_token),
                     has_comments_before: self.lexer.had_comments(),
+                    hash_location,
+                    name_location: directive_location,
                 }),
+                raw: None,
+                invocation: None,
             })
         }
     }
@@ -535,42 +1251,126 @@ impl<'a> DirectiveProcessor<'a> {
             self.consume_until_newline()?;
             Continue.into()
         } else {
+            let tokens = self.gather_until_newline()?;
+            if !self.options.preserve_directives {
+                self.track_extension(&tokens);
+            }
+
             Ok(Token {
                 location: directive_location,
                 value: TokenValue::Extension(Extension {
-                    tokens: self.gather_until_newline()?,
+                    tokens,
                     has_non_directive_before: self.had_non_directive_token,
                 }),
+                raw: None,
+                invocation: None,
             })
         }
     }
 
+    // Updates the enabled extensions for `#extension NAME : behavior`, other forms are left for
+    // the user of the Extension token to validate.
+    fn track_extension(&mut self, tokens: &[Token]) {
+        if let [Token {
+            value: TokenValue::Ident(name),
+            ..
+        }, Token {
+            value: TokenValue::Punct(Punct::Colon),
+            ..
+        }, Token {
+            value: TokenValue::Ident(behavior),
+            ..
+        }] = tokens
+        {
+            self.macro_generation += 1;
+            match (name.as_str(), behavior.as_str()) {
+                ("all", "disable") => self.enabled_extensions.clear(),
+                ("all", _) => {}
+                (_, "require") | (_, "enable") | (_, "warn") => {
+                    self.enabled_extensions.insert(name.clone());
+                }
+                (_, "disable") => {
+                    self.enabled_extensions.remove(name);
+                }
+                _ => {}
+            }
+        }
+    }
+
     fn parse_pragma_directive(&mut self, directive_location: Location) -> Step<Token> {
         if self.skipping {
             self.consume_until_newline()?;
             Continue.into()
         } else {
+            let tokens = self.gather_until_newline()?;
+            if !self.options.preserve_directives {
+                self.interpret_pragma(&tokens)?;
+            }
+
             Ok(Token {
                 location: directive_location,
-                value: TokenValue::Pragma(Pragma {
-                    tokens: self.gather_until_newline()?,
-                }),
+                value: TokenValue::Pragma(Pragma { tokens }),
+                raw: None,
+                invocation: None,
             })
         }
     }
 
+    // Handles the pragmas known by the preprocessor, other ones are only output.
+    fn interpret_pragma(&mut self, tokens: &[Token]) -> Step<()> {
+        if let Some(Token {
+            value: TokenValue::Ident(namespace),
+            ..
+        }) = tokens.first()
+        {
+            if namespace == "STDGL" {
+                self.emit_event(PreprocessEvent::StdglPragma {
+                    tokens: tokens[1..].to_vec(),
+                });
+            } else if namespace == "shader_stage" {
+                self.shader_stage = Some(parse_shader_stage(tokens)?);
+            } else if namespace == "optimize" {
+                let pragma = StdPragma::Optimize(parse_pragma_switch(tokens)?);
+                self.emit_event(PreprocessEvent::StdPragma { pragma });
+            } else if namespace == "debug" {
+                let pragma = StdPragma::Debug(parse_pragma_switch(tokens)?);
+                self.emit_event(PreprocessEvent::StdPragma { pragma });
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_unknown_directive(&mut self, directive_location: Location) -> Step<()> {
+        if !self.skipping && self.options.error_on_unknown_directive {
+            return Err(StepExit::Error((
+                PreprocessorError::UnknownDirective,
+                directive_location,
+            )));
+        }
+        self.consume_until_newline()
+    }
+
     fn parse_directive(&mut self, hash_location: Location) -> Step<Token> {
         let token = self.expect_a_lexer_token(hash_location)?;
 
+        match token.value {
+            LexerTokenValue::Ident(ref directive)
+                if directive == "elif" || directive == "else" || directive == "endif" => {}
+            _ => self.mark_block_content(),
+        }
+
         if let LexerTokenValue::Ident(ref directive) = token.value {
             match directive.as_str() {
+                "define" => self.parse_define_directive(token.location)?,
+                "undef" => self.parse_undef_directive(token.location)?,
+
+                // Only #define and #undef are known in macros-only mode.
+                _ if self.options.macros_only => self.parse_unknown_directive(token.location)?,
+
                 // TODO elif line
                 "error" => self.parse_error_directive(token.location)?,
                 "line" => self.parse_line_directive(token.location)?,
 
-                "define" => self.parse_define_directive(token.location)?,
-                "undef" => self.parse_undef_directive(token.location)?,
-
                 "if" => self.parse_if_directive(token.location)?,
                 "ifdef" => self.parse_ifdef_directive(token.location)?,
                 "ifndef" => self.parse_ifndef_directive(token.location)?,
@@ -579,7 +1379,7 @@ impl<'a> DirectiveProcessor<'a> {
                 "endif" => self.parse_endif_directive(token.location)?,
 
                 "version" => {
-                    let result = self.parse_version_directive(token.location);
+                    let result = self.parse_version_directive(hash_location, token.location);
                     self.had_directive = true;
                     return result;
                 }
@@ -593,14 +1393,7 @@ impl<'a> DirectiveProcessor<'a> {
                     self.had_directive = true;
                     return result;
                 }
-                _ => {
-                    if !self.skipping {
-                        return Err(StepExit::Error((
-                            PreprocessorError::UnknownDirective,
-                            token.location,
-                        )));
-                    }
-                }
+                _ => self.parse_unknown_directive(token.location)?,
             }
             self.had_directive = true;
             Continue.into()
@@ -617,11 +1410,34 @@ impl<'a> MELexer for DirectiveProcessor<'a> {
         let step = (|| {
             // TODO: if we are skipping invalid characters should be allowed.
             let lexer_token = self.get_lexer_token()?;
+
+            // Lines past u32::MAX saturate, the first one is reported before its first token.
+            if !self.line_overflowed
+                && lexer_token.location.line as i64 + self.line_offset > u32::MAX as i64
+            {
+                self.line_overflowed = true;
+                let location = lexer_token.location;
+                self.pending_token = Some(lexer_token);
+                return Err(make_line_overflow_error(location));
+            }
             match lexer_token.value {
                 LexerTokenValue::NewLine => Continue.into(),
                 LexerTokenValue::Hash => {
                     if lexer_token.start_of_line {
-                        self.parse_directive(lexer_token.location)
+                        let step = self.parse_directive(lexer_token.location);
+                        // A `#` where the directive expected something else, like the second one
+                        // of `# # define`, doesn't end the line: skip the rest so it isn't output.
+                        if let Err(StepExit::Error((PreprocessorError::UnexpectedHash, _))) = step {
+                            let _ = self.consume_until_newline();
+                        }
+                        if let Err(StepExit::Error((_, location))) = step {
+                            let span = Span {
+                                start: lexer_token.location,
+                                end: self.directive_line_end(),
+                            };
+                            self.directive_error_span = Some((location, span));
+                        }
+                        step
                     } else if !self.skipping {
                         make_unexpected_error(lexer_token).into()
                     } else {
@@ -630,6 +1446,7 @@ impl<'a> MELexer for DirectiveProcessor<'a> {
                 }
 
                 _ => {
+                    self.mark_block_content();
                     if !self.skipping {
                         self.had_non_directive_token = true;
                         convert_lexer_token_to_step(lexer_token)
@@ -640,13 +1457,15 @@ impl<'a> MELexer for DirectiveProcessor<'a> {
             }
         })();
 
-        if step == Err(StepExit::Finished) {
-            if let Some(block) = self.blocks.pop() {
-                return Err(StepExit::Error((
-                    PreprocessorError::UnfinishedBlock,
-                    block.start_location,
-                )));
-            }
+        // Report the outermost unfinished block, as the inner ones might be closed by the #endif
+        // that was meant for the outer one.
+        if step == Err(StepExit::Finished) && !self.blocks.is_empty() && !self.options.partial_input
+        {
+            let outermost = self.blocks.drain(..).next().unwrap();
+            return Err(StepExit::Error((
+                PreprocessorError::UnfinishedBlock,
+                outermost.start_location,
+            )));
         }
 
         step
@@ -656,25 +1475,62 @@ impl<'a> MELexer for DirectiveProcessor<'a> {
         self.defines.get(name)
     }
 
-    fn apply_line_offset(&self, line: u32, location: Location) -> Step<u32> {
-        if let Ok(offset_line) = u32::try_from(line as i64 + self.line_offset) {
-            Ok(offset_line)
-        } else {
-            Err(make_line_overflow_error(location))
-        }
+    fn apply_line_offset(&self, line: u32, _: Location) -> Step<u32> {
+        // #line only accepts positive values so the line can only go past the maximum, which
+        // is reported separately.
+        Ok(u32::try_from(line as i64 + self.line_offset).unwrap_or(u32::MAX))
+    }
+
+    fn source_string(&self) -> u32 {
+        self.source_string
     }
 }
 
-#[derive(Default)]
 struct MacroProcessor {
     define_invocations: Vec<DefineInvocation>,
     defines_being_expanded: HashSet<String>,
 
     peeked: Option<Step<Token>>,
     define_line: u32,
+
+    max_expansion_tokens: Option<usize>,
+    // The number of tokens produced by the current top-level invocation and its location.
+    expansion_tokens: usize,
+    expansion_location: Location,
+    // The number of invocations started, including the ones in arguments.
+    expansion_count: usize,
+    // The name and location of each invocation, only recorded if record_uses is set.
+    record_uses: bool,
+    uses: Vec<(String, Location)>,
+    // The names of the macros invoked, only recorded if track_used is set.
+    track_used: bool,
+    used: HashSet<String>,
+    allow_trailing_argument_comma: bool,
+    bracket_argument_commas: bool,
 }
 
 impl MacroProcessor {
+    fn new(max_expansion_tokens: Option<usize>) -> Self {
+        MacroProcessor {
+            define_invocations: Default::default(),
+            defines_being_expanded: Default::default(),
+
+            peeked: None,
+            define_line: 0,
+
+            max_expansion_tokens,
+            expansion_tokens: 0,
+            expansion_location: Location::default(),
+            expansion_count: 0,
+            record_uses: false,
+            uses: Vec::new(),
+            track_used: false,
+            used: Default::default(),
+            allow_trailing_argument_comma: false,
+            bracket_argument_commas: false,
+        }
+    }
+
     fn start_define_invocation(
         &mut self,
         name: &str,
@@ -692,14 +1548,17 @@ impl MacroProcessor {
                 define_position: 0,
 
                 parameters: Default::default(),
+                parameter_origins: Default::default(),
                 parameter_position: 0,
-                parameter_expanding: std::usize::MAX,
+                parameter_expanding: usize::MAX,
             };
 
             // If this is a not a function-like define, __LINE__ inside the define is the line of the first
             // character of the invocation. Only the line of the top-level invocation counts.
             if !self.is_expanding_define() {
                 self.define_line = location.line;
+                self.expansion_tokens = 0;
+                self.expansion_location = location;
             }
 
             if invocation.define.function_like {
@@ -707,6 +1566,7 @@ impl MacroProcessor {
                     Ok(Token {
                         value: TokenValue::Punct(Punct::LeftParen),
                         location,
+                        ..
                     }) => location,
 
                     // Function-like macros are not processed if there is no ( right after the identifier
@@ -715,12 +1575,22 @@ impl MacroProcessor {
                         return Ok(false);
                     }
                 };
+                self.record_use(name, location);
 
                 // TODO still bail out if define was undefined until now? This would match
                 // clang and GCC
-                let (parameters, closing_location) =
+                let (mut parameters, closing_location) =
                     self.parse_define_call_arguments(lexer, lparen_location)?;
 
+                // The empty argument after a trailing comma is dropped if it is one too many.
+                if self.allow_trailing_argument_comma
+                    && parameters.len() == invocation.define.params.len() + 1
+                    && parameters.len() > 1
+                    && parameters[parameters.len() - 1].is_empty()
+                {
+                    parameters.pop();
+                }
+
                 if !self.is_expanding_define() {
                     self.define_line = closing_location.line;
                 }
@@ -749,16 +1619,19 @@ impl MacroProcessor {
 
                 // Fully expand the parameters
                 for parameter in parameters {
-                    invocation
-                        .parameters
-                        .push(self.expand_parameter(lexer, parameter)?);
+                    let (tokens, origins) = self.expand_parameter(lexer, parameter)?;
+                    invocation.parameters.push(tokens);
+                    invocation.parameter_origins.push(origins);
                 }
+            } else {
+                self.record_use(name, location);
             }
 
             assert!(self
                 .defines_being_expanded
                 .insert(invocation.define.name.clone()));
             self.define_invocations.push(invocation);
+            self.expansion_count += 1;
 
             return Ok(true);
         }
@@ -766,6 +1639,19 @@ impl MacroProcessor {
         Ok(false)
     }
 
+    fn record_use(&mut self, name: &str, location: Location) {
+        if self.record_uses {
+            self.uses.push((name.to_string(), location));
+        }
+        self.record_used(name);
+    }
+
+    fn record_used(&mut self, name: &str) {
+        if self.track_used && !self.used.contains(name) {
+            self.used.insert(name.to_string());
+        }
+    }
+
     // Parse the arguments of the function-like define starting after the first (. Also returns
     // the location of the closing ).
     fn parse_define_call_arguments(
@@ -774,6 +1660,9 @@ impl MacroProcessor {
         mut current_location: Location,
     ) -> Step<(Vec<Vec<Token>>, Location)> {
         let mut paren_nesting = 0u32;
+        // Commas inside of [] are part of the argument too if bracket_argument_commas is set, but
+        // only () delimit the arguments.
+        let mut bracket_nesting = 0u32;
         let mut arguments = vec![vec![]];
 
         loop {
@@ -819,12 +1708,17 @@ impl MacroProcessor {
                     paren_nesting -= 1;
                 }
 
-                TokenValue::Punct(Punct::Comma) => {
-                    // Commas outside of () split arguments and must not be added to them.
-                    if paren_nesting == 0 {
-                        arguments.push(Default::default());
-                        continue;
-                    }
+                TokenValue::Punct(Punct::LeftBracket) if self.bracket_argument_commas => {
+                    bracket_nesting = bracket_nesting.saturating_add(1);
+                }
+                TokenValue::Punct(Punct::RightBracket) if self.bracket_argument_commas => {
+                    bracket_nesting = bracket_nesting.saturating_sub(1);
+                }
+
+                // Commas outside of () (and [] if enabled) split arguments and must not be added to them.
+                TokenValue::Punct(Punct::Comma) if paren_nesting == 0 && bracket_nesting == 0 => {
+                    arguments.push(Default::default());
+                    continue;
                 }
 
                 _ => {}
@@ -834,7 +1728,14 @@ impl MacroProcessor {
         }
     }
 
-    fn expand_parameter(&self, lexer: &mut dyn MELexer, parameter: Vec<Token>) -> Step<Vec<Token>> {
+    // Returns the expanded tokens of the parameter along with the location each of them comes
+    // from in the invocation, which is the location of the macro invocation for tokens produced by
+    // expanding a macro.
+    fn expand_parameter(
+        &mut self,
+        lexer: &mut dyn MELexer,
+        parameter: Vec<Token>,
+    ) -> Step<(Vec<Token>, Vec<Location>)> {
         struct ExpandParameterLexer<'a> {
             parent_lexer: &'a dyn MELexer,
             expander: &'a MacroProcessor,
@@ -863,6 +1764,10 @@ impl MacroProcessor {
             fn apply_line_offset(&self, line: u32, _: Location) -> Step<u32> {
                 Ok(line)
             }
+
+            fn source_string(&self) -> u32 {
+                self.parent_lexer.source_string()
+            }
         }
 
         let mut parameter_lexer = ExpandParameterLexer {
@@ -872,12 +1777,17 @@ impl MacroProcessor {
             position: 0,
         };
 
-        let mut processor: MacroProcessor = Default::default();
-        let mut expanded_parameters = Default::default();
+        let mut processor = MacroProcessor::new(self.max_expansion_tokens);
+        processor.record_uses = self.record_uses;
+        processor.track_used = self.track_used;
+        processor.allow_trailing_argument_comma = self.allow_trailing_argument_comma;
+        processor.bracket_argument_commas = self.bracket_argument_commas;
+        let mut expanded_parameters = Vec::new();
+        let mut origins = Vec::new();
         loop {
             match processor.step(&mut parameter_lexer) {
                 Err(err @ StepExit::Error(_)) => return Err(err),
-                Err(StepExit::Finished) => return Ok(expanded_parameters),
+                Err(StepExit::Finished) => break,
                 Err(StepExit::Continue) => continue,
                 Ok(token) => {
                     if let TokenValue::Ident(name) = &token.value {
@@ -890,16 +1800,44 @@ impl MacroProcessor {
                         }
                     }
 
+                    processor.count_expanded_token()?;
+                    origins.push(if processor.is_expanding_define() {
+                        processor.expansion_location
+                    } else {
+                        token.location
+                    });
                     expanded_parameters.push(token);
                 }
             }
         }
+
+        self.expansion_count += processor.expansion_count;
+        self.uses.append(&mut processor.uses);
+        self.used.extend(processor.used);
+        Ok((expanded_parameters, origins))
     }
 
     fn is_expanding_define(&self) -> bool {
         !self.define_invocations.is_empty()
     }
 
+    // Must be called for each token that is output while expanding a define, checks that the
+    // top-level invocation doesn't produce more tokens than allowed.
+    fn count_expanded_token(&mut self) -> Step<()> {
+        if !self.is_expanding_define() {
+            return Ok(());
+        }
+
+        self.expansion_tokens += 1;
+        match self.max_expansion_tokens {
+            Some(max) if self.expansion_tokens > max => Err(StepExit::Error((
+                PreprocessorError::ExpansionTooLarge,
+                self.expansion_location,
+            ))),
+            _ => Ok(()),
+        }
+    }
+
     fn step_internal(&mut self, lexer: &mut dyn MELexer) -> Step<Token> {
         if let Some(step) = self.peeked.take() {
             return step;
@@ -912,7 +1850,7 @@ impl MacroProcessor {
                     invocation.parameter_position += 1;
                     return Ok(token.clone());
                 } else {
-                    invocation.parameter_expanding = std::usize::MAX;
+                    invocation.parameter_expanding = usize::MAX;
                     return Continue.into();
                 }
             }
@@ -960,6 +1898,21 @@ impl MacroProcessor {
                         width: 32,
                     }),
                     location: token.location,
+                    raw: None,
+                    invocation: None,
+                });
+            }
+
+            if name == "__FILE__" {
+                return Ok(Token {
+                    value: TokenValue::Integer(Integer {
+                        value: lexer.source_string() as u64,
+                        signed: false,
+                        width: 32,
+                    }),
+                    location: token.location,
+                    raw: None,
+                    invocation: None,
                 });
             }
         }
@@ -980,16 +1933,82 @@ impl MacroProcessor {
 pub struct Preprocessor<'a> {
     directive_processor: DirectiveProcessor<'a>,
     macro_processor: MacroProcessor,
+    token_count: usize,
+    // Set once max_total_tokens is exceeded, after which nothing else is output.
+    token_limit_exceeded: bool,
+    // Whether the warnings of warn_unused_macros were given, at the end of the input.
+    unused_macros_reported: bool,
+    // The top-level macro invocation being expanded, only recorded if record_invocations is set.
+    invocation: Option<(String, Location)>,
+}
+
+/// Simple statistics about a preprocessor run, as a rough measure of the complexity of a shader.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct LexStats {
+    /// The number of tokens output by the preprocessor.
+    pub token_count: usize,
+    /// The number of lines in the input.
+    pub line_count: usize,
+    /// The number of comments in the input.
+    pub comment_count: usize,
+    /// The number of macro invocations that were expanded, including nested ones.
+    pub macro_expansions: usize,
+}
+
+/// The state a [`Preprocessor`] carries from one chunk of input to the next, see
+/// [`Preprocessor::snapshot`].
+#[derive(Clone, Debug)]
+pub struct PreprocessorState {
+    defines: HashMap<String, Rc<Define>>,
+    skipping: bool,
+    blocks: Vec<DirectiveBlock>,
+    // The start of the excluded region left open, in the chunk it started in.
+    excluded_region_start: Location,
+    // The line offset for the next chunk, which continues after the last line of this one.
+    line_offset: i64,
+    line_overflowed: bool,
+    source_string: u32,
+    shader_stage: Option<ShaderStage>,
+    enabled_extensions: HashSet<String>,
+    had_directive: bool,
+    had_non_directive_token: bool,
+    defined_macros: Vec<(String, Location)>,
+    used_macros: HashSet<String>,
 }
 
 impl<'a> Preprocessor<'a> {
-    pub fn new(input: &'a str) -> Preprocessor {
+    pub fn new(input: &'a str) -> Preprocessor<'a> {
+        Self::with_options(input, Default::default())
+    }
+
+    pub fn with_options(input: &'a str, options: PreprocessorOptions) -> Preprocessor<'a> {
+        let mut macro_processor = MacroProcessor::new(options.max_expansion_tokens);
+        macro_processor.record_uses = options.record_macro_uses;
+        macro_processor.track_used = options.warn_unused_macros;
+        macro_processor.allow_trailing_argument_comma = options.allow_trailing_argument_comma;
+        macro_processor.bracket_argument_commas = options.bracket_argument_commas;
         Preprocessor {
-            directive_processor: DirectiveProcessor::new(input),
-            macro_processor: Default::default(),
+            macro_processor,
+            directive_processor: DirectiveProcessor::new(input, options),
+            token_count: 0,
+            token_limit_exceeded: false,
+            unused_macros_reported: false,
+            invocation: None,
         }
     }
 
+    /// Creates a preprocessor in macros-only mode, where directives other than `#define` and
+    /// `#undef` are errors. See [`PreprocessorOptions::macros_only`] to ignore them instead.
+    pub fn macros_only(input: &'a str) -> Preprocessor<'a> {
+        Self::with_options(
+            input,
+            PreprocessorOptions {
+                macros_only: true,
+                ..Default::default()
+            },
+        )
+    }
+
     pub fn add_define(
         &mut self,
         name: &str,
@@ -998,27 +2017,487 @@ impl<'a> Preprocessor<'a> {
         self.directive_processor.add_define(name, content)
     }
 
+    /// Sets a prologue that is preprocessed before the input, like a common header. The macros it
+    /// defines and the extensions it enables apply to the input, and its tokens are output before
+    /// the input's.
+    ///
+    /// Unlike concatenating the prologue to the input, the input keeps its own line numbers and a
+    /// `#version` at its start is still considered the first directive. Conditional blocks must
+    /// be closed in the prologue. This must be called before reading any token.
+    pub fn set_prologue(&mut self, prologue: &'a str) {
+        let options = self.directive_processor.options.lexer;
+        self.directive_processor.prologue = Some(lexer::Lexer::with_options(prologue, options));
+    }
+
+    /// Returns the source string number set by the last `#line` directive, 0 by default.
+    pub fn source_string_number(&self) -> u32 {
+        self.directive_processor.source_string
+    }
+
+    /// Returns the regions of the input that were excluded by conditional directives so far.
+    ///
+    /// Each region goes from the directive that started the exclusion to the directive that
+    /// ended it, or to the end of the input for a region that is still open there. Nested
+    /// exclusions are part of the outermost region. With [`PreprocessorOptions::partial_input`]
+    /// a region still open at the end is reported by the preprocessor of the next chunk, see
+    /// [`Preprocessor::snapshot`], and starts at its location in the chunk it started in.
+    pub fn excluded_regions(&self) -> &[(Location, Location)] {
+        &self.directive_processor.excluded_regions
+    }
+
+    /// Returns the stage selected by the last `#pragma shader_stage(stage)` directive processed so
+    /// far, a form of `#pragma` used by shaderc. The directive is still output as a `Pragma` token.
+    pub fn shader_stage(&self) -> Option<ShaderStage> {
+        self.directive_processor.shader_stage
+    }
+
+    /// Returns whether the extension was enabled by an `#extension NAME : behavior` directive
+    /// processed so far, with a behavior of `require`, `enable` or `warn`.
+    pub fn is_extension_enabled(&self, name: &str) -> bool {
+        self.directive_processor.enabled_extensions.contains(name)
+    }
+
+    /// Returns the name and location of the macro invocations expanded so far, including the ones
+    /// in macro arguments and bodies, if [`PreprocessorOptions::record_macro_uses`] is set.
+    /// Invocations in `#if` conditions aren't recorded.
+    ///
+    /// The location of an invocation in a macro body is in the `#define` of that macro.
+    pub fn macro_uses(&self) -> &[(String, Location)] {
+        &self.macro_processor.uses
+    }
+
+    /// Returns what the macro expands to with the macros defined so far, for example to show it
+    /// when hovering its name, or `None` if it isn't defined or its expansion fails.
+    ///
+    /// Object-like macros are fully expanded, within [`PreprocessorOptions::max_expansion_tokens`].
+    /// Function-like macros are expanded one level with their parameters as arguments, which is
+    /// their body as written. The state of the preprocessor isn't changed.
+    pub fn expand_macro_preview(&self, name: &str) -> Option<Vec<Token>> {
+        let define = self.directive_processor.defines.get(name)?;
+        if define.function_like {
+            return Some(define.tokens.clone());
+        }
+
+        self.expand_tokens(vec![Token {
+            value: TokenValue::Ident(name.to_string()),
+            location: Location::default(),
+            raw: None,
+            invocation: None,
+        }])
+        .ok()
+    }
+
+    /// Expands the macros in `snippet` with the macros defined so far, like in the rest of the
+    /// input, but without processing directives. Locations are relative to `snippet`. The state
+    /// of the preprocessor isn't changed.
+    pub fn expand_str(&self, snippet: &str) -> Result<Vec<Token>, (PreprocessorError, Location)> {
+        let mut tokens = Vec::new();
+        for item in lexer::Lexer::with_options(snippet, self.directive_processor.options.lexer) {
+            let token = item?;
+            if token.value != LexerTokenValue::NewLine {
+                tokens.push(convert_lexer_token(token)?);
+            }
+        }
+        self.expand_tokens(tokens)
+    }
+
+    // Expands the macros in `tokens` with a separate MacroProcessor, so that the state of the
+    // preprocessor isn't changed.
+    fn expand_tokens(
+        &self,
+        tokens: Vec<Token>,
+    ) -> Result<Vec<Token>, (PreprocessorError, Location)> {
+        // Gives `tokens`, with the macros of the preprocessor.
+        struct ExpandTokensLexer<'a> {
+            parent_lexer: &'a dyn MELexer,
+            tokens: std::vec::IntoIter<Token>,
+        }
+
+        impl<'a> MELexer for ExpandTokensLexer<'a> {
+            fn step(&mut self) -> Step<Token> {
+                self.tokens.next().ok_or(StepExit::Finished)
+            }
+
+            fn get_define(&self, name: &str) -> Option<&Rc<Define>> {
+                self.parent_lexer.get_define(name)
+            }
+
+            fn apply_line_offset(&self, line: u32, location: Location) -> Step<u32> {
+                self.parent_lexer.apply_line_offset(line, location)
+            }
+
+            fn source_string(&self) -> u32 {
+                self.parent_lexer.source_string()
+            }
+        }
+
+        // Only the steps of the lexer finish, which is handled below.
+        let error = |exit| match exit {
+            StepExit::Error(error) => error,
+            _ => unreachable!(),
+        };
+
+        let mut lexer = ExpandTokensLexer {
+            parent_lexer: &self.directive_processor,
+            tokens: tokens.into_iter(),
+        };
+        let options = &self.directive_processor.options;
+        let mut processor = MacroProcessor::new(options.max_expansion_tokens);
+        processor.allow_trailing_argument_comma = options.allow_trailing_argument_comma;
+        processor.bracket_argument_commas = options.bracket_argument_commas;
+        let mut expanded = Vec::new();
+        loop {
+            match processor.step(&mut lexer) {
+                Err(StepExit::Finished) => return Ok(expanded),
+                Err(StepExit::Continue) => continue,
+                Err(exit) => return Err(error(exit)),
+                Ok(token) => {
+                    if let TokenValue::Ident(name) = &token.value {
+                        if processor
+                            .start_define_invocation(name, token.location, &mut lexer)
+                            .map_err(error)?
+                        {
+                            continue;
+                        }
+                    }
+
+                    processor.count_expanded_token().map_err(error)?;
+                    expanded.push(token);
+                }
+            }
+        }
+    }
+
+    /// Returns the name and location of the macros defined in the input that weren't used so far,
+    /// if [`PreprocessorOptions::warn_unused_macros`] is set. Once the input is processed they are
+    /// also reported as [`PreprocessorWarning::UnusedMacro`] warnings.
+    pub fn unused_macros(&self) -> Vec<(String, Location)> {
+        let directive_processor = &self.directive_processor;
+        directive_processor
+            .defined_macros
+            .iter()
+            .filter(|(name, _)| {
+                !directive_processor.used_macros.contains(name)
+                    && !self.macro_processor.used.contains(name)
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Removes and returns the warnings produced so far. [`Preprocessor::process_into`] gives them
+    /// to its sink, but when iterating over the tokens they are kept until they are taken.
+    pub fn take_warnings(&mut self) -> Vec<(PreprocessorWarning, Location)> {
+        core::mem::take(&mut self.directive_processor.warnings)
+    }
+
+    /// Returns the state needed to continue preprocessing in another preprocessor, once this one
+    /// processed its input: the macros, the open conditional blocks and the line numbering, as
+    /// well as the enabled extensions, the shader stage and what is needed for `#version` and
+    /// unused macros checks. This lets a host preprocess a source in chunks, with
+    /// [`PreprocessorOptions::partial_input`] set for all of them but the last.
+    ///
+    /// Each chunk continues on the line after the last line of the previous one, so `__LINE__`
+    /// and `#line` behave like for the whole source. Chunks must be split between lines that
+    /// aren't in the middle of a macro invocation or a comment, and locations are relative to the
+    /// chunk they are in.
+    pub fn snapshot(&self) -> PreprocessorState {
+        let directive_processor = &self.directive_processor;
+        PreprocessorState {
+            defines: directive_processor.defines.clone(),
+            skipping: directive_processor.skipping,
+            blocks: directive_processor.blocks.clone(),
+            excluded_region_start: directive_processor.excluded_region_start,
+            line_offset: directive_processor.line_offset
+                + directive_processor.last_location.line as i64,
+            line_overflowed: directive_processor.line_overflowed,
+            source_string: directive_processor.source_string,
+            shader_stage: directive_processor.shader_stage,
+            enabled_extensions: directive_processor.enabled_extensions.clone(),
+            had_directive: directive_processor.had_directive,
+            had_non_directive_token: directive_processor.had_non_directive_token,
+            defined_macros: directive_processor.defined_macros.clone(),
+            used_macros: directive_processor
+                .used_macros
+                .union(&self.macro_processor.used)
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Continues from the state of the preprocessor that processed the previous chunk of input,
+    /// see [`Preprocessor::snapshot`]. This must be called before reading any token, and replaces
+    /// the macros added so far and the prologue.
+    pub fn restore(&mut self, state: PreprocessorState) {
+        let directive_processor = &mut self.directive_processor;
+        directive_processor.defines = state.defines;
+        directive_processor.skipping = state.skipping;
+        directive_processor.blocks = state.blocks;
+        directive_processor.excluded_region_start = state.excluded_region_start;
+        directive_processor.line_offset = state.line_offset;
+        directive_processor.line_overflowed = state.line_overflowed;
+        directive_processor.source_string = state.source_string;
+        directive_processor.shader_stage = state.shader_stage;
+        directive_processor.enabled_extensions = state.enabled_extensions;
+        directive_processor.had_directive = state.had_directive;
+        directive_processor.had_non_directive_token = state.had_non_directive_token;
+        directive_processor.defined_macros = state.defined_macros;
+        directive_processor.used_macros = state.used_macros;
+        directive_processor.prologue = None;
+        directive_processor.macro_generation += 1;
+    }
+
+    /// Returns the number of conditional blocks (`#if`, `#ifdef` and `#ifndef`) currently open.
+    pub fn conditional_depth(&self) -> usize {
+        self.directive_processor.blocks.len()
+    }
+
+    /// Returns statistics about the input processed so far, complete once the preprocessor is
+    /// exhausted.
+    pub fn stats(&self) -> LexStats {
+        LexStats {
+            token_count: self.token_count,
+            line_count: self.directive_processor.lexer.line_count(),
+            comment_count: self.directive_processor.lexer.comment_count(),
+            macro_expansions: self.macro_processor.expansion_count,
+        }
+    }
+
+    /// Registers a callback that gets called for each [`PreprocessEvent`], in the order the
+    /// directives are processed. Directives in skipped blocks don't produce events.
+    pub fn on_event(&mut self, handler: impl FnMut(PreprocessEvent) + 'a) {
+        self.directive_processor.event_handler = Some(Box::new(handler));
+    }
+
+    /// Registers a callback giving the value of identifiers in `#if` and `#elif` conditions that
+    /// aren't macros, for example to inject capabilities of the target, instead of them being 0.
+    /// Returning `None` keeps them 0. `defined` only considers macros.
+    pub fn set_identifier_resolver(&mut self, resolver: impl Fn(&str) -> Option<i64> + 'a) {
+        self.directive_processor.identifier_resolver = Some(Box::new(resolver));
+    }
+
+    /// Preprocesses the whole input, returning each token with information about its origin,
+    /// see [`MappedToken`]. Stops at the first error.
+    pub fn into_mapped_tokens(mut self) -> Result<Vec<MappedToken>, (PreprocessorError, Location)> {
+        let mut tokens = Vec::new();
+        loop {
+            match self.step() {
+                Ok(token) => {
+                    // The invocations that produced the token are still on the stack, they are
+                    // only removed when trying to get the next token.
+                    let expansion: Vec<String> = self
+                        .macro_processor
+                        .define_invocations
+                        .iter()
+                        .map(|invocation| invocation.define.name.clone())
+                        .collect();
+                    let original_location = if expansion.is_empty() {
+                        token.location
+                    } else {
+                        self.macro_processor.expansion_location
+                    };
+
+                    let provenance = match self.macro_processor.define_invocations.last() {
+                        None => Provenance::Source,
+                        Some(invocation) => {
+                            // The position was already advanced past the token.
+                            match invocation
+                                .parameter_origins
+                                .get(invocation.parameter_expanding)
+                            {
+                                Some(origins) => {
+                                    Provenance::Argument(origins[invocation.parameter_position - 1])
+                                }
+                                None => Provenance::Definition(token.location),
+                            }
+                        }
+                    };
+
+                    tokens.push(MappedToken {
+                        token,
+                        original_location,
+                        expansion,
+                        provenance,
+                        source_string: self.directive_processor.source_string,
+                    });
+                }
+                Err(StepExit::Error(err)) => return Err(err),
+                Err(StepExit::Finished) => return Ok(tokens),
+                Err(StepExit::Continue) => continue,
+            }
+        }
+    }
+
+    /// Like [`Iterator::next`] but returns errors with a span, for example to underline them in an
+    /// editor. Errors in a directive span the directive, from its `#` to the end of its line, and
+    /// other errors have an empty span at their location. [`lexer::Lexer::next_spanned`] gives
+    /// the span of lexer errors, like a whole literal for an `IntegerOverflow`.
+    pub fn next_spanned(&mut self) -> Option<Result<Token, (PreprocessorError, Span)>> {
+        let item = self.next()?;
+        Some(item.map_err(|(error, location)| {
+            let span = match self.directive_processor.directive_error_span.take() {
+                Some((error_location, span)) if error_location == location => span,
+                _ => Span {
+                    start: location,
+                    end: location,
+                },
+            };
+            (error, span)
+        }))
+    }
+
+    /// Preprocesses the whole input, giving the errors and warnings to `sink` instead of
+    /// returning them, and returns the tokens. Unlike [`Preprocessor::into_mapped_tokens`],
+    /// processing continues after errors.
+    pub fn process_into<S: DiagnosticSink + ?Sized>(mut self, sink: &mut S) -> Vec<Token> {
+        let mut tokens = Vec::new();
+        loop {
+            let step = self.step();
+            for (warning, location) in self.directive_processor.warnings.drain(..) {
+                sink.warning(warning, location);
+            }
+            match step {
+                Ok(token) => tokens.push(token),
+                Err(StepExit::Error((error, location))) => sink.error(error, location),
+                Err(StepExit::Finished) => return tokens,
+                Err(StepExit::Continue) => continue,
+            }
+        }
+    }
+
     fn step(&mut self) -> Step<Token> {
-        let token = self.macro_processor.step(&mut self.directive_processor)?;
+        if self.token_limit_exceeded {
+            return Finished.into();
+        }
+
+        let mut token = match self.macro_processor.step(&mut self.directive_processor) {
+            Err(StepExit::Finished) => {
+                if !self.unused_macros_reported && !self.directive_processor.options.partial_input {
+                    self.unused_macros_reported = true;
+                    for (name, location) in self.unused_macros() {
+                        self.directive_processor
+                            .warnings
+                            .push((PreprocessorWarning::UnusedMacro(name), location));
+                    }
+                }
+                return Finished.into();
+            }
+            step => step?,
+        };
 
         // Is this token the start of a new macro?
         if let TokenValue::Ident(name) = &token.value {
+            let was_expanding = self.macro_processor.is_expanding_define();
             // Returns Continue if it started the define, token otherwise.
             if self.macro_processor.start_define_invocation(
                 name,
                 token.location,
                 &mut self.directive_processor,
             )? {
+                if !was_expanding && self.directive_processor.options.record_invocations {
+                    self.invocation = Some((name.clone(), token.location));
+                }
                 return Continue.into();
             }
         }
 
+        self.macro_processor.count_expanded_token()?;
+
+        if let Some(max_total_tokens) = self.directive_processor.options.max_total_tokens {
+            if self.token_count >= max_total_tokens {
+                self.token_limit_exceeded = true;
+                // Tokens of macros are reported at the invocation, like for max_expansion_tokens.
+                let location = if self.macro_processor.is_expanding_define() {
+                    self.macro_processor.expansion_location
+                } else {
+                    token.location
+                };
+                return Err(StepExit::Error((
+                    PreprocessorError::TokenLimitExceeded,
+                    location,
+                )));
+            }
+        }
+
+        if self.macro_processor.is_expanding_define() {
+            token.invocation = self.invocation.clone();
+        }
+
+        self.token_count += 1;
         Ok(token)
     }
 }
 
 pub type PreprocessorItem = Result<Token, (PreprocessorError, Location)>;
 
+/// Preprocesses several translation units that share the same options and predefined macros.
+///
+/// Each unit starts from the shared macros, and its `#define` and `#undef` directives only affect
+/// that unit.
+#[derive(Clone, Debug, Default)]
+pub struct SharedPreprocessor {
+    options: PreprocessorOptions,
+    defines: HashMap<String, Rc<Define>>,
+}
+
+impl SharedPreprocessor {
+    pub fn new(options: PreprocessorOptions) -> Self {
+        SharedPreprocessor {
+            options,
+            defines: Default::default(),
+        }
+    }
+
+    /// Adds an object-like macro visible in all the units processed afterwards.
+    pub fn add_define(
+        &mut self,
+        name: &str,
+        content: &str,
+    ) -> Result<(), (PreprocessorError, Location)> {
+        let define = make_define(name, content)?;
+        self.defines.insert(define.name.clone(), Rc::new(define));
+        Ok(())
+    }
+
+    /// Returns a preprocessor for the unit, starting with the shared macros. The macro table is
+    /// cloned, but the definitions in it are reference-counted and not copied.
+    pub fn process_unit<'a>(&self, input: &'a str) -> Preprocessor<'a> {
+        let mut preprocessor = Preprocessor::with_options(input, self.options.clone());
+        preprocessor.directive_processor.defines = self.defines.clone();
+        preprocessor
+    }
+}
+
+/// A token output by the preprocessor along with information about where it comes from.
+#[derive(Clone, PartialEq, Debug)]
+pub struct MappedToken {
+    pub token: Token,
+    /// The location of the token in the source if it wasn't produced by a macro expansion,
+    /// otherwise the location of the top-level macro invocation that produced it.
+    pub original_location: Location,
+    /// The names of the macros that were being expanded when the token was produced, starting
+    /// with the top-level invocation. Empty if the token wasn't produced by a macro expansion.
+    pub expansion: Vec<String>,
+    /// Where the token comes from in the innermost macro invocation that produced it.
+    pub provenance: Provenance,
+    /// The source string number set by the last `#line` directive before the token, 0 by
+    /// default. Tools that concatenate several files in a single source use it as the index of
+    /// the file the token comes from.
+    pub source_string: u32,
+}
+
+/// The origin of a [`MappedToken`] relative to the innermost macro invocation that produced it.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Provenance {
+    /// The token isn't produced by a macro expansion.
+    Source,
+    /// The token was substituted for a parameter, with the location of the argument in the
+    /// invocation. Tokens produced by expanding a macro in the argument have the location of that
+    /// macro invocation.
+    Argument(Location),
+    /// The token comes from the body of the macro, with its location in the definition.
+    Definition(Location),
+}
+
 impl<'a> Iterator for Preprocessor<'a> {
     type Item = PreprocessorItem;
 
@@ -1,43 +1,76 @@
 use crate::token::{Integer, PreprocessorError, Punct};
 
 use super::{Define, Location, MELexer, MacroProcessor, Step, StepExit, Token, TokenValue};
-use std::{collections::HashMap, rc::Rc, vec};
+use std::{
+    cell::Cell,
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+    vec,
+};
 
 struct IfLexer<'macros> {
     tokens: vec::IntoIter<Token>,
     defines: &'macros HashMap<String, Rc<Define>>,
+    source_string: u32,
+    // Whether __LINE__ was expanded, making the result depend on the location.
+    used_line: Cell<bool>,
 }
 
+type ResolveIdentifier<'a> = &'a dyn Fn(&str) -> Option<i64>;
+
 pub(super) struct IfParser<'macros> {
     lexer: IfLexer<'macros>,
+    extensions: &'macros HashSet<String>,
     macro_processor: MacroProcessor,
     location: Location,
 
     parsing_if: bool,
+    prec: PrecTable,
+    // Gives the value of identifiers that aren't macros, see Preprocessor::set_identifier_resolver.
+    pub resolve_identifier: Option<ResolveIdentifier<'macros>>,
     carry: Option<Token>,
+    // Whether the right operands of && and || are skipped when the left one decides the result.
+    skip_short_circuited: bool,
+    // Whether identifiers are kept as Expr::Ident instead of being resolved like in #if.
+    plain_identifiers: bool,
 }
 
 impl<'macros> IfParser<'macros> {
     /// Builds a new IfParser that can be reused
     ///
     /// `parsing_if` indicates wether or not non defined macros should be
-    /// replaced with 0
+    /// replaced with 0, `extensions` are the enabled extensions for `__has_extension`
     pub fn new(
         tokens: Vec<Token>,
         defines: &'macros HashMap<String, Rc<Define>>,
+        extensions: &'macros HashSet<String>,
         location: Location,
         parsing_if: bool,
+        max_expansion_tokens: Option<usize>,
+        source_string: u32,
     ) -> Self {
+        // Like in C, there is no `^^` in preprocessor expressions.
+        let mut prec = PrecTable::new();
+        prec.set(Punct::LogicalXor, None);
+
         IfParser {
             lexer: IfLexer {
                 tokens: tokens.into_iter(),
                 defines,
+                source_string,
+                used_line: Cell::new(false),
             },
-            macro_processor: MacroProcessor::default(),
+            extensions,
+            macro_processor: MacroProcessor::new(max_expansion_tokens),
             location,
 
             parsing_if,
+            prec,
+            resolve_identifier: None,
             carry: None,
+            skip_short_circuited: true,
+            plain_identifiers: false,
         }
     }
 
@@ -45,7 +78,7 @@ impl<'macros> IfParser<'macros> {
     fn raw_next(&mut self) -> Option<Token> {
         self.carry
             .take()
-            .or_else(|| self.macro_processor.step(&mut self.lexer).ok())
+            .or_else(|| self.macro_processor.step_no_continue(&mut self.lexer).ok())
     }
 
     /// Helper method to consume the next token with define expansion
@@ -62,7 +95,10 @@ impl<'macros> IfParser<'macros> {
                     None => self.next()?,
                 }
             }
-            _ => Some(token),
+            _ => {
+                self.macro_processor.count_expanded_token()?;
+                Some(token)
+            }
         })
     }
 
@@ -102,34 +138,29 @@ impl<'macros> IfParser<'macros> {
         )))
     }
 
+    // Starts the expansion of a macro, otherwise returns the identifier that is then handled by
+    // parse_atom.
     fn add_define(&mut self, name: &str, location: Location) -> Step<Option<Token>> {
         if self
             .macro_processor
             .start_define_invocation(name, location, &mut self.lexer)?
         {
             Ok(None)
-        } else if self.parsing_if {
+        } else {
             Ok(Some(Token {
-                value: TokenValue::Integer(Integer {
-                    value: 0,
-                    signed: true,
-                    width: 64,
-                }),
+                value: TokenValue::Ident(name.to_string()),
                 location,
+                raw: None,
+                invocation: None,
             }))
-        } else {
-            Err(StepExit::Error((
-                PreprocessorError::UnexpectedToken(TokenValue::Ident(name.to_string())),
-                location,
-            )))
         }
     }
 
-    fn handle_defined(&mut self) -> Step<i64> {
+    fn handle_defined(&mut self) -> Step<Expr> {
         let next = self.expect_raw_next()?;
 
-        match next.value {
-            TokenValue::Ident(ref name) => Ok(self.lexer.defines.get(name).is_some() as i64),
+        let name = match next.value {
+            TokenValue::Ident(name) => name,
             TokenValue::Punct(Punct::LeftParen) => {
                 let name_token = self.expect_raw_next()?;
                 let name = match name_token.value {
@@ -143,39 +174,91 @@ impl<'macros> IfParser<'macros> {
                 let close_brace = self.expect_next()?;
 
                 match close_brace.value {
-                    TokenValue::Punct(Punct::RightParen) => {
-                        Ok(self.lexer.defines.get(&name).is_some() as i64)
+                    TokenValue::Punct(Punct::RightParen) => name,
+                    value => {
+                        return Err(StepExit::Error((
+                            PreprocessorError::UnexpectedToken(value),
+                            close_brace.location,
+                        )))
                     }
-                    value => Err(StepExit::Error((
+                }
+            }
+            value => {
+                return Err(StepExit::Error((
+                    PreprocessorError::UnexpectedToken(value),
+                    next.location,
+                )))
+            }
+        };
+
+        self.macro_processor.record_used(&name);
+        Ok(Expr::Defined {
+            defined: self.lexer.defines.contains_key(&name),
+            name,
+        })
+    }
+
+    // Parses the `(NAME)` after `__has_extension`, without expanding `NAME`.
+    fn handle_has_extension(&mut self) -> Step<Expr> {
+        let mut name = String::new();
+        for i in 0..3 {
+            let token = self.expect_raw_next()?;
+            match (i, token.value) {
+                (0, TokenValue::Punct(Punct::LeftParen))
+                | (2, TokenValue::Punct(Punct::RightParen)) => {}
+                (1, TokenValue::Ident(ident)) => name = ident,
+                (_, value) => {
+                    return Err(StepExit::Error((
                         PreprocessorError::UnexpectedToken(value),
-                        close_brace.location,
-                    ))),
+                        token.location,
+                    )))
                 }
             }
-            value => Err(StepExit::Error((
-                PreprocessorError::UnexpectedToken(value),
-                next.location,
-            ))),
         }
+
+        Ok(Expr::HasExtension {
+            enabled: self.extensions.contains(&name),
+            name,
+        })
     }
 
-    fn parse_atom(&mut self) -> Step<i64> {
+    fn parse_atom(&mut self) -> Step<Expr> {
         let token = self.expect_next()?;
 
         match token.value {
-            TokenValue::Ident(name) => {
-                debug_assert_eq!(name, "defined");
-
-                self.handle_defined()
-            }
-            TokenValue::Integer(int) => Ok(int.value as i64),
+            TokenValue::Ident(name) => match name.as_str() {
+                "defined" => self.handle_defined(),
+                // GLSL has no separate notion of features so both are about extensions.
+                "__has_extension" | "__has_feature" if self.parsing_if => {
+                    self.handle_has_extension()
+                }
+                // The boolean keywords of GLSL evaluate to 1 and 0 like in C++.
+                "true" | "false" if self.parsing_if => Ok(Expr::Integer(Integer {
+                    value: (name == "true") as u64,
+                    signed: true,
+                    width: 64,
+                })),
+                _ if self.plain_identifiers => Ok(Expr::Ident(name)),
+                _ if self.parsing_if => {
+                    if let Some(value) = self.resolve_identifier.and_then(|resolve| resolve(&name))
+                    {
+                        return Ok(Expr::Resolved { name, value });
+                    }
+                    Ok(Expr::Undefined(name))
+                }
+                _ => Err(StepExit::Error((
+                    PreprocessorError::UnexpectedToken(TokenValue::Ident(name)),
+                    token.location,
+                ))),
+            },
+            TokenValue::Integer(int) => Ok(Expr::Integer(int)),
             TokenValue::Punct(Punct::LeftParen) => {
-                let val = self.parse_logical_or()?;
+                let expr = self.parse_expression()?;
 
                 let close_brace = self.expect_next()?;
 
                 match close_brace.value {
-                    TokenValue::Punct(Punct::RightParen) => Ok(val),
+                    TokenValue::Punct(Punct::RightParen) => Ok(expr),
                     value => Err(StepExit::Error((
                         PreprocessorError::UnexpectedToken(value),
                         close_brace.location,
@@ -189,215 +272,442 @@ impl<'macros> IfParser<'macros> {
         }
     }
 
-    fn parse_unary(&mut self) -> Step<i64> {
-        match self.expect_peek()?.value {
-            TokenValue::Punct(punct) => match punct {
-                Punct::Plus | Punct::Minus | Punct::Bang | Punct::Tilde => {
-                    self.next()?;
-
-                    let val = self.parse_unary()?;
-
-                    Ok(match punct {
-                        Punct::Plus => val,
-                        Punct::Minus => -val,
-                        Punct::Bang => (val == 0) as i64,
-                        Punct::Tilde => !val,
-                        _ => unreachable!(),
-                    })
+    // Skips the right operand of a short-circuited `&&` or `||` without expanding or parsing it,
+    // like `F(1)` in `defined(F) && F(1)`. It ends before the first binary operator outside of
+    // parentheses that binds less tightly than `min_precedence`, or before an unmatched `)`.
+    // Operators produced by macros in the operand aren't seen as they are not expanded.
+    fn skip_operand(&mut self, min_precedence: u16) -> Step<Expr> {
+        let mut location = None;
+        let mut nesting = 0u32;
+        // Whether the previous token ends an operand, so that an operator after it is binary.
+        let mut after_operand = false;
+
+        while let Some(token) = self.raw_next() {
+            let ends_operand = match token.value {
+                TokenValue::Punct(Punct::LeftParen) => {
+                    nesting += 1;
+                    false
+                }
+                TokenValue::Punct(Punct::RightParen) if nesting > 0 => {
+                    nesting -= 1;
+                    true
                 }
-                _ => self.parse_atom(),
+                TokenValue::Punct(Punct::RightParen) => {
+                    self.carry = Some(token);
+                    break;
+                }
+                TokenValue::Punct(op) if after_operand && nesting == 0 => match self.prec.get(op) {
+                    Some(precedence) if u16::from(precedence) >= min_precedence => false,
+                    _ => {
+                        self.carry = Some(token);
+                        break;
+                    }
+                },
+                TokenValue::Punct(_) => false,
+                _ => true,
+            };
+            after_operand = ends_operand;
+            location.get_or_insert(token.location);
+        }
+
+        match location {
+            Some(location) => Ok(Expr::Skipped { location }),
+            // The operand is missing, report it like parse_atom does.
+            None => match self.carry.take() {
+                Some(token) => Err(StepExit::Error((
+                    PreprocessorError::UnexpectedToken(token.value),
+                    token.location,
+                ))),
+                None => Err(StepExit::Error((
+                    PreprocessorError::UnexpectedEndOfInput,
+                    self.location,
+                ))),
             },
-            _ => self.parse_atom(),
         }
     }
 
-    fn parse_multiplicative(&mut self) -> Step<i64> {
-        let mut left = self.parse_unary()?;
-
-        while let Some(TokenValue::Punct(punct)) = self.peek()?.map(|t| t.value) {
-            if let Punct::Star | Punct::Slash | Punct::Percent = punct {
-                self.next()?;
-
-                let right = self.parse_unary()?;
-
-                match punct {
-                    Punct::Star => left *= right,
-                    Punct::Slash => left /= right,
-                    Punct::Percent => left %= right,
-                    _ => unreachable!(),
-                }
-            } else {
-                break;
+    fn parse_unary(&mut self) -> Step<Expr> {
+        match self.expect_peek()?.value {
+            TokenValue::Punct(op @ Punct::Plus)
+            | TokenValue::Punct(op @ Punct::Minus)
+            | TokenValue::Punct(op @ Punct::Bang)
+            | TokenValue::Punct(op @ Punct::Tilde) => {
+                let location = self.expect_next()?.location;
+
+                let operand = self.parse_unary()?;
+
+                Ok(Expr::Unary {
+                    op,
+                    location,
+                    operand: Box::new(operand),
+                })
             }
+            _ => self.parse_atom(),
         }
-
-        Ok(left)
     }
 
-    fn parse_additive(&mut self) -> Step<i64> {
-        let mut left = self.parse_multiplicative()?;
-
-        while let Some(TokenValue::Punct(punct)) = self.peek()?.map(|t| t.value) {
-            if let Punct::Plus | Punct::Minus = punct {
-                self.next()?;
-
-                let right = self.parse_multiplicative()?;
+    // Parses the binary operators that bind at least as tightly as `min_precedence` by precedence
+    // climbing, they are all left-associative.
+    fn parse_binary(&mut self, min_precedence: u16) -> Step<Expr> {
+        let mut left = self.parse_unary()?;
 
-                match punct {
-                    Punct::Plus => left += right,
-                    Punct::Minus => left -= right,
-                    _ => unreachable!(),
+        while let Some(Token {
+            value: TokenValue::Punct(op),
+            location,
+            ..
+        }) = self.peek()?
+        {
+            let precedence = match self.prec.get(op) {
+                Some(precedence) if u16::from(precedence) >= min_precedence => {
+                    u16::from(precedence)
                 }
+                _ => break,
+            };
+            self.next()?;
+
+            // The right operand is skipped when its value doesn't matter.
+            let short_circuits = self.skip_short_circuited
+                && match (op, left.evaluate()) {
+                    (Punct::LogicalAnd, Ok(value)) => value == 0,
+                    (Punct::LogicalOr, Ok(value)) => value != 0,
+                    _ => false,
+                };
+            let right = if short_circuits {
+                self.skip_operand(precedence + 1)?
             } else {
-                break;
-            }
+                self.parse_binary(precedence + 1)?
+            };
+
+            left = Expr::Binary {
+                op,
+                location,
+                left: Box::new(left),
+                right: Box::new(right),
+            };
         }
 
         Ok(left)
     }
 
-    fn parse_shift(&mut self) -> Step<i64> {
-        let mut left = self.parse_additive()?;
-
-        while let Some(TokenValue::Punct(punct)) = self.peek()?.map(|t| t.value) {
-            if let Punct::LeftShift | Punct::RightShift = punct {
-                self.next()?;
+    /// Parses an expression, after macro expansion.
+    pub fn parse_expression(&mut self) -> Step<Expr> {
+        self.parse_binary(0)
+    }
 
-                let right = self.parse_additive()?;
+    pub fn evaluate_expression(&mut self) -> Step<i64> {
+        self.parse_expression()?.evaluate().map_err(StepExit::Error)
+    }
 
-                match punct {
-                    Punct::LeftShift => left <<= right,
-                    Punct::RightShift => left >>= right,
-                    _ => unreachable!(),
-                }
-            } else {
-                break;
-            }
-        }
+    /// Records the names of the macros expanded or tested with `defined`, see
+    /// [`IfParser::take_used_macros`].
+    pub fn track_used_macros(&mut self) {
+        self.macro_processor.track_used = true;
+    }
 
-        Ok(left)
+    /// See [`crate::pp::PreprocessorOptions::allow_trailing_argument_comma`].
+    pub fn allow_trailing_argument_comma(&mut self) {
+        self.macro_processor.allow_trailing_argument_comma = true;
     }
 
-    fn parse_comparative(&mut self) -> Step<i64> {
-        let mut left = self.parse_shift()?;
+    /// See [`crate::pp::PreprocessorOptions::bracket_argument_commas`].
+    pub fn bracket_argument_commas(&mut self) {
+        self.macro_processor.bracket_argument_commas = true;
+    }
 
-        while let Some(TokenValue::Punct(punct)) = self.peek()?.map(|t| t.value) {
-            if let Punct::LeftAngle | Punct::RightAngle | Punct::LessEqual | Punct::GreaterEqual =
-                punct
-            {
-                self.next()?;
+    pub fn take_used_macros(&mut self) -> HashSet<String> {
+        std::mem::take(&mut self.macro_processor.used)
+    }
 
-                let right = self.parse_shift()?;
+    /// Whether `__LINE__` was expanded while parsing, so the value depends on the line.
+    pub fn used_line(&self) -> bool {
+        self.lexer.used_line.get()
+    }
+}
 
-                match punct {
-                    Punct::LeftAngle => left = (left < right) as i64,
-                    Punct::RightAngle => left = (left > right) as i64,
-                    Punct::LessEqual => left = (left <= right) as i64,
-                    Punct::GreaterEqual => left = (left >= right) as i64,
-                    _ => unreachable!(),
-                }
-            } else {
-                break;
-            }
-        }
+/// The expression of an `#if` or `#elif` directive, after macro expansion.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Expr {
+    Integer(Integer),
+    /// `defined NAME` or `defined(NAME)`, along with whether the macro was defined.
+    Defined {
+        name: String,
+        defined: bool,
+    },
+    /// `__has_extension(NAME)` or `__has_feature(NAME)`, along with whether the extension was
+    /// enabled by an `#extension` directive.
+    HasExtension {
+        name: String,
+        enabled: bool,
+    },
+    /// An identifier that isn't a macro, which evaluates to 0.
+    Undefined(String),
+    /// An identifier of an expression parsed with [`parse_expr`], which evaluates to 0 like an
+    /// undefined one.
+    Ident(String),
+    /// An identifier that isn't a macro, with the value given by the resolver set with
+    /// [`crate::pp::Preprocessor::set_identifier_resolver`].
+    Resolved {
+        name: String,
+        value: i64,
+    },
+    /// The right operand of a `&&` or `||` that is skipped without expanding its macros or
+    /// parsing it, because the left operand decides the result. `location` is the location of
+    /// its first token. It evaluates to 0.
+    Skipped {
+        location: Location,
+    },
+    /// `location` is the location of the operator.
+    Unary {
+        op: Punct,
+        location: Location,
+        operand: Box<Expr>,
+    },
+    /// `location` is the location of the operator.
+    Binary {
+        op: Punct,
+        location: Location,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+}
 
-        Ok(left)
+impl Expr {
+    /// Evaluates the expression like the preprocessor does, `&&` and `||` only evaluate their
+    /// right operand when needed.
+    pub fn evaluate(&self) -> Result<i64, (PreprocessorError, Location)> {
+        self.value().map(|val| val.value)
     }
 
-    fn parse_equality(&mut self) -> Step<i64> {
-        let mut left = self.parse_comparative()?;
-
-        while let Some(TokenValue::Punct(punct)) = self.peek()?.map(|t| t.value) {
-            if let Punct::EqualEqual | Punct::NotEqual = punct {
-                self.next()?;
+    fn value(&self) -> Result<Value, (PreprocessorError, Location)> {
+        Ok(match self {
+            // Like in C, literals that don't fit in the signed type of their width are unsigned.
+            // This applies to decimal literals too, like in C89, so `4294967295` is unsigned when
+            // integers are 32-bit.
+            Expr::Integer(int) => {
+                let signed_max = match int.width {
+                    16 => i16::MAX as u64,
+                    32 => i32::MAX as u64,
+                    _ => i64::MAX as u64,
+                };
+                Value {
+                    value: int.value as i64,
+                    signed: int.signed && int.value <= signed_max,
+                }
+            }
+            Expr::Defined { defined, .. } => Value::from_bool(*defined),
+            Expr::HasExtension { enabled, .. } => Value::from_bool(*enabled),
+            Expr::Undefined(_) | Expr::Ident(_) => Value::signed(0),
+            Expr::Resolved { value, .. } => Value::signed(*value),
+            Expr::Skipped { .. } => Value::signed(0),
+            Expr::Unary {
+                op,
+                location,
+                operand,
+            } => {
+                let val = operand.value()?;
+                match op {
+                    Punct::Plus => val,
+                    Punct::Minus => val.map(i64::wrapping_neg),
+                    Punct::Bang => Value::from_bool(!val.is_true()),
+                    Punct::Tilde => val.map(|v| !v),
+                    _ => {
+                        return Err((
+                            PreprocessorError::UnexpectedToken(TokenValue::Punct(*op)),
+                            *location,
+                        ))
+                    }
+                }
+            }
+            Expr::Binary {
+                op,
+                location,
+                left,
+                right,
+            } => {
+                let left = left.value()?;
+                match op {
+                    Punct::LogicalAnd => {
+                        return Ok(Value::from_bool(left.is_true() && right.value()?.is_true()))
+                    }
+                    Punct::LogicalOr => {
+                        return Ok(Value::from_bool(left.is_true() || right.value()?.is_true()))
+                    }
+                    _ => {}
+                }
 
-                let right = self.parse_comparative()?;
+                let right = right.value()?;
+                match op {
+                    Punct::Slash | Punct::Percent if right.value == 0 => {
+                        return Err((PreprocessorError::DivideByZero, *location));
+                    }
+                    // Shifting by a negative amount or by more than the width of the value is
+                    // undefined, so it is an error instead.
+                    Punct::LeftShift | Punct::RightShift if !(0..64).contains(&right.value) => {
+                        return Err((PreprocessorError::ShiftOutOfRange, *location));
+                    }
+                    _ => {}
+                }
 
-                match punct {
-                    Punct::EqualEqual => left = (left == right) as i64,
-                    Punct::NotEqual => left = (left != right) as i64,
-                    _ => unreachable!(),
+                match op {
+                    Punct::Star => left.arithmetic(right, i64::wrapping_mul, u64::wrapping_mul),
+                    Punct::Slash => left.arithmetic(right, i64::wrapping_div, u64::wrapping_div),
+                    Punct::Percent => left.arithmetic(right, i64::wrapping_rem, u64::wrapping_rem),
+                    Punct::Plus => left.arithmetic(right, i64::wrapping_add, u64::wrapping_add),
+                    Punct::Minus => left.arithmetic(right, i64::wrapping_sub, u64::wrapping_sub),
+
+                    // The type of a shift is the type of its left operand, so unsigned values are
+                    // shifted right logically.
+                    Punct::LeftShift => left.map(|v| v << right.value),
+                    Punct::RightShift if left.signed => left.map(|v| v >> right.value),
+                    Punct::RightShift => left.map(|v| ((v as u64) >> right.value) as i64),
+
+                    Punct::LeftAngle => Value::from_bool(left.compare(right) == Ordering::Less),
+                    Punct::RightAngle => Value::from_bool(left.compare(right) == Ordering::Greater),
+                    Punct::LessEqual => Value::from_bool(left.compare(right) != Ordering::Greater),
+                    Punct::GreaterEqual => Value::from_bool(left.compare(right) != Ordering::Less),
+                    Punct::EqualEqual => Value::from_bool(left.value == right.value),
+                    Punct::NotEqual => Value::from_bool(left.value != right.value),
+
+                    Punct::Ampersand => left.arithmetic(right, |a, b| a & b, |a, b| a & b),
+                    Punct::Caret => left.arithmetic(right, |a, b| a ^ b, |a, b| a ^ b),
+                    Punct::Pipe => left.arithmetic(right, |a, b| a | b, |a, b| a | b),
+                    Punct::LogicalXor => Value::from_bool(left.is_true() != right.is_true()),
+                    // Operators added with a PrecTable that have no meaning here.
+                    _ => {
+                        return Err((
+                            PreprocessorError::UnexpectedToken(TokenValue::Punct(*op)),
+                            *location,
+                        ))
+                    }
                 }
-            } else {
-                break;
             }
-        }
-
-        Ok(left)
+        })
     }
+}
 
-    fn parse_bit_and(&mut self) -> Step<i64> {
-        let mut left = self.parse_equality()?;
-
-        while let Some(TokenValue::Punct(Punct::Ampersand)) = self.peek()?.map(|t| t.value) {
-            self.next()?;
-
-            let right = self.parse_equality()?;
-
-            left &= right
-        }
+/// The precedence of the binary operators parsed by [`parse_expr`], higher binds tighter. It is
+/// [`Punct::precedence`] unless changed with [`PrecTable::set`].
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct PrecTable {
+    overrides: Vec<(Punct, Option<u8>)>,
+}
 
-        Ok(left)
+impl PrecTable {
+    pub fn new() -> Self {
+        Default::default()
     }
 
-    fn parse_bit_xor(&mut self) -> Step<i64> {
-        let mut left = self.parse_bit_and()?;
-
-        while let Some(TokenValue::Punct(Punct::Caret)) = self.peek()?.map(|t| t.value) {
-            self.next()?;
-
-            let right = self.parse_bit_and()?;
+    /// Sets the precedence of `op`, `None` makes it not a binary operator.
+    pub fn set(&mut self, op: Punct, precedence: Option<u8>) {
+        self.overrides.retain(|&(other, _)| other != op);
+        self.overrides.push((op, precedence));
+    }
 
-            left ^= right
+    pub fn get(&self, op: Punct) -> Option<u8> {
+        match self.overrides.iter().find(|&&(other, _)| other == op) {
+            Some(&(_, precedence)) => precedence,
+            None => op.precedence(),
         }
-
-        Ok(left)
     }
+}
 
-    fn parse_bit_or(&mut self) -> Step<i64> {
-        let mut left = self.parse_bit_xor()?;
-
-        while let Some(TokenValue::Punct(Punct::Pipe)) = self.peek()?.map(|t| t.value) {
-            self.next()?;
+/// Parses all of `tokens` as an integer `#if`-style expression with the binary operators of `prec`,
+/// without macro expansion. Float literals are an error, and identifiers are [`Expr::Ident`]
+/// except the ones with a meaning in `#if` like `defined` or `true`. Unlike in `#if`, both operands
+/// of `&&` and `||` are always parsed. A missing operand is reported at the last token.
+pub fn parse_expr(
+    tokens: &[Token],
+    prec: &PrecTable,
+) -> Result<Expr, (PreprocessorError, Location)> {
+    // The parser only stops early because of errors.
+    let error = |exit| match exit {
+        StepExit::Error(error) => error,
+        _ => unreachable!(),
+    };
+
+    let defines = HashMap::new();
+    let extensions = HashSet::new();
+    let location = tokens
+        .last()
+        .map_or(Location::default(), |token| token.location);
+    let mut parser = IfParser::new(
+        tokens.to_vec(),
+        &defines,
+        &extensions,
+        location,
+        true,
+        None,
+        0,
+    );
+    parser.prec = prec.clone();
+    parser.skip_short_circuited = false;
+    parser.plain_identifiers = true;
+
+    let expression = parser.parse_expression().map_err(error)?;
+    match parser.peek().map_err(error)? {
+        Some(token) => Err((
+            PreprocessorError::UnexpectedToken(token.value),
+            token.location,
+        )),
+        None => Ok(expression),
+    }
+}
 
-            let right = self.parse_bit_xor()?;
+/// An intermediate value of an expression, the signedness follows the C rules so that for example
+/// `-1 < 1u` is false.
+#[derive(Clone, Copy)]
+struct Value {
+    value: i64,
+    signed: bool,
+}
 
-            left |= right
+impl Value {
+    fn signed(value: i64) -> Self {
+        Value {
+            value,
+            signed: true,
         }
-
-        Ok(left)
     }
 
-    fn parse_logical_and(&mut self) -> Step<i64> {
-        let mut left = self.parse_bit_or()?;
-
-        while let Some(TokenValue::Punct(Punct::LogicalAnd)) = self.peek()?.map(|t| t.value) {
-            self.next()?;
+    fn from_bool(value: bool) -> Self {
+        Value::signed(value as i64)
+    }
 
-            let right = self.parse_bit_or()?;
+    fn is_true(self) -> bool {
+        self.value != 0
+    }
 
-            left = (left != 0 && right != 0) as i64;
+    fn map(self, f: impl FnOnce(i64) -> i64) -> Self {
+        Value {
+            value: f(self.value),
+            signed: self.signed,
         }
-
-        Ok(left)
     }
 
-    fn parse_logical_or(&mut self) -> Step<i64> {
-        let mut left = self.parse_logical_and()?;
-
-        while let Some(TokenValue::Punct(Punct::LogicalAnd)) = self.peek()?.map(|t| t.value) {
-            self.next()?;
-
-            let right = self.parse_logical_and()?;
-
-            left = (left != 0 || right != 0) as i64;
+    /// Applies a binary operator after the usual arithmetic conversions: if either operand is
+    /// unsigned both are converted to unsigned.
+    fn arithmetic(
+        self,
+        other: Value,
+        signed_op: impl FnOnce(i64, i64) -> i64,
+        unsigned_op: impl FnOnce(u64, u64) -> u64,
+    ) -> Self {
+        if self.signed && other.signed {
+            Value::signed(signed_op(self.value, other.value))
+        } else {
+            Value {
+                value: unsigned_op(self.value as u64, other.value as u64) as i64,
+                signed: false,
+            }
         }
-
-        Ok(left)
     }
 
-    pub fn evaluate_expression(&mut self) -> Step<i64> {
-        self.parse_logical_or()
+    fn compare(self, other: Value) -> Ordering {
+        if self.signed && other.signed {
+            self.value.cmp(&other.value)
+        } else {
+            (self.value as u64).cmp(&(other.value as u64))
+        }
     }
 }
 
@@ -411,6 +721,11 @@ impl<'macros> MELexer for IfLexer<'macros> {
     }
 
     fn apply_line_offset(&self, line: u32, _: Location) -> Step<u32> {
+        self.used_line.set(true);
         Ok(line)
     }
+
+    fn source_string(&self) -> u32 {
+        self.source_string
+    }
 }
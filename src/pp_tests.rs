@@ -1,13 +1,20 @@
 use super::lexer::{self, Token as LexerToken, TokenValue as LexerTokenValue};
-use super::pp::{convert_lexer_token, Preprocessor, PreprocessorItem};
-use super::token::{Integer, Location, PreprocessorError, Punct, Token, TokenValue};
+use super::pp::{
+    convert_lexer_token, is_known_directive, parse_expr, validate_macro_body, DiagnosticSink,
+    Diagnostics, Dialect, Expr, LexStats, MappedToken, PrecTable, PreprocessEvent, Preprocessor,
+    PreprocessorItem, PreprocessorOptions, Provenance, SharedPreprocessor, DIRECTIVES,
+};
+use super::token::{
+    ExpectedKind, Float, Integer, Location, PreprocessorError, PreprocessorWarning, Profile, Punct,
+    ShaderStage, Span, StdPragma, Token, TokenKind, TokenValue,
+};
 
 struct NoopPreprocessor<'a> {
     lexer: lexer::Lexer<'a>,
 }
 
 impl<'a> NoopPreprocessor<'a> {
-    pub fn new(input: &'a str) -> NoopPreprocessor {
+    pub fn new(input: &'a str) -> NoopPreprocessor<'a> {
         NoopPreprocessor {
             lexer: lexer::Lexer::new(input),
         }
@@ -90,7 +97,10 @@ fn parse_define() {
     // Test the define name must be an identifier
     check_preprocessing_error(
         "#define [",
-        PreprocessorError::UnexpectedToken(TokenValue::Punct(Punct::LeftBracket)),
+        PreprocessorError::Expected {
+            expected: ExpectedKind::Identifier,
+            found: TokenValue::Punct(Punct::LeftBracket),
+        },
     );
 
     // Test that there must be a name before the new line
@@ -106,7 +116,10 @@ fn parse_undef() {
     // Test the define name must be an identifier
     check_preprocessing_error(
         "#undef !",
-        PreprocessorError::UnexpectedToken(TokenValue::Punct(Punct::Bang)),
+        PreprocessorError::Expected {
+            expected: ExpectedKind::Identifier,
+            found: TokenValue::Punct(Punct::Bang),
+        },
     );
 
     // Test that there must be a name before the new line
@@ -248,7 +261,10 @@ fn function_like_define() {
     );
     check_preprocessing_error(
         "#define A(a, %) foo",
-        PreprocessorError::UnexpectedToken(TokenValue::Punct(Punct::Percent)),
+        PreprocessorError::Expected {
+            expected: ExpectedKind::Identifier,
+            found: TokenValue::Punct(Punct::Percent),
+        },
     );
 
     // Test that starting the param list with a comma is disallowed
@@ -299,7 +315,7 @@ fn function_like_define() {
     check_preprocessing_error(
         "#define A(a) foo
          A($)",
-        PreprocessorError::UnexpectedCharacter,
+        PreprocessorError::UnexpectedCharacter('$'),
     );
 
     // Test #error while parsing arguments
@@ -566,6 +582,20 @@ fn parse_if() {
     // TODO test expressions?
 }
 
+#[test]
+fn if_logical_or() {
+    // Test that || is parsed, with a lower precedence than &&.
+    check_preprocessed_result("#if 0 || 1\na\n#endif", "a");
+    check_preprocessed_result("#if 0 || 0\na\n#endif\nb", "b");
+    check_preprocessed_result("#if 1 || 0 && 0\na\n#endif", "a");
+    check_preprocessed_result("#if (1 || 0) && 0\na\n#endif\nb", "b");
+
+    // Test that the right operand is only evaluated when needed, so errors in it are only
+    // reported then.
+    check_preprocessed_result("#if 1 || 1 / 0\na\n#endif", "a");
+    check_preprocessing_error("#if 0 || 1 / 0\n#endif", PreprocessorError::DivideByZero);
+}
+
 #[test]
 fn parse_ifdef() {
     // Basic test of parsing and operations.
@@ -584,7 +614,10 @@ fn parse_ifdef() {
     check_preprocessing_error(
         "#ifdef B ;
          #endif",
-        PreprocessorError::UnexpectedToken(TokenValue::Punct(Punct::Semicolon)),
+        PreprocessorError::Expected {
+            expected: ExpectedKind::EndOfLine,
+            found: TokenValue::Punct(Punct::Semicolon),
+        },
     );
 
     // Check that the identifier is required.
@@ -631,7 +664,10 @@ fn parse_ifndef() {
     check_preprocessing_error(
         "#ifndef B ;
          #endif",
-        PreprocessorError::UnexpectedToken(TokenValue::Punct(Punct::Semicolon)),
+        PreprocessorError::Expected {
+            expected: ExpectedKind::EndOfLine,
+            found: TokenValue::Punct(Punct::Semicolon),
+        },
     );
 
     // Check that the identifier is required.
@@ -832,19 +868,21 @@ fn parse_endif() {
         "b",
     );
 
-    // Check that extra tokens are disallowed.
-    check_preprocessing_error(
-        "#if 1
-         #endif %",
-        PreprocessorError::UnexpectedToken(TokenValue::Punct(Punct::Percent)),
-    );
-
-    // Check that extra tokens are disallowed even for an inner_skipped block.
-    check_preprocessing_error(
-        "#if 0
-         #endif %",
-        PreprocessorError::UnexpectedToken(TokenValue::Punct(Punct::Percent)),
-    );
+    // Check that extra tokens are disallowed in strict mode, even for an inner_skipped block.
+    let strict = PreprocessorOptions {
+        strict_directive_trailing: true,
+        ..Default::default()
+    };
+    for input in &["#if 1\n#endif %", "#if 0\n#endif %"] {
+        let error = Preprocessor::with_options(input, strict.clone()).find_map(Result::err);
+        assert_eq!(
+            error,
+            Some((
+                PreprocessorError::ExtraTokensAfterDirective,
+                Location { line: 2, pos: 7 }
+            ))
+        );
+    }
 
     // Check that extra tokens are allowed if we are skipping.
     check_preprocessed_result(
@@ -972,6 +1010,11 @@ fn parse_line() {
         "#line foo",
         PreprocessorError::UnexpectedToken(TokenValue::Ident("foo".into())),
     );
+    // Test that the boolean keywords are only integers in #if.
+    check_preprocessing_error(
+        "#line true",
+        PreprocessorError::UnexpectedToken(TokenValue::Ident("true".into())),
+    );
 
     // Test that an invalid #line directive is allowed if skipping
     check_preprocessed_result(
@@ -1007,6 +1050,45 @@ fn parse_line() {
         __LINE__",
         "1u",
     );
+
+    // Test the source string number form, and that it sets __FILE__.
+    check_preprocessed_result(
+        "__FILE__
+         #line 10 3
+         __FILE__ __LINE__
+         #line 20
+         __FILE__ __LINE__",
+        "0u 3u 11u 3u 21u",
+    );
+    let mut pp = Preprocessor::new("#line 10 2 + 5");
+    assert_eq!(pp.source_string_number(), 0);
+    assert_eq!(pp.next(), None);
+    assert_eq!(pp.source_string_number(), 7);
+
+    // Test that __FILE__ in defines and #if uses the current source string number.
+    check_preprocessed_result(
+        "#line 1 4
+         #define F __FILE__
+         #if __FILE__ == 4
+         F
+         #endif",
+        "4u",
+    );
+
+    // Test that the source string number must be a valid number.
+    check_preprocessing_error(
+        "#line 1 foo",
+        PreprocessorError::UnexpectedToken(TokenValue::Ident("foo".into())),
+    );
+    check_preprocessing_error("#line 1 (-1)", PreprocessorError::IntegerOverflow);
+    check_preprocessing_error(
+        "#line 1 2 3",
+        PreprocessorError::UnexpectedToken(TokenValue::Integer(Integer {
+            value: 3,
+            signed: true,
+            width: 32,
+        })),
+    );
 }
 
 #[test]
@@ -1355,6 +1437,2638 @@ fn add_define() {
     let mut pp = Preprocessor::new("A");
     assert_eq!(
         pp.add_define("A", "@").unwrap_err().0,
-        PreprocessorError::UnexpectedCharacter
+        PreprocessorError::UnexpectedCharacter('@')
+    );
+}
+
+#[test]
+fn max_expansion_tokens() {
+    // Each level of define multiplies the number of tokens by 4, so C expands to 64 tokens.
+    let input = "#define A x x x x
+                 #define B A A A A
+                 #define C B B B B
+                 y C";
+
+    // Test that there is no limit by default.
+    assert_eq!(Preprocessor::new(input).count(), 65);
+
+    // Test that the limit isn't hit when the expansion fits in it.
+    let options = PreprocessorOptions {
+        max_expansion_tokens: Some(64),
+        ..Default::default()
+    };
+    let items: Vec<PreprocessorItem> = Preprocessor::with_options(input, options).collect();
+    assert_eq!(items.len(), 65);
+    assert!(items.iter().all(|item| item.is_ok()));
+
+    // Test that going over the limit produces an error at the invocation.
+    let options = PreprocessorOptions {
+        max_expansion_tokens: Some(50),
+        ..Default::default()
+    };
+    let error = Preprocessor::with_options(input, options)
+        .find_map(|item| item.err())
+        .unwrap();
+    assert_eq!(
+        error,
+        (
+            PreprocessorError::ExpansionTooLarge,
+            Location { line: 4, pos: 19 }
+        )
+    );
+
+    // Test that the limit is reset between top-level invocations.
+    let options = PreprocessorOptions {
+        max_expansion_tokens: Some(16),
+        ..Default::default()
+    };
+    let items: Vec<PreprocessorItem> =
+        Preprocessor::with_options(&input.replace("y C", "B B B"), options).collect();
+    assert_eq!(items.len(), 48);
+    assert!(items.iter().all(|item| item.is_ok()));
+
+    // Test that the limit also applies to the expansion of arguments.
+    let options = PreprocessorOptions {
+        max_expansion_tokens: Some(50),
+        ..Default::default()
+    };
+    let error = Preprocessor::with_options(
+        "#define D(x) x x
+         #define E(x) D(D(D(x)))
+         E(E(1))",
+        options,
+    )
+    .find_map(|item| item.err())
+    .unwrap();
+    assert_eq!(error.0, PreprocessorError::ExpansionTooLarge);
+}
+
+#[test]
+fn on_event() {
+    // Test that events are emitted in order for processed directives only.
+    let mut events = Vec::new();
+    let mut pp = Preprocessor::new(
+        "#version 450 core
+         #define A 1
+         #if 0
+             #define B 2
+         #endif
+         #undef A
+         #define A(x) x",
+    );
+    pp.on_event(|event| events.push(event));
+    assert_eq!(pp.count(), 1);
+
+    assert_eq!(
+        events,
+        vec![
+            PreprocessEvent::Version { number: Some(450) },
+            PreprocessEvent::Define { name: "A".into() },
+            PreprocessEvent::Condition {
+                expression: Expr::Integer(Integer {
+                    value: 0,
+                    signed: true,
+                    width: 32
+                }),
+                value: false
+            },
+            PreprocessEvent::Undef { name: "A".into() },
+            PreprocessEvent::Define { name: "A".into() },
+        ]
+    );
+
+    // Test the version number is None when it isn't an integer.
+    let mut events = Vec::new();
+    let mut pp = Preprocessor::new("#version foo");
+    pp.on_event(|event| events.push(event));
+    assert_eq!(pp.count(), 1);
+    assert_eq!(events, vec![PreprocessEvent::Version { number: None }]);
+}
+
+#[test]
+fn excluded_regions() {
+    // Test a simple excluded block goes from the #if to the #endif.
+    let mut pp = Preprocessor::new(
+        "a
+         #if 0
+             b
+         #endif
+         c",
+    );
+    assert_eq!(pp.by_ref().count(), 2);
+    assert_eq!(
+        pp.excluded_regions(),
+        &[(Location { line: 2, pos: 10 }, Location { line: 4, pos: 10 })]
+    );
+
+    // Test nested exclusions are reported as the outermost region and that regions started by
+    // #elif and #else are reported.
+    let mut pp = Preprocessor::new(
+        "#if 0
+             #if 1
+             #endif
+         #elif 1
+         #else
+         #endif",
+    );
+    assert_eq!(pp.by_ref().count(), 0);
+    assert_eq!(
+        pp.excluded_regions(),
+        &[
+            (Location { line: 1, pos: 1 }, Location { line: 4, pos: 10 }),
+            (Location { line: 5, pos: 10 }, Location { line: 6, pos: 10 }),
+        ]
+    );
+
+    // Test that no regions are reported when nothing is excluded.
+    let mut pp = Preprocessor::new(
+        "#if 1
+         #endif",
+    );
+    assert_eq!(pp.by_ref().count(), 0);
+    assert_eq!(pp.excluded_regions(), &[]);
+
+    // Test that a region still open at the end of the input ends with it.
+    let mut pp = Preprocessor::new(
+        "a
+         #if 0
+             b",
+    );
+    assert_eq!(pp.by_ref().count(), 2);
+    assert_eq!(
+        pp.excluded_regions(),
+        &[(Location { line: 2, pos: 10 }, Location { line: 3, pos: 14 })]
+    );
+}
+
+#[test]
+fn dialects() {
+    let literals = "1s 2l 3.0hf 4.0lf";
+    let first_error = |dialect: Dialect| {
+        Preprocessor::with_options(literals, dialect.into())
+            .find_map(|item| item.err())
+            .map(|(err, _)| err)
+    };
+
+    // Test that GLSL ES rejects 16 and 64-bit literals but Vulkan accepts them.
+    assert_eq!(
+        first_error(Dialect::GlslEs),
+        Some(PreprocessorError::NotSupported16BitLiteral)
+    );
+    assert_eq!(first_error(Dialect::Vulkan), None);
+    assert_eq!(first_error(Dialect::Permissive), None);
+
+    let widths: Vec<i32> = Preprocessor::with_options(literals, Dialect::Vulkan.into())
+        .map(|item| match item.unwrap().value {
+            TokenValue::Integer(Integer { width, .. }) => width,
+            TokenValue::Float(Float { width, .. }) => width,
+            _ => unreachable!(),
+        })
+        .collect();
+    assert_eq!(widths, vec![16, 64, 16, 64]);
+
+    // Test that desktop GLSL only accepts 64-bit literals.
+    assert_eq!(
+        first_error(Dialect::GlslCore),
+        Some(PreprocessorError::NotSupported16BitLiteral)
+    );
+    assert_eq!(
+        Preprocessor::with_options("2l 4.0lf", Dialect::GlslCore.into()).count(),
+        2
+    );
+
+    // Test that individual options can be overriden from the dialect defaults.
+    let mut options = Dialect::Vulkan.options();
+    options.lexer.allow_64bit_literals = false;
+    assert_eq!(
+        Preprocessor::with_options("2l", options)
+            .next()
+            .unwrap()
+            .unwrap_err()
+            .0,
+        PreprocessorError::NotSupported64BitLiteral
+    );
+
+    // Test that unknown directives are only ignored in the permissive dialect.
+    let unknown = "#foo bar
+                   a";
+    assert_eq!(
+        Preprocessor::with_options(unknown, Dialect::GlslEs.into())
+            .next()
+            .unwrap()
+            .unwrap_err()
+            .0,
+        PreprocessorError::UnknownDirective
+    );
+    let tokens: Vec<PreprocessorItem> =
+        Preprocessor::with_options(unknown, Dialect::Permissive.into()).collect();
+    assert_eq!(tokens.len(), 1);
+    assert_eq!(
+        tokens[0].as_ref().unwrap().value,
+        TokenValue::Ident("a".into())
+    );
+}
+
+#[test]
+fn into_mapped_tokens() {
+    let tokens = Preprocessor::new(
+        "#define B b
+         #define A(x) x B
+         a A(1)",
+    )
+    .into_mapped_tokens()
+    .unwrap();
+    assert_eq!(tokens.len(), 3);
+
+    // Test a token that's not from an expansion.
+    assert_eq!(
+        tokens[0],
+        MappedToken {
+            token: Token {
+                value: TokenValue::Ident("a".into()),
+                location: Location { line: 3, pos: 9 },
+                raw: None,
+                invocation: None,
+            },
+            original_location: Location { line: 3, pos: 9 },
+            expansion: vec![],
+            provenance: Provenance::Source,
+            source_string: 0,
+        }
+    );
+
+    // Test a token from an argument, and one from a nested expansion.
+    assert_eq!(tokens[1].original_location, Location { line: 3, pos: 11 });
+    assert_eq!(tokens[1].expansion, vec!["A".to_string()]);
+    assert_eq!(
+        tokens[2],
+        MappedToken {
+            token: Token {
+                value: TokenValue::Ident("b".into()),
+                location: Location { line: 1, pos: 10 },
+                raw: None,
+                invocation: None,
+            },
+            original_location: Location { line: 3, pos: 11 },
+            expansion: vec!["A".to_string(), "B".to_string()],
+            provenance: Provenance::Definition(Location { line: 1, pos: 10 }),
+            source_string: 0,
+        }
+    );
+
+    // Test that errors are returned.
+    assert_eq!(
+        Preprocessor::new("#error").into_mapped_tokens(),
+        Err((
+            PreprocessorError::ErrorDirective,
+            Location { line: 1, pos: 1 }
+        ))
+    );
+}
+
+#[test]
+fn if_signedness() {
+    // Test that the signed operand is converted to unsigned in comparisons
+    check_preprocessed_result(
+        "#if -1 < 1u
+         1
+         #else
+         2
+         #endif",
+        "2",
+    );
+    check_preprocessed_result(
+        "#if -1 < 1
+         1
+         #else
+         2
+         #endif",
+        "1",
+    );
+    check_preprocessed_result(
+        "#if -1 > 0u && -1 / 2u == 0x7FFFFFFFFFFFFFFF
+         1
+         #endif",
+        "1",
+    );
+
+    // Test that the right shift of an unsigned value is logical
+    check_preprocessed_result(
+        "#if (-1u >> 63) == 1 && (-1 >> 63) == -1
+         1
+         #endif",
+        "1",
+    );
+
+    // Test that comparisons give signed values
+    check_preprocessed_result(
+        "#if (1u < 2u) - 2 < 0
+         1
+         #endif",
+        "1",
+    );
+}
+
+#[test]
+fn reserved_macro_names() {
+    // Test that predefined macros can't be redefined or undefined
+    check_preprocessing_error(
+        "#define __LINE__ 5",
+        PreprocessorError::ReservedMacroRedefinition("__LINE__".into()),
+    );
+    check_preprocessing_error(
+        "#undef GL_core_profile",
+        PreprocessorError::ReservedMacroRedefinition("GL_core_profile".into()),
+    );
+
+    // Test that all names starting with two underscores are reserved
+    check_preprocessing_error(
+        "#define __my 1",
+        PreprocessorError::ReservedMacroRedefinition("__my".into()),
+    );
+    check_preprocessing_error(
+        "#undef __my",
+        PreprocessorError::ReservedMacroRedefinition("__my".into()),
+    );
+
+    // Test that a single underscore is fine, as well as reserved names in skipped blocks
+    check_preprocessed_result(
+        "#define _my 1
+         #if 0
+         #define __my 2
+         #endif
+         _my",
+        "1",
+    );
+}
+
+#[test]
+fn stats() {
+    let mut pp = Preprocessor::new(
+        "// A comment
+         #define A(x) x + B
+         #define B /* b */ 1
+         A(B) A
+         ;",
+    );
+    for item in &mut pp {
+        item.unwrap();
+    }
+    assert_eq!(
+        pp.stats(),
+        LexStats {
+            token_count: 5,
+            line_count: 5,
+            comment_count: 2,
+            macro_expansions: 3,
+        }
+    );
+}
+
+#[test]
+fn if_division_and_shifts() {
+    // Test that division and modulo by zero are errors
+    check_preprocessing_error("#if 5 % 0", PreprocessorError::DivideByZero);
+    check_preprocessing_error("#if 5 / (1 - 1)", PreprocessorError::DivideByZero);
+    let err = Preprocessor::new("#if 5 / 0").next().unwrap().unwrap_err();
+    assert_eq!(
+        err,
+        (
+            PreprocessorError::DivideByZero,
+            Location { line: 1, pos: 6 }
+        )
+    );
+
+    // Test that the division follows C semantics
+    check_preprocessed_result(
+        "#if -7 / 2 == -3 && -7 % 2 == -1 && 7u % 4 == 3
+         1
+         #endif",
+        "1",
+    );
+
+    // Test shifts in range
+    check_preprocessed_result(
+        "#if 1 << 3 == 8 && 1 << 63 < 0 && 16 >> 4 == 1
+         1
+         #endif",
+        "1",
+    );
+
+    // Test that shift amounts outside of [0, 64) are errors
+    check_preprocessing_error("#if 1 << 70", PreprocessorError::ShiftOutOfRange);
+    check_preprocessing_error("#if 1 << 64", PreprocessorError::ShiftOutOfRange);
+    check_preprocessing_error("#if 1 >> -1", PreprocessorError::ShiftOutOfRange);
+}
+
+#[test]
+fn if_booleans() {
+    // Test that true and false evaluate to 1 and 0 in #if
+    check_preprocessed_result(
+        "#if true
+         1
+         #endif
+         #if false
+         2
+         #endif
+         #if true == 1 && !false
+         3
+         #endif",
+        "1 3",
+    );
+
+    // Test that they are still identifiers outside of directives
+    check_preprocessed_result("true false", "true false");
+}
+
+#[test]
+fn default_integer_type() {
+    let integer = |options: PreprocessorOptions| match Preprocessor::with_options("5 6u", options)
+        .next()
+        .unwrap()
+        .unwrap()
+        .value
+    {
+        TokenValue::Integer(integer) => integer,
+        _ => unreachable!(),
+    };
+
+    // Test that suffixless literals are signed 32-bit by default.
+    let default = integer(Dialect::Vulkan.options());
+    assert_eq!((default.width, default.signed), (32, true));
+
+    // Test overriding the default width and signedness.
+    let mut options = Dialect::Vulkan.options();
+    options.lexer.default_integer_width = 64;
+    options.lexer.default_integer_signed = false;
+    let overriden = integer(options.clone());
+    assert_eq!((overriden.width, overriden.signed), (64, false));
+
+    // Test that the default width must be allowed by the dialect.
+    let mut es_options = Dialect::GlslEs.options();
+    es_options.lexer.default_integer_width = 64;
+    let error = Preprocessor::with_options("1", es_options).next().unwrap();
+    assert_eq!(
+        error,
+        Err((
+            PreprocessorError::NotSupported64BitLiteral,
+            Location { line: 1, pos: 0 }
+        ))
+    );
+
+    // Test that widths other than 16, 32 and 64 are rejected.
+    for width in [0, 8, 128] {
+        let mut options = Dialect::Vulkan.options();
+        options.lexer.default_integer_width = width;
+        let error = Preprocessor::with_options("1", options).next().unwrap();
+        assert_eq!(
+            error,
+            Err((
+                PreprocessorError::InvalidIntegerWidth(width),
+                Location { line: 1, pos: 0 }
+            ))
+        );
+    }
+
+    // Test that it affects the promotion rules in #if.
+    check_preprocessed_result(
+        "#if -1 < 1
+         1
+         #endif",
+        "1",
+    );
+    let output: Vec<PreprocessorItem> = Preprocessor::with_options(
+        "#if -1 < 1
+         1
+         #endif",
+        options,
+    )
+    .collect();
+    assert!(output.is_empty());
+}
+
+#[test]
+fn conditional_depth() {
+    let mut pp = Preprocessor::new(
+        "a
+         #if 1
+         b
+         #ifdef FOO
+         #else
+         c
+         #endif
+         d
+         #endif
+         e",
+    );
+    assert_eq!(pp.conditional_depth(), 0);
+
+    // Test that the depth is updated as the directives before each token are processed.
+    let mut depths = Vec::new();
+    while let Some(item) = pp.next() {
+        item.unwrap();
+        depths.push(pp.conditional_depth());
+    }
+    assert_eq!(depths, vec![0, 1, 2, 1, 0]);
+}
+
+#[test]
+fn unfinished_block_location() {
+    // Test that the error points at the outermost unclosed directive, and is only reported once.
+    let items: Vec<PreprocessorItem> = Preprocessor::new(
+        "a
+         #if 1
+         #ifdef B
+         #if 1
+         #endif",
+    )
+    .collect();
+    assert_eq!(items.len(), 2);
+    assert_eq!(
+        items[1],
+        Err((
+            PreprocessorError::UnfinishedBlock,
+            Location { line: 2, pos: 10 }
+        ))
+    );
+}
+
+#[test]
+fn ifdef_extra_tokens() {
+    let input = "#define A
+         #ifdef A B
+         1
+         #endif
+         #ifndef A B
+         2
+         #endif";
+
+    // Test that extra tokens are an error by default but ignored by the permissive dialect.
+    check_preprocessing_error(
+        input,
+        PreprocessorError::Expected {
+            expected: ExpectedKind::EndOfLine,
+            found: TokenValue::Ident("B".into()),
+        },
+    );
+    let output: Vec<Token> = Preprocessor::with_options(input, Dialect::Permissive.into())
+        .map(|item| item.unwrap())
+        .collect();
+    assert_eq!(output.len(), 1);
+    assert_eq!(
+        output[0].value,
+        TokenValue::Integer(Integer {
+            value: 1,
+            signed: true,
+            width: 32
+        })
+    );
+
+    // Test that the name is still required.
+    let error = Preprocessor::with_options("#ifndef\n#endif", Dialect::Permissive.into())
+        .next()
+        .unwrap();
+    assert_eq!(
+        error,
+        Err((
+            PreprocessorError::UnexpectedNewLine,
+            Location { line: 1, pos: 7 }
+        ))
+    );
+}
+
+#[test]
+fn define_argument_splitting() {
+    // Test that commas inside of nested () don't split arguments
+    check_preprocessed_result(
+        "#define F(a, b, c) c b a
+         F(a, (b,c), d)",
+        "d (b,c) a",
+    );
+
+    // Test that commas inside of [] split arguments by default, like in C
+    check_preprocessed_result(
+        "#define F(a, b, c) c b a
+         F(x[1,2], y)",
+        "y 2] x[1",
+    );
+
+    // Test that they don't with bracket_argument_commas
+    let options = PreprocessorOptions {
+        bracket_argument_commas: true,
+        ..Default::default()
+    };
+    let values: Vec<TokenValue> = Preprocessor::with_options(
+        "#define F(a, b, c) c b a
+         F(x[1,2], y, z)",
+        options,
+    )
+    .map(|item| item.unwrap().value)
+    .collect();
+    let expected: Vec<TokenValue> = NoopPreprocessor::new("z y x[1,2]")
+        .map(|item| item.unwrap().value)
+        .collect();
+    assert_eq!(values, expected);
+
+    // Test that F() is a single empty argument for a one parameter macro
+    check_preprocessed_result(
+        "#define F(a) [a]
+         F()",
+        "[]",
     );
+
+    // Test that F(,) gives two empty arguments
+    check_preprocessed_result(
+        "#define F(a, b) [a|b]
+         F(,)
+         F(a,)",
+        "[|] [a|]",
+    );
+    check_preprocessing_error(
+        "#define F(a) a
+         F(,)",
+        PreprocessorError::TooManyDefineArguments,
+    );
+    check_preprocessing_error(
+        "#define F(a, b, c) a
+         F(,)",
+        PreprocessorError::TooFewDefineArguments,
+    );
+}
+
+#[test]
+fn macros_only() {
+    let input = "#define A 1
+         #if 0
+         A
+         #endif";
+
+    // Test that defines are expanded but other directives are errors by default.
+    let values: Vec<TokenValue> = Preprocessor::macros_only(
+        "#define A 1
+         #define B A
+         #undef A
+         A B",
+    )
+    .map(|item| item.unwrap().value)
+    .collect();
+    assert_eq!(values, vec![TokenValue::Ident("A".into()); 2]);
+    assert_eq!(
+        Preprocessor::macros_only(input).next(),
+        Some(Err((
+            PreprocessorError::UnknownDirective,
+            Location { line: 2, pos: 10 }
+        )))
+    );
+
+    // Test that they can be ignored instead.
+    let options = PreprocessorOptions {
+        macros_only: true,
+        error_on_unknown_directive: false,
+        ..Default::default()
+    };
+    let values: Vec<TokenValue> = Preprocessor::with_options(input, options)
+        .map(|item| item.unwrap().value)
+        .collect();
+    assert_eq!(
+        values,
+        vec![TokenValue::Integer(Integer {
+            value: 1,
+            signed: true,
+            width: 32
+        })]
+    );
+}
+
+#[test]
+fn expected_token_errors() {
+    // Test that directives say what they expected
+    check_preprocessing_error(
+        "#define 5",
+        PreprocessorError::Expected {
+            expected: ExpectedKind::Identifier,
+            found: TokenValue::Integer(Integer {
+                value: 5,
+                signed: true,
+                width: 32,
+            }),
+        },
+    );
+    check_preprocessing_error(
+        "#undef +",
+        PreprocessorError::Expected {
+            expected: ExpectedKind::Identifier,
+            found: TokenValue::Punct(Punct::Plus),
+        },
+    );
+    assert_eq!(
+        PreprocessorError::Expected {
+            expected: ExpectedKind::EndOfLine,
+            found: TokenValue::Punct(Punct::Plus),
+        }
+        .to_string(),
+        "expected the end of the line, found Punct(Plus)"
+    );
+
+    // Test that the end of the line is still reported as unexpected
+    check_preprocessing_error("#undef", PreprocessorError::UnexpectedNewLine);
+}
+
+#[test]
+fn validate_macro_bodies() {
+    let params = vec!["a".to_string(), "b".to_string()];
+
+    // Test valid bodies
+    assert_eq!(validate_macro_body(&params, "(a + b) * 2.0"), Ok(()));
+    assert_eq!(validate_macro_body(&[], ""), Ok(()));
+    assert_eq!(validate_macro_body(&[], "1 \\\n + 2\n"), Ok(()));
+
+    // Test that # and ## are rejected like in #define
+    assert_eq!(
+        validate_macro_body(&params, "## a"),
+        Err((
+            PreprocessorError::UnexpectedHash,
+            Location { line: 1, pos: 0 }
+        ))
+    );
+    check_preprocessing_error("#define A(a) ## a", PreprocessorError::UnexpectedHash);
+
+    // Test other errors
+    assert_eq!(
+        validate_macro_body(&params, "a\nb"),
+        Err((
+            PreprocessorError::UnexpectedNewLine,
+            Location { line: 1, pos: 1 }
+        ))
+    );
+    assert_eq!(
+        validate_macro_body(&params, "a @"),
+        Err((
+            PreprocessorError::UnexpectedCharacter('@'),
+            Location { line: 1, pos: 2 }
+        ))
+    );
+    assert_eq!(
+        validate_macro_body(&["a".to_string(), "a".to_string()], "a"),
+        Err((
+            PreprocessorError::DuplicateParameter,
+            Location { line: 0, pos: 4 }
+        ))
+    );
+
+    // Test that parameters are checked like in #define, on line 0 of the list written as (a, b)
+    assert!(matches!(
+        validate_macro_body(&["1".to_string()], "a"),
+        Err((
+            PreprocessorError::UnexpectedToken(_),
+            Location { line: 0, pos: 1 }
+        ))
+    ));
+    assert!(matches!(
+        validate_macro_body(&["a b".to_string()], "a"),
+        Err((
+            PreprocessorError::UnexpectedToken(_),
+            Location { line: 0, pos: 3 }
+        ))
+    ));
+}
+
+#[test]
+fn directive_leading_whitespace() {
+    // Test that whitespace, comments and line continuations before the # are allowed
+    check_preprocessed_result(
+        "   #define A 1
+\t#define B 2
+\\
+#define C 3
+ /* comment */ \\
+ \t # \\
+ define D 4
+A B C D",
+        "1 2 3 4",
+    );
+}
+
+#[test]
+fn if_expression_ast() {
+    let mut conditions = Vec::new();
+    let mut pp = Preprocessor::new(
+        "#define A
+         #if defined(A) && B > 2
+         #elif 1
+         #endif",
+    );
+    pp.on_event(|event| {
+        if let PreprocessEvent::Condition { expression, value } = event {
+            conditions.push((expression, value));
+        }
+    });
+    assert_eq!(pp.count(), 0);
+
+    // Test the shape of the expression, and that it is evaluated.
+    let two = Expr::Integer(Integer {
+        value: 2,
+        signed: true,
+        width: 32,
+    });
+    assert_eq!(
+        conditions[0],
+        (
+            Expr::Binary {
+                op: Punct::LogicalAnd,
+                location: Location { line: 2, pos: 24 },
+                left: Box::new(Expr::Defined {
+                    name: "A".into(),
+                    defined: true
+                }),
+                right: Box::new(Expr::Binary {
+                    op: Punct::RightAngle,
+                    location: Location { line: 2, pos: 29 },
+                    left: Box::new(Expr::Undefined("B".into())),
+                    right: Box::new(two),
+                }),
+            },
+            false
+        )
+    );
+    assert_eq!(conditions.len(), 2);
+    assert!(conditions[1].1);
+
+    // Test that || works and that && and || short-circuit.
+    check_preprocessed_result(
+        "#if 0 || 1
+         1
+         #endif
+         #if 0 && 1 / 0
+         #elif 1 || 1 % 0
+         2
+         #endif",
+        "1 2",
+    );
+}
+
+#[test]
+fn version_profile() {
+    let profile = |input| match Preprocessor::new(input).next() {
+        Some(Ok(Token {
+            value: TokenValue::Version(version),
+            ..
+        })) => version.profile(),
+        _ => unreachable!(),
+    };
+
+    // Test the known profiles, including the Vulkan one
+    assert_eq!(profile("#version 450"), Ok(None));
+    assert_eq!(profile("#version 450 core"), Ok(Some(Profile::Core)));
+    assert_eq!(
+        profile("#version 450 compatibility"),
+        Ok(Some(Profile::Compatibility))
+    );
+    assert_eq!(profile("#version 300 es"), Ok(Some(Profile::Es)));
+    assert_eq!(profile("#version 460 vulkan"), Ok(Some(Profile::Vulkan)));
+
+    // Test that unknown profiles are errors
+    assert_eq!(
+        profile("#version 450 metal"),
+        Err((
+            PreprocessorError::UnexpectedToken(TokenValue::Ident("metal".into())),
+            Location { line: 1, pos: 13 }
+        ))
+    );
+    assert_eq!(
+        profile("#version 450 ;"),
+        Err((
+            PreprocessorError::UnexpectedToken(TokenValue::Punct(Punct::Semicolon)),
+            Location { line: 1, pos: 13 }
+        ))
+    );
+}
+
+#[test]
+fn mapped_token_provenance() {
+    let tokens = Preprocessor::new(
+        "#define ID(x) x
+         #define B b
+         #define BODY(x) body x
+         ID(foo) ID(B) BODY(bar)",
+    )
+    .into_mapped_tokens()
+    .unwrap();
+    let provenances: Vec<_> = tokens.iter().map(|token| token.provenance).collect();
+
+    assert_eq!(
+        provenances,
+        vec![
+            // Test that a token from an argument points at its call-site location.
+            Provenance::Argument(Location { line: 4, pos: 12 }),
+            // Test that a macro expanded in an argument points at its invocation.
+            Provenance::Argument(Location { line: 4, pos: 20 }),
+            // Test that tokens from the body point at the definition.
+            Provenance::Definition(Location { line: 3, pos: 25 }),
+            Provenance::Argument(Location { line: 4, pos: 28 }),
+        ]
+    );
+}
+
+#[test]
+fn directive_trailing_tokens() {
+    let input = "#if 0
+         #else extra
+         1
+         #endif extra
+         2";
+    let strict = PreprocessorOptions {
+        strict_directive_trailing: true,
+        ..Default::default()
+    };
+
+    // Test that trailing tokens are ignored by default.
+    check_preprocessed_result(input, "1 2");
+
+    // Test that they are errors in strict mode, that don't prevent processing the directives.
+    let (tokens, errors): (Vec<_>, Vec<_>) =
+        Preprocessor::with_options(input, strict.clone()).partition(Result::is_ok);
+    assert_eq!(tokens.len(), 2);
+    let errors: Vec<_> = errors.into_iter().map(Result::unwrap_err).collect();
+    assert_eq!(
+        errors,
+        vec![
+            (
+                PreprocessorError::ExtraTokensAfterDirective,
+                Location { line: 2, pos: 15 }
+            ),
+            (
+                PreprocessorError::ExtraTokensAfterDirective,
+                Location { line: 4, pos: 16 }
+            ),
+        ]
+    );
+    assert_eq!(
+        Preprocessor::with_options("#if 1\n#endif\n", strict).find_map(Result::err),
+        None
+    );
+
+    // Test that the GLSL dialects are strict, but not the permissive one.
+    let first_error = |dialect: Dialect| {
+        Preprocessor::with_options(input, dialect.into())
+            .find_map(Result::err)
+            .map(|(err, _)| err)
+    };
+    for dialect in &[Dialect::GlslEs, Dialect::GlslCore, Dialect::Vulkan] {
+        assert_eq!(
+            first_error(*dialect),
+            Some(PreprocessorError::ExtraTokensAfterDirective)
+        );
+    }
+    assert_eq!(first_error(Dialect::Permissive), None);
+}
+
+#[test]
+fn glsl_keywords_pass_through() {
+    let tokens: Vec<Token> = Preprocessor::new(
+        "#version 450
+layout(set=0) uniform Foo;
+in out",
+    )
+    .skip(1)
+    .map(|item| item.unwrap())
+    .collect();
+
+    let ident = |name: &str, line, pos| Token {
+        value: TokenValue::Ident(name.into()),
+        location: Location { line, pos },
+        raw: None,
+        invocation: None,
+    };
+    let punct = |punct, line, pos| Token {
+        value: TokenValue::Punct(punct),
+        location: Location { line, pos },
+        raw: None,
+        invocation: None,
+    };
+
+    // Test that GLSL keywords are plain identifiers that the preprocessor doesn't touch.
+    assert_eq!(
+        tokens,
+        vec![
+            ident("layout", 2, 0),
+            punct(Punct::LeftParen, 2, 6),
+            ident("set", 2, 7),
+            punct(Punct::Equal, 2, 10),
+            Token {
+                value: TokenValue::Integer(Integer {
+                    value: 0,
+                    signed: true,
+                    width: 32
+                }),
+                location: Location { line: 2, pos: 11 },
+                raw: None,
+                invocation: None,
+            },
+            punct(Punct::RightParen, 2, 12),
+            ident("uniform", 2, 14),
+            ident("Foo", 2, 22),
+            punct(Punct::Semicolon, 2, 25),
+            ident("in", 3, 0),
+            ident("out", 3, 3),
+        ]
+    );
+}
+
+#[test]
+fn stdgl_pragma() {
+    let mut events = Vec::new();
+    let mut pp = Preprocessor::new(
+        "#pragma STDGL invariant(all)
+         #pragma foo(off)
+         #pragma STDGL",
+    );
+    pp.on_event(|event| events.push(event));
+    let tokens: Vec<Token> = pp.map(|item| item.unwrap()).collect();
+
+    // Test that all the pragmas are still output.
+    assert_eq!(tokens.len(), 3);
+    match &tokens[0].value {
+        TokenValue::Pragma(pragma) => assert_eq!(pragma.tokens.len(), 5),
+        _ => unreachable!(),
+    }
+
+    // Test that only the STDGL pragmas produce events, with the tokens after STDGL.
+    let ident = |name: &str, pos| Token {
+        value: TokenValue::Ident(name.into()),
+        location: Location { line: 1, pos },
+        raw: None,
+        invocation: None,
+    };
+    let punct = |punct, pos| Token {
+        value: TokenValue::Punct(punct),
+        location: Location { line: 1, pos },
+        raw: None,
+        invocation: None,
+    };
+    assert_eq!(
+        events,
+        vec![
+            PreprocessEvent::StdglPragma {
+                tokens: vec![
+                    ident("invariant", 14),
+                    punct(Punct::LeftParen, 23),
+                    ident("all", 24),
+                    punct(Punct::RightParen, 27),
+                ]
+            },
+            PreprocessEvent::StdglPragma { tokens: vec![] },
+        ]
+    );
+}
+
+#[test]
+fn shared_preprocessor() {
+    let mut shared = SharedPreprocessor::new(Default::default());
+    shared.add_define("BASE", "1").unwrap();
+
+    let check_unit = |input: &str, expected: &[TokenValue]| {
+        let values: Vec<TokenValue> = shared
+            .process_unit(input)
+            .map(|item| item.unwrap().value)
+            .collect();
+        assert_eq!(values, expected);
+    };
+    let one = TokenValue::Integer(Integer {
+        value: 1,
+        signed: true,
+        width: 32,
+    });
+
+    // Test that the base macros are visible in each unit but defines don't leak between units.
+    check_unit(
+        "#define LOCAL 2
+         #undef BASE
+         LOCAL BASE",
+        &[
+            TokenValue::Integer(Integer {
+                value: 2,
+                signed: true,
+                width: 32,
+            }),
+            TokenValue::Ident("BASE".into()),
+        ],
+    );
+    check_unit("BASE LOCAL", &[one, TokenValue::Ident("LOCAL".into())]);
+
+    // Test that the units use the shared options.
+    let shared = SharedPreprocessor::new(Dialect::Permissive.into());
+    assert_eq!(shared.process_unit("#foo\n").count(), 0);
+}
+
+#[test]
+fn pragma_position() {
+    let values: Vec<TokenValue> = Preprocessor::new(
+        "a
+         #pragma optionNV(unroll all)
+         b",
+    )
+    .map(|item| item.unwrap().value)
+    .collect();
+
+    // Test that pragmas are output inline, between the tokens surrounding them in the source.
+    assert_eq!(values.len(), 3);
+    assert_eq!(values[0], TokenValue::Ident("a".into()));
+    match &values[1] {
+        TokenValue::Pragma(pragma) => {
+            assert_eq!(pragma.tokens[0].value, TokenValue::Ident("optionNV".into()));
+            assert_eq!(pragma.tokens.len(), 5);
+        }
+        _ => unreachable!(),
+    }
+    assert_eq!(values[2], TokenValue::Ident("b".into()));
+}
+
+#[test]
+fn if_large_literals() {
+    // Test that literals that don't fit in a signed int are unsigned, be it hexadecimal, octal or
+    // decimal.
+    for literal in &["0xFFFFFFFF", "037777777777", "4294967295", "2147483648"] {
+        check_preprocessed_result(
+            &format!(
+                "#if {0} > 0 && {0} > -1
+                 1
+                 #else
+                 2
+                 #endif",
+                literal
+            ),
+            "2",
+        );
+    }
+
+    // Test that literals that fit are still signed.
+    check_preprocessed_result(
+        "#if 0x7FFFFFFF > -1 && 2147483647 > -1
+         1
+         #endif",
+        "1",
+    );
+
+    // Test that the limit depends on the width of the literal.
+    let options = PreprocessorOptions {
+        lexer: lexer::LexerOptions {
+            allow_16bit_literals: true,
+            allow_64bit_literals: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let output: Vec<Token> = Preprocessor::with_options(
+        "#if 0xFFFFFFFFl > -1 && !(0x8000s > -1)
+         1
+         #endif",
+        options,
+    )
+    .map(|item| item.unwrap())
+    .collect();
+    assert_eq!(output.len(), 1);
+}
+
+#[test]
+fn known_directives() {
+    // Test that the known directives are handled by the preprocessor, even if their arguments are
+    // invalid.
+    for directive in DIRECTIVES {
+        assert!(is_known_directive(directive));
+        let input = format!("#{}", directive);
+        let error = Preprocessor::new(&input).find_map(Result::err);
+        assert_ne!(
+            error.map(|e| e.0),
+            Some(PreprocessorError::UnknownDirective)
+        );
+    }
+
+    // Test that unknown directives aren't.
+    check_preprocessing_error("#include", PreprocessorError::UnknownDirective);
+    assert!(!is_known_directive("include"));
+    assert!(!is_known_directive("DEFINE"));
+    assert!(!is_known_directive(""));
+}
+
+#[test]
+fn if_macro_expansion() {
+    // Test that object-like macros are expanded in the condition.
+    check_preprocessed_result(
+        "#define N 4
+         #if N > 2
+         1
+         #endif",
+        "1",
+    );
+
+    // Test that the operand of defined isn't expanded, with and without parenthesis.
+    check_preprocessed_result(
+        "#define N M
+         #if defined(N) && defined N
+         1
+         #endif",
+        "1",
+    );
+
+    // Test that function-like macros are expanded, including nested ones.
+    check_preprocessed_result(
+        "#define N 3
+         #define SQUARE(x) ((x) * (x))
+         #if SQUARE(N) == 9 && SQUARE(SQUARE(2)) == 16
+         1
+         #endif",
+        "1",
+    );
+
+    // Test that a function-like macro name without arguments is an undefined identifier.
+    check_preprocessed_result(
+        "#define F(x) x
+         #if F
+         1
+         #else
+         2
+         #endif",
+        "2",
+    );
+}
+
+#[test]
+fn if_short_circuit_calls() {
+    // Test that calls to undefined macros are allowed when they are not evaluated.
+    check_preprocessed_result(
+        "#if defined(F) && F(1)
+         1
+         #elif !defined(F) || F(1, (2))
+         2
+         #endif",
+        "2",
+    );
+    check_preprocessed_result(
+        "#if 0 && (F(1) || G(2)) || 1
+         1
+         #endif",
+        "1",
+    );
+
+    // Test that they are still errors when evaluated.
+    check_preprocessing_error(
+        "#if 1 && F(1)
+         #endif",
+        PreprocessorError::UnexpectedToken(TokenValue::Punct(Punct::LeftParen)),
+    );
+    check_preprocessing_error(
+        "#if 0 || 1 && F(1)
+         #endif",
+        PreprocessorError::UnexpectedToken(TokenValue::Punct(Punct::LeftParen)),
+    );
+
+    // Test that defined macros aren't expanded in operands that are not evaluated, so a call with
+    // the wrong number of arguments isn't an error there, and that parsing continues after them.
+    check_preprocessed_result(
+        "#define G(a, b) a
+         #if 0 && G(1)
+         1
+         #elif 1 || G(1) / 0
+         2
+         #endif
+         #if 0 && (G(1) + 1) * 2 || 1
+         3
+         #endif",
+        "2 3",
+    );
+    check_preprocessing_error(
+        "#define G(a, b) a
+         #if 1 && G(1)
+         #endif",
+        PreprocessorError::TooFewDefineArguments,
+    );
+
+    // Test that the operand is still required.
+    check_preprocessing_error(
+        "#if 0 &&
+         #endif",
+        PreprocessorError::UnexpectedEndOfInput,
+    );
+    check_preprocessing_error(
+        "#if (0 && )
+         #endif",
+        PreprocessorError::UnexpectedToken(TokenValue::Punct(Punct::RightParen)),
+    );
+}
+
+#[test]
+fn empty_conditionals() {
+    let empty_conditionals = |input| {
+        let options = PreprocessorOptions {
+            warn_empty_conditionals: true,
+            ..Default::default()
+        };
+        let mut events = Vec::new();
+        let mut pp = Preprocessor::with_options(input, options);
+        pp.on_event(|event| events.push(event));
+        for item in pp {
+            item.unwrap();
+        }
+        events
+            .into_iter()
+            .filter_map(|event| match event {
+                PreprocessEvent::EmptyConditional { location } => Some(location),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // Test that an #if without tokens produces the warning, at the location of the #if.
+    assert_eq!(
+        empty_conditionals("#if 1\n#endif"),
+        vec![Location { line: 1, pos: 1 }]
+    );
+    assert_eq!(
+        empty_conditionals("a\n#ifdef A\n#elif 1\n#else\n#endif"),
+        vec![Location { line: 2, pos: 1 }]
+    );
+
+    // Test that blocks with tokens or directives in any branch, even excluded, don't.
+    assert_eq!(empty_conditionals("#if 0\na\n#endif"), vec![]);
+    assert_eq!(empty_conditionals("#if 1\n#else\na\n#endif"), vec![]);
+    assert_eq!(empty_conditionals("#if 1\n#define A\n#endif"), vec![]);
+
+    // Test that only the empty inner block of nested blocks produces the warning.
+    assert_eq!(
+        empty_conditionals("#if 0\n#if 1\n#endif\n#endif"),
+        vec![Location { line: 2, pos: 1 }]
+    );
+
+    // Test that there is no warning by default.
+    let mut events = Vec::new();
+    let mut pp = Preprocessor::new("#if 1\n#endif");
+    pp.on_event(|event| events.push(event));
+    for item in pp {
+        item.unwrap();
+    }
+    assert!(!events
+        .iter()
+        .any(|event| matches!(event, PreprocessEvent::EmptyConditional { .. })));
+}
+
+#[test]
+fn diagnostic_sink() {
+    struct CountingSink {
+        errors: usize,
+        warnings: usize,
+    }
+
+    impl DiagnosticSink for CountingSink {
+        fn error(&mut self, _error: PreprocessorError, _location: Location) {
+            self.errors += 1;
+        }
+
+        fn warning(&mut self, _warning: PreprocessorWarning, _location: Location) {
+            self.warnings += 1;
+        }
+    }
+
+    let input = "a\n#foo\nb\n#undef __LINE__\n#if 1\n#endif\nc";
+    let options = PreprocessorOptions {
+        warn_empty_conditionals: true,
+        ..Default::default()
+    };
+    let ident = |name: &str| TokenValue::Ident(name.into());
+
+    // Test that a custom sink gets all the diagnostics and that processing continues after errors.
+    let mut sink = CountingSink {
+        errors: 0,
+        warnings: 0,
+    };
+    let tokens = Preprocessor::with_options(input, options.clone()).process_into(&mut sink);
+    assert_eq!(
+        tokens.into_iter().map(|t| t.value).collect::<Vec<_>>(),
+        vec![ident("a"), ident("b"), ident("c")]
+    );
+    assert_eq!(sink.errors, 2);
+    assert_eq!(sink.warnings, 1);
+
+    // Test that the default sink collects them in order.
+    let mut diagnostics = Diagnostics::default();
+    Preprocessor::with_options(input, options).process_into(&mut diagnostics);
+    assert_eq!(
+        diagnostics,
+        Diagnostics {
+            errors: vec![
+                (
+                    PreprocessorError::UnknownDirective,
+                    Location { line: 2, pos: 1 }
+                ),
+                (
+                    PreprocessorError::ReservedMacroRedefinition("__LINE__".into()),
+                    Location { line: 4, pos: 7 }
+                ),
+            ],
+            warnings: vec![(
+                PreprocessorWarning::EmptyConditional,
+                Location { line: 5, pos: 1 }
+            )],
+        }
+    );
+}
+
+#[test]
+fn line_overflow() {
+    // Test that line numbers past u32::MAX produce a single LineOverflow, at the start of the
+    // first overflowing line even if it is empty, and that __LINE__ saturates without losing
+    // tokens.
+    let items: Vec<PreprocessorItem> = Preprocessor::new(
+        "#line 4294967294
+         __LINE__
+
+         a __LINE__
+         b __LINE__",
+    )
+    .collect();
+    let line = |value| {
+        Ok(TokenValue::Integer(Integer {
+            value,
+            signed: false,
+            width: 32,
+        }))
+    };
+    assert_eq!(
+        items
+            .into_iter()
+            .map(|item| item.map(|token| token.value))
+            .collect::<Vec<_>>(),
+        vec![
+            line(4294967295),
+            Err((
+                PreprocessorError::LineOverflow,
+                Location { line: 3, pos: 0 }
+            )),
+            Ok(TokenValue::Ident("a".into())),
+            line(4294967295),
+            Ok(TokenValue::Ident("b".into())),
+            line(4294967295),
+        ]
+    );
+
+    // Test that a #line directive on the last line doesn't overflow.
+    check_preprocessed_result("#line 4294967295\n", "");
+}
+
+#[test]
+fn shader_stage_pragma() {
+    // Test that the stage is exposed and that the pragma is still output.
+    let mut pp = Preprocessor::new("#pragma shader_stage(vertex)\na");
+    assert_eq!(pp.shader_stage(), None);
+    match pp.next().unwrap().unwrap().value {
+        TokenValue::Pragma(pragma) => assert_eq!(pragma.tokens.len(), 4),
+        _ => unreachable!(),
+    }
+    assert_eq!(pp.shader_stage(), Some(ShaderStage::Vertex));
+
+    let mut pp = Preprocessor::new("#pragma shader_stage( fragment )");
+    assert!(pp.next().unwrap().is_ok());
+    assert_eq!(pp.shader_stage(), Some(ShaderStage::Fragment));
+
+    // Test that skipped pragmas and other pragmas don't set the stage.
+    let mut pp =
+        Preprocessor::new("#if 0\n#pragma shader_stage(vertex)\n#endif\n#pragma foo(vertex)");
+    pp.by_ref().for_each(|item| {
+        item.unwrap();
+    });
+    assert_eq!(pp.shader_stage(), None);
+
+    // Test that unknown stages and malformed directives are errors.
+    let error = |input| Preprocessor::new(input).next().unwrap().unwrap_err();
+    assert_eq!(
+        error("#pragma shader_stage(vertx)"),
+        (
+            PreprocessorError::UnexpectedToken(TokenValue::Ident("vertx".into())),
+            Location { line: 1, pos: 21 }
+        )
+    );
+    assert_eq!(
+        error("#pragma shader_stage(1)").0,
+        PreprocessorError::UnexpectedToken(TokenValue::Integer(Integer {
+            value: 1,
+            signed: true,
+            width: 32
+        }))
+    );
+    assert_eq!(
+        error("#pragma shader_stage[vertex)").0,
+        PreprocessorError::UnexpectedToken(TokenValue::Punct(Punct::LeftBracket))
+    );
+    assert_eq!(
+        error("#pragma shader_stage(vertex) a").0,
+        PreprocessorError::UnexpectedToken(TokenValue::Ident("a".into()))
+    );
+    assert_eq!(
+        error("#pragma shader_stage(vertex").0,
+        PreprocessorError::UnexpectedNewLine
+    );
+}
+
+#[test]
+fn has_extension() {
+    let idents = |input| -> Vec<TokenValue> {
+        Preprocessor::new(input)
+            .map(|item| item.unwrap().value)
+            .filter(|value| value.kind() == TokenKind::Ident)
+            .collect()
+    };
+    let ident = |name: &str| TokenValue::Ident(name.into());
+
+    // Test that __has_extension is true for enabled extensions.
+    assert_eq!(
+        idents(
+            "#extension GL_foo : enable
+         #if __has_extension(GL_foo)
+         a
+         #endif
+         #if __has_extension(GL_bar) || __has_feature(GL_bar)
+         b
+         #endif"
+        ),
+        vec![ident("a")]
+    );
+
+    // Test that the argument isn't macro expanded and that disabling extensions is tracked.
+    assert_eq!(
+        idents(
+            "#define GL_foo GL_bar
+         #extension GL_bar : require
+         #extension GL_baz : warn
+         #extension GL_baz : disable
+         #if __has_extension(GL_foo) || __has_extension(GL_baz)
+         a
+         #elif __has_feature(GL_bar)
+         b
+         #endif"
+        ),
+        vec![ident("b")]
+    );
+
+    // Test that `all : disable` disables all the extensions.
+    let mut pp = Preprocessor::new(
+        "#extension GL_foo : enable
+         #extension all : disable",
+    );
+    assert!(pp.next().unwrap().is_ok());
+    assert!(pp.is_extension_enabled("GL_foo"));
+    assert!(pp.next().unwrap().is_ok());
+    assert!(!pp.is_extension_enabled("GL_foo"));
+
+    // Test that malformed calls are errors.
+    check_preprocessing_error(
+        "#if __has_extension GL_foo\n#endif",
+        PreprocessorError::UnexpectedToken(TokenValue::Ident("GL_foo".into())),
+    );
+    check_preprocessing_error(
+        "#if __has_extension(1)\n#endif",
+        PreprocessorError::UnexpectedToken(TokenValue::Integer(Integer {
+            value: 1,
+            signed: true,
+            width: 32,
+        })),
+    );
+}
+
+#[test]
+fn raw_literals_through_macros() {
+    let mut options = PreprocessorOptions::default();
+    options.lexer.keep_raw_literals = true;
+    let raw = |input| -> Vec<Option<String>> {
+        Preprocessor::with_options(input, options.clone())
+            .map(|item| item.unwrap().raw)
+            .collect()
+    };
+
+    // Test that literals keep their spelling when passing through macro arguments and bodies.
+    assert_eq!(
+        raw("#define ID(x) x
+             ID(0x10)"),
+        vec![Some("0x10".into())]
+    );
+    assert_eq!(
+        raw("#define A 1.50e1 + 010u
+             A"),
+        vec![Some("1.50e1".into()), None, Some("010u".into())]
+    );
+
+    // Test that generated literals have no raw text.
+    assert_eq!(raw("__LINE__"), vec![None]);
+
+    // Test that there is no raw text by default.
+    let tokens: Vec<Token> = Preprocessor::new("0x10")
+        .map(|item| item.unwrap())
+        .collect();
+    assert_eq!(tokens[0].raw, None);
+}
+
+#[test]
+fn max_total_tokens() {
+    // C expands to 64 tokens, and each expansion is small enough for max_expansion_tokens.
+    let input = "#define A x x x x
+                 #define B A A A A
+                 #define C B B B B
+                 C C";
+    let options = |max_total_tokens| PreprocessorOptions {
+        max_total_tokens,
+        max_expansion_tokens: Some(64),
+        ..Default::default()
+    };
+
+    // Test that the whole output fits in a large enough limit.
+    let items: Vec<PreprocessorItem> =
+        Preprocessor::with_options(input, options(Some(128))).collect();
+    assert_eq!(items.len(), 128);
+    assert!(items.iter().all(|item| item.is_ok()));
+
+    // Test that going over the limit produces an error at the invocation, after which
+    // preprocessing stops.
+    let items: Vec<PreprocessorItem> =
+        Preprocessor::with_options(input, options(Some(100))).collect();
+    assert_eq!(items.len(), 101);
+    assert!(items[..100].iter().all(|item| item.is_ok()));
+    assert_eq!(
+        items[100],
+        Err((
+            PreprocessorError::TokenLimitExceeded,
+            Location { line: 4, pos: 19 }
+        ))
+    );
+}
+
+#[test]
+fn mapped_token_source_string() {
+    // Test that tokens report the source string set by #line, as used by tools that inline
+    // included files. Tokens of macros report the source string of the invocation.
+    let tokens = Preprocessor::new(
+        "#line 1 1
+         #define A a
+         #line 5 0
+         b A
+         #line 1 2
+         c A",
+    )
+    .into_mapped_tokens()
+    .unwrap();
+    assert_eq!(
+        tokens
+            .iter()
+            .map(|token| (token.token.value.to_string(), token.source_string))
+            .collect::<Vec<_>>(),
+        vec![
+            ("b".to_string(), 0),
+            ("a".to_string(), 0),
+            ("c".to_string(), 2),
+            ("a".to_string(), 2),
+        ]
+    );
+}
+
+#[test]
+fn macro_uses() {
+    let input = "#define FOO 1
+                 #define BAR(x) x FOO
+                 FOO + FOO
+                 BAR(FOO) BAR";
+    let options = PreprocessorOptions {
+        record_macro_uses: true,
+        ..Default::default()
+    };
+    let mut pp = Preprocessor::with_options(input, options);
+    pp.by_ref().for_each(|item| {
+        item.unwrap();
+    });
+
+    // Test that all the invocations are recorded, including the ones in arguments and macro
+    // bodies, but not function-like macro names without arguments.
+    let at = |line, pos| Location { line, pos };
+    assert_eq!(
+        pp.macro_uses(),
+        &[
+            ("FOO".to_string(), at(3, 17)),
+            ("FOO".to_string(), at(3, 23)),
+            ("BAR".to_string(), at(4, 17)),
+            ("FOO".to_string(), at(4, 21)),
+            ("FOO".to_string(), at(2, 34)),
+        ][..]
+    );
+
+    // Test that nothing is recorded by default.
+    let mut pp = Preprocessor::new(input);
+    pp.by_ref().for_each(|item| {
+        item.unwrap();
+    });
+    assert!(pp.macro_uses().is_empty());
+}
+
+#[test]
+fn prologue() {
+    // Test that macros and extensions of the prologue apply to the input, that the input keeps
+    // its line numbers and that its #version is still the first directive.
+    let mut pp =
+        Preprocessor::new("#version 450\nFOO __LINE__\n#if __has_extension(GL_foo)\na\n#endif");
+    pp.set_prologue("#define FOO 1\n#extension GL_foo : enable\n#line 100");
+    let tokens: Vec<TokenValue> = pp.map(|item| item.unwrap().value).collect();
+    assert_eq!(tokens.len(), 5);
+    match &tokens[0] {
+        TokenValue::Extension(extension) => assert!(!extension.has_non_directive_before),
+        _ => unreachable!(),
+    }
+    match &tokens[1] {
+        TokenValue::Version(version) => assert!(version.is_first_directive),
+        _ => unreachable!(),
+    }
+    assert_eq!(
+        tokens[2..],
+        [
+            TokenValue::Integer(Integer {
+                value: 1,
+                signed: true,
+                width: 32
+            }),
+            TokenValue::Integer(Integer {
+                value: 2,
+                signed: false,
+                width: 32
+            }),
+            TokenValue::Ident("a".into()),
+        ]
+    );
+
+    // Test that #version isn't the first directive if the input has tokens before it.
+    let mut pp = Preprocessor::new("a\n#version 450");
+    pp.set_prologue("#define FOO");
+    match pp.nth(1).unwrap().unwrap().value {
+        TokenValue::Version(version) => assert!(!version.is_first_directive),
+        _ => unreachable!(),
+    }
+
+    // Test that conditional blocks can't continue in the input.
+    let mut pp = Preprocessor::new("a\n#endif");
+    pp.set_prologue("#if 0");
+    assert_eq!(
+        pp.next(),
+        Some(Err((
+            PreprocessorError::UnfinishedBlock,
+            Location { line: 1, pos: 1 }
+        )))
+    );
+}
+
+#[test]
+fn cached_conditions() {
+    // Test that caching conditions gives the same result as evaluating them each time, including
+    // when the macros, extensions, line or source string they use change in between.
+    let input = "#define A 1
+                 #define F(x) (x + A)
+                 #if F(A) == 2
+                 a
+                 #endif
+                 #if F(A) == 2
+                 b
+                 #endif
+                 #undef A
+                 #define A 2
+                 #if F(A) == 2
+                 c
+                 #elif F(A) == 4
+                 d
+                 #endif
+                 #if __LINE__ == 16
+                 e
+                 #endif
+                 #if __LINE__ == 16
+                 f
+                 #endif
+                 #if __has_extension(GL_foo)
+                 g
+                 #endif
+                 #extension GL_foo : enable
+                 #if __has_extension(GL_foo)
+                 h
+                 #endif
+                 #line 30 1
+                 #if __FILE__ == 0
+                 i
+                 #endif
+                 #if __FILE__ == 1
+                 j
+                 #endif";
+
+    let uncached: Vec<PreprocessorItem> = Preprocessor::new(input).collect();
+    let options = PreprocessorOptions {
+        cache_conditions: true,
+        ..Default::default()
+    };
+    let cached: Vec<PreprocessorItem> = Preprocessor::with_options(input, options).collect();
+    assert_eq!(cached, uncached);
+
+    let idents: Vec<String> = cached
+        .into_iter()
+        .filter_map(|item| match item.unwrap().value {
+            TokenValue::Ident(name) => Some(name),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(idents, vec!["a", "b", "d", "e", "h", "j"]);
+}
+
+#[test]
+fn parse_expr_precedence() {
+    fn tokens(input: &str) -> Vec<Token> {
+        lexer::Lexer::new(input)
+            .map(Result::unwrap)
+            .filter(|token| token.value != LexerTokenValue::NewLine)
+            .map(|token| convert_lexer_token(token).unwrap())
+            .collect()
+    }
+
+    fn render(expr: &Expr) -> String {
+        match expr {
+            Expr::Integer(int) => int.value.to_string(),
+            Expr::Ident(name) => name.clone(),
+            Expr::Unary { op, operand, .. } => format!("({}{})", op, render(operand)),
+            Expr::Binary {
+                op, left, right, ..
+            } => format!("({} {} {})", render(left), op, render(right)),
+            _ => unreachable!(),
+        }
+    }
+
+    // Test that operators with mixed precedence are grouped like in GLSL, left-associatively.
+    let table = PrecTable::new();
+    let expr = parse_expr(
+        &tokens("a + b * -c << 2 == d & e | f ^ g && h ^^ i || j - k - l"),
+        &table,
+    )
+    .unwrap();
+    assert_eq!(
+        render(&expr),
+        "((((((((a + (b * (-c))) << 2) == d) & e) | (f ^ g)) && h) ^^ i) || ((j - k) - l))"
+    );
+
+    // Test that parentheses override the precedence.
+    let expr = parse_expr(&tokens("(a + b) * c"), &table).unwrap();
+    assert_eq!(render(&expr), "((a + b) * c)");
+
+    // Test that the table can change the precedence of operators.
+    let mut table = PrecTable::new();
+    table.set(Punct::Plus, Some(12));
+    assert_eq!(table.get(Punct::Plus), Some(12));
+    let expr = parse_expr(&tokens("a * b + c"), &table).unwrap();
+    assert_eq!(render(&expr), "(a * (b + c))");
+
+    // Test that operators removed from the table end the expression, which is then an error.
+    table.set(Punct::Star, None);
+    assert_eq!(
+        parse_expr(&tokens("a * b"), &table),
+        Err((
+            PreprocessorError::UnexpectedToken(TokenValue::Punct(Punct::Star)),
+            Location { line: 1, pos: 2 }
+        ))
+    );
+
+    // Test that float literals are rejected.
+    assert_eq!(
+        parse_expr(&tokens("a + 1.5"), &PrecTable::new()),
+        Err((
+            PreprocessorError::UnexpectedToken(TokenValue::Float(Float {
+                value: 1.5,
+                width: 32
+            })),
+            Location { line: 1, pos: 4 }
+        ))
+    );
+
+    // Test that a missing operand is reported at the last token.
+    assert_eq!(
+        parse_expr(&tokens("a +"), &PrecTable::new()),
+        Err((
+            PreprocessorError::UnexpectedEndOfInput,
+            Location { line: 1, pos: 2 }
+        ))
+    );
+
+    // Test that ^^ still isn't an operator in #if.
+    check_preprocessing_error(
+        "#if 1 ^^ 0\n#endif",
+        PreprocessorError::UnexpectedToken(TokenValue::Punct(Punct::LogicalXor)),
+    );
+}
+
+#[test]
+fn std_pragmas() {
+    // Test that optimize and debug pragmas produce events and are still output.
+    let mut events = Vec::new();
+    let mut pp = Preprocessor::new("#pragma optimize(off)\n#pragma debug( on )\n#pragma foo(on)");
+    pp.on_event(|event| events.push(event));
+    let tokens: Vec<Token> = pp.map(Result::unwrap).collect();
+    assert_eq!(tokens.len(), 3);
+    assert!(tokens
+        .iter()
+        .all(|token| matches!(token.value, TokenValue::Pragma(_))));
+    assert_eq!(
+        events,
+        vec![
+            PreprocessEvent::StdPragma {
+                pragma: StdPragma::Optimize(false)
+            },
+            PreprocessEvent::StdPragma {
+                pragma: StdPragma::Debug(true)
+            },
+        ]
+    );
+
+    // Test that invalid arguments are errors.
+    assert_eq!(
+        Preprocessor::new("#pragma optimize(maybe)")
+            .next()
+            .unwrap()
+            .unwrap_err(),
+        (
+            PreprocessorError::UnexpectedToken(TokenValue::Ident("maybe".into())),
+            Location { line: 1, pos: 17 }
+        )
+    );
+    check_preprocessing_error("#pragma debug(on", PreprocessorError::UnexpectedNewLine);
+}
+
+#[test]
+fn expand_macro_preview() {
+    let input = "#define B 2
+                 #define A (B + 1)
+                 #define F(x) x * A
+                 #define R R + 1
+                 A";
+    let mut pp = Preprocessor::new(input);
+    assert_eq!(pp.expand_macro_preview("A"), None);
+    let first = pp.next().unwrap().unwrap();
+
+    let preview = |pp: &Preprocessor, name| {
+        pp.expand_macro_preview(name).map(|tokens| {
+            tokens
+                .into_iter()
+                .map(|token| token.value)
+                .collect::<Vec<_>>()
+        })
+    };
+    let ident = |name: &str| TokenValue::Ident(name.into());
+    let punct = TokenValue::Punct;
+    let int = |value| {
+        TokenValue::Integer(Integer {
+            value,
+            signed: true,
+            width: 32,
+        })
+    };
+
+    // Test that object-like macros are fully expanded, using the macros they reference.
+    assert_eq!(
+        preview(&pp, "A"),
+        Some(vec![
+            punct(Punct::LeftParen),
+            int(2),
+            punct(Punct::Plus),
+            int(1),
+            punct(Punct::RightParen),
+        ])
+    );
+
+    // Test that function-like macros give their body and recursive macros stop expanding.
+    assert_eq!(
+        preview(&pp, "F"),
+        Some(vec![ident("x"), punct(Punct::Star), ident("A")])
+    );
+    assert_eq!(
+        preview(&pp, "R"),
+        Some(vec![ident("R"), punct(Punct::Plus), int(1)])
+    );
+    assert_eq!(preview(&pp, "C"), None);
+
+    // Test that the preprocessor isn't affected.
+    assert_eq!(first.value, punct(Punct::LeftParen));
+    let rest: Vec<TokenValue> = pp.map(|item| item.unwrap().value).collect();
+    assert_eq!(
+        rest,
+        vec![int(2), punct(Punct::Plus), int(1), punct(Punct::RightParen)]
+    );
+
+    // Test that the expansion limit applies.
+    let options = PreprocessorOptions {
+        max_expansion_tokens: Some(3),
+        ..Default::default()
+    };
+    let mut pp = Preprocessor::with_options(input, options);
+    pp.next();
+    assert_eq!(preview(&pp, "B"), Some(vec![int(2)]));
+    assert_eq!(preview(&pp, "A"), None);
+}
+
+#[test]
+fn preserve_directives() {
+    let input = "#version 450 core
+                 #extension GL_foo : enable
+                 #define A 1
+                 #pragma shader_stage(bogus)
+                 #if __has_extension(GL_foo)
+                 B
+                 #endif
+                 A";
+    let options = PreprocessorOptions {
+        preserve_directives: true,
+        ..Default::default()
+    };
+    let mut events = Vec::new();
+    let mut pp = Preprocessor::with_options(input, options);
+    pp.on_event(|event| events.push(event));
+    let tokens: Vec<Token> = pp.by_ref().map(Result::unwrap).collect();
+
+    // Test that #version, #extension and #pragma are output as is, while #define and #if are
+    // still processed.
+    assert_eq!(tokens.len(), 4);
+    match &tokens[0].value {
+        TokenValue::Version(version) => assert_eq!(version.tokens.len(), 2),
+        _ => unreachable!(),
+    }
+    match &tokens[1].value {
+        TokenValue::Extension(extension) => assert_eq!(extension.tokens.len(), 3),
+        _ => unreachable!(),
+    }
+    match &tokens[2].value {
+        TokenValue::Pragma(pragma) => assert_eq!(pragma.tokens.len(), 4),
+        _ => unreachable!(),
+    }
+    assert_eq!(
+        tokens[3].value,
+        TokenValue::Integer(Integer {
+            value: 1,
+            signed: true,
+            width: 32
+        })
+    );
+
+    // Test that they aren't interpreted.
+    assert_eq!(pp.shader_stage(), None);
+    assert!(!pp.is_extension_enabled("GL_foo"));
+    drop(pp);
+    assert_eq!(
+        events,
+        vec![
+            PreprocessEvent::Define { name: "A".into() },
+            PreprocessEvent::Condition {
+                expression: Expr::HasExtension {
+                    name: "GL_foo".into(),
+                    enabled: false
+                },
+                value: false
+            },
+        ]
+    );
+}
+
+#[test]
+fn expand_str() {
+    let mut pp = Preprocessor::new("#define FOO 1\n#define BAR (FOO * 2)\n#define F(x) x + FOO\na");
+    assert_eq!(
+        pp.expand_str("FOO").unwrap()[0].value,
+        TokenValue::Ident("FOO".into())
+    );
+    pp.next().unwrap().unwrap();
+
+    let values = |snippet| -> Vec<TokenValue> {
+        pp.expand_str(snippet)
+            .unwrap()
+            .into_iter()
+            .map(|token| token.value)
+            .collect()
+    };
+    let int = |value| {
+        TokenValue::Integer(Integer {
+            value,
+            signed: true,
+            width: 32,
+        })
+    };
+    let punct = TokenValue::Punct;
+
+    // Test that the snippet is expanded with the macros defined so far.
+    assert_eq!(
+        values("FOO + BAR"),
+        vec![
+            int(1),
+            punct(Punct::Plus),
+            punct(Punct::LeftParen),
+            int(1),
+            punct(Punct::Star),
+            int(2),
+            punct(Punct::RightParen),
+        ]
+    );
+    assert_eq!(
+        values("F(3)\nc"),
+        vec![
+            int(3),
+            punct(Punct::Plus),
+            int(1),
+            TokenValue::Ident("c".into())
+        ]
+    );
+
+    // Test that errors are reported with locations in the snippet, and directives aren't allowed.
+    assert_eq!(
+        pp.expand_str("F(1, 2)"),
+        Err((
+            PreprocessorError::TooManyDefineArguments,
+            Location { line: 1, pos: 1 }
+        ))
+    );
+    assert_eq!(
+        pp.expand_str("F(1").unwrap_err().0,
+        PreprocessorError::UnexpectedEndOfInput
+    );
+    assert_eq!(
+        pp.expand_str("#define G").unwrap_err().0,
+        PreprocessorError::UnexpectedHash
+    );
+}
+
+#[test]
+fn define_paren_whitespace() {
+    // Test that a parenthesis directly after the name makes the macro function-like.
+    check_preprocessed_result("#define F(x) [x]\nF(1)", "[1]");
+
+    // Test that whitespace or a comment before the parenthesis makes it object-like, with the
+    // parenthesis in its body.
+    check_preprocessed_result("#define F (x) [x]\nF(1)", "(x) [x](1)");
+    check_preprocessed_result("#define F\t(x)\nF", "(x)");
+    check_preprocessed_result("#define F/**/(x) x\nF(1)", "(x) x(1)");
+
+    // Test that a line continuation isn't whitespace.
+    check_preprocessed_result("#define F\\\n(x) [x]\nF(1)", "[1]");
+}
+
+#[test]
+fn identifier_resolver() {
+    let input = "#if GPU_TIER >= 2
+                 a
+                 #endif
+                 #if defined(GPU_TIER) || UNKNOWN
+                 b
+                 #endif
+                 #define GPU_TIER 1
+                 #if GPU_TIER >= 2
+                 c
+                 #endif";
+    let mut events = Vec::new();
+    let mut pp = Preprocessor::new(input);
+    pp.set_identifier_resolver(|name| match name {
+        "GPU_TIER" => Some(2),
+        _ => None,
+    });
+    pp.on_event(|event| {
+        if let PreprocessEvent::Condition { expression, .. } = event {
+            events.push(expression);
+        }
+    });
+    let idents: Vec<TokenValue> = pp.map(|item| item.unwrap().value).collect();
+
+    // Test that the resolver gives the value of identifiers that aren't macros, but isn't used by
+    // defined or for macros.
+    assert_eq!(idents, vec![TokenValue::Ident("a".into())]);
+    assert_eq!(
+        events[0],
+        Expr::Binary {
+            op: Punct::GreaterEqual,
+            location: Location { line: 1, pos: 13 },
+            left: Box::new(Expr::Resolved {
+                name: "GPU_TIER".into(),
+                value: 2
+            }),
+            right: Box::new(Expr::Integer(Integer {
+                value: 2,
+                signed: true,
+                width: 32
+            })),
+        }
+    );
+}
+
+#[test]
+fn directive_hashes() {
+    let items = |input| -> Vec<PreprocessorItem> { Preprocessor::new(input).collect() };
+    let hash_at = |pos| Err((PreprocessorError::UnexpectedHash, Location { line: 1, pos }));
+    let ident = |name: &str, line, pos| {
+        Ok(Token {
+            value: TokenValue::Ident(name.into()),
+            location: Location { line, pos },
+            raw: None,
+            invocation: None,
+        })
+    };
+
+    // Test that a second # in a directive is an error at its location, and the rest of the line
+    // isn't output.
+    assert_eq!(items("# # define A\nb"), vec![hash_at(2), ident("b", 2, 0)]);
+    assert_eq!(items("##define A\nb"), vec![hash_at(1), ident("b", 2, 0)]);
+
+    // Test that a # where a directive expects a name is an error at its location.
+    assert_eq!(items("#define #foo\nb"), vec![hash_at(8), ident("b", 2, 0)]);
+    assert_eq!(items("#undef # A\nb"), vec![hash_at(7), ident("b", 2, 0)]);
+    assert_eq!(items("#define A #\nA"), vec![hash_at(10), ident("A", 2, 0)]);
+
+    // Test that a # outside of directives is an error without skipping the line.
+    assert_eq!(
+        items("a # b"),
+        vec![ident("a", 1, 0), hash_at(2), ident("b", 1, 4)]
+    );
+
+    // Test that skipped directives with several # are ignored.
+    check_preprocessed_result("#if 0\n# # define A\n#endif\nb", "b");
+}
+
+#[test]
+fn unused_macros() {
+    let input = "#define USED 1
+                 #define UNUSED 2
+                 #define FLAG
+                 #define INNER 3
+                 #define OUTER INNER
+                 #define F(x) x
+                 #define ARG 4
+                 #define IN_IF 1
+                 #ifdef FLAG
+                 #endif
+                 #if IN_IF
+                 #endif
+                 USED OUTER F(ARG)";
+    let options = PreprocessorOptions {
+        warn_unused_macros: true,
+        ..Default::default()
+    };
+    let mut pp = Preprocessor::with_options(input, options.clone());
+    pp.add_define("PREDEFINED", "1").unwrap();
+    pp.set_prologue("#define PROLOGUE 1\n");
+    let mut diagnostics = Diagnostics::default();
+    assert_eq!(pp.process_into(&mut diagnostics).len(), 3);
+
+    // Test that only the macro never expanded or tested is reported, at its name, and that
+    // predefined macros aren't reported.
+    assert_eq!(diagnostics.errors, vec![]);
+    assert_eq!(
+        diagnostics.warnings,
+        vec![(
+            PreprocessorWarning::UnusedMacro("UNUSED".into()),
+            Location { line: 2, pos: 25 }
+        )]
+    );
+
+    // Test that unused macros can be queried before the end, and aren't tracked by default.
+    let mut pp = Preprocessor::with_options("#define A 1\n#define B 2\nA\nB", options.clone());
+    pp.next();
+    assert_eq!(
+        pp.unused_macros(),
+        vec![("B".to_string(), Location { line: 2, pos: 8 })]
+    );
+
+    // Test that the warnings can be taken when iterating over the tokens.
+    let mut pp = Preprocessor::with_options("#define A 1\n#define B 2\nA", options.clone());
+    assert_eq!(pp.by_ref().count(), 1);
+    assert_eq!(
+        pp.take_warnings(),
+        vec![(
+            PreprocessorWarning::UnusedMacro("B".into()),
+            Location { line: 2, pos: 8 }
+        )]
+    );
+    assert_eq!(pp.take_warnings(), vec![]);
+    let mut diagnostics = Diagnostics::default();
+    Preprocessor::new("#define A 1").process_into(&mut diagnostics);
+    assert_eq!(diagnostics.warnings, vec![]);
+
+    // Test that a macro that is undefined and defined again is only reported once, at its last
+    // definition.
+    let mut pp = Preprocessor::with_options("#define A\n#undef A\n#define A\n", options);
+    assert_eq!(pp.by_ref().count(), 0);
+    assert_eq!(
+        pp.take_warnings(),
+        vec![(
+            PreprocessorWarning::UnusedMacro("A".into()),
+            Location { line: 3, pos: 8 }
+        )]
+    );
+}
+
+#[test]
+fn reconstruct_version() {
+    let mut options = PreprocessorOptions::default();
+    options.lexer.keep_raw_literals = true;
+    let reconstruct = |input| match Preprocessor::with_options(input, options.clone()).next() {
+        Some(Ok(Token {
+            value: TokenValue::Version(version),
+            ..
+        })) => version.reconstruct(),
+        _ => unreachable!(),
+    };
+
+    // Test that the version line is rebuilt with its original spacing.
+    assert_eq!(reconstruct("#version 450 core\n"), "#version 450 core");
+    assert_eq!(reconstruct("#version 450"), "#version 450");
+    assert_eq!(
+        reconstruct("  #  version   300  es  \n"),
+        "#  version   300  es"
+    );
+    assert_eq!(reconstruct("#version"), "#version");
+
+    // Test that comments are replaced by spaces and literals keep their spelling.
+    assert_eq!(
+        reconstruct("#version/**/0x1C2 /* c */ core // c\n"),
+        "#version    0x1C2         core"
+    );
+
+    // Test that a line continuation becomes a single space.
+    assert_eq!(reconstruct("#version 450 \\\n   core"), "#version 450 core");
+}
+
+#[test]
+fn token_invocation() {
+    let options = PreprocessorOptions {
+        record_invocations: true,
+        ..Default::default()
+    };
+    let tokens: Vec<Token> =
+        Preprocessor::with_options("#define BAR 2 +\n#define FOO(x) BAR x\nFOO(1) 3", options)
+            .map(Result::unwrap)
+            .collect();
+    assert_eq!(tokens.len(), 4);
+
+    // Test that the tokens of the expansion, including the ones of nested macros and arguments,
+    // point back at the FOO identifier in the source.
+    for token in &tokens[..3] {
+        assert_eq!(
+            token.invocation,
+            Some(("FOO".to_string(), Location { line: 3, pos: 0 }))
+        );
+    }
+
+    // Test that tokens outside of expansions don't have an invocation.
+    assert_eq!(tokens[3].invocation, None);
+
+    // Test that tokens can still be sent to other threads.
+    fn assert_send<T: Send + Sync>() {}
+    assert_send::<Token>();
+
+    // Test that invocations aren't recorded by default.
+    assert!(
+        Preprocessor::new("#define FOO 1\nFOO").all(|token| token.unwrap().invocation.is_none())
+    );
+}
+
+#[test]
+fn trailing_argument_comma() {
+    let input = "#define FOO(a, b) a + b\nFOO(1, 2,)";
+
+    // Test that a trailing comma is one argument too many by default.
+    let items: Vec<PreprocessorItem> = Preprocessor::new(input).collect();
+    assert_eq!(
+        items,
+        vec![Err((
+            PreprocessorError::TooManyDefineArguments,
+            Location { line: 2, pos: 3 }
+        ))]
+    );
+
+    let options = PreprocessorOptions {
+        allow_trailing_argument_comma: true,
+        ..Default::default()
+    };
+    let values = |input: &str| -> Vec<PreprocessorItem> {
+        Preprocessor::with_options(input, options.clone())
+            .map(|item| {
+                item.map(|token| Token {
+                    location: Location::default(),
+                    ..token
+                })
+            })
+            .collect()
+    };
+    let token = |value| {
+        Ok(Token {
+            value,
+            location: Location::default(),
+            raw: None,
+            invocation: None,
+        })
+    };
+    let one = || {
+        token(TokenValue::Integer(Integer {
+            value: 1,
+            signed: true,
+            width: 32,
+        }))
+    };
+
+    // Test that the trailing comma is ignored in lenient mode.
+    assert_eq!(
+        values(input),
+        vec![
+            one(),
+            token(TokenValue::Punct(Punct::Plus)),
+            token(TokenValue::Integer(Integer {
+                value: 2,
+                signed: true,
+                width: 32
+            })),
+        ]
+    );
+    assert_eq!(values("#define ONE(a) a\nONE(1,)"), vec![one()]);
+    assert_eq!(
+        values("#define ONE(a) a\n#if ONE(1,)\n1\n#endif"),
+        vec![one()]
+    );
+
+    // Test that a trailing comma still makes an empty last argument when it is needed, and that
+    // more than one extra argument is an error.
+    assert_eq!(values("#define FOO(a, b) a b\nFOO(1,)"), vec![one()]);
+    assert_eq!(
+        values("#define ONE(a) a\nONE(1,,)"),
+        vec![Err((
+            PreprocessorError::TooManyDefineArguments,
+            Location { line: 2, pos: 3 }
+        ))]
+    );
+}
+
+#[test]
+fn snapshot_restore() {
+    let first = "#version 450\n\
+                 #define A 1\n\
+                 #define F(x) x + A\n\
+                 #if A\n\
+                 a F(2) __LINE__\n\
+                 #line 20\n\
+                 b __LINE__\n\
+                 #if A == 2\n\
+                 c\n";
+    let second = "#else\n\
+                  d __LINE__ F(3)\n\
+                  #endif\n\
+                  #undef A\n\
+                  #ifndef A\n\
+                  e __LINE__ __FILE__\n\
+                  #endif\n\
+                  #endif\n";
+    let values = |preprocessor: &mut Preprocessor| -> Vec<TokenValue> {
+        preprocessor.map(|item| item.unwrap().value).collect()
+    };
+
+    // Test that the first chunk can end inside of a conditional block.
+    let options = PreprocessorOptions {
+        partial_input: true,
+        ..Default::default()
+    };
+    let mut preprocessor = Preprocessor::with_options(first, options);
+    let mut chunked = values(&mut preprocessor);
+    assert_eq!(preprocessor.conditional_depth(), 2);
+    assert_eq!(preprocessor.excluded_regions(), &[]);
+
+    // Test that continuing in another preprocessor gives the same tokens as a single pass, with
+    // the macros, the conditional blocks and the line numbers carried over.
+    let state = preprocessor.snapshot();
+    let mut preprocessor = Preprocessor::new(second);
+    preprocessor.restore(state.clone());
+    chunked.extend(values(&mut preprocessor));
+
+    let whole = format!("{}{}", first, second);
+    let mut whole_preprocessor = Preprocessor::new(&whole);
+    assert_eq!(chunked, values(&mut whole_preprocessor));
+
+    // Test that the excluded region left open in the first chunk is reported by the second one
+    // like in a single pass, with its end in the second chunk.
+    let first_lines = first.lines().count() as u32;
+    let shifted: Vec<_> = preprocessor
+        .excluded_regions()
+        .iter()
+        .map(|&(start, end)| {
+            let end = Location {
+                line: end.line + first_lines,
+                ..end
+            };
+            (start, end)
+        })
+        .collect();
+    assert_eq!(shifted, whole_preprocessor.excluded_regions());
+    assert_eq!(
+        shifted[0],
+        (Location { line: 8, pos: 1 }, Location { line: 10, pos: 1 })
+    );
+    // The __LINE__ of d and e in the second chunk continue from the #line of the first one.
+    for line in [25, 29] {
+        assert!(chunked.contains(&TokenValue::Integer(Integer {
+            value: line,
+            signed: false,
+            width: 32
+        })));
+    }
+
+    // Test that the blocks left open are still reported at the end of the last chunk.
+    let mut preprocessor = Preprocessor::new("#endif\n");
+    preprocessor.restore(state);
+    let items: Vec<PreprocessorItem> = preprocessor.collect();
+    assert_eq!(
+        items,
+        vec![Err((
+            PreprocessorError::UnfinishedBlock,
+            Location { line: 4, pos: 1 }
+        ))]
+    );
+}
+
+#[test]
+fn evaluate_invalid_unary() {
+    // Test that evaluating a unary operator that has no meaning is an error at its location.
+    let location = Location { line: 1, pos: 2 };
+    let expr = Expr::Unary {
+        op: Punct::Star,
+        location,
+        operand: Box::new(Expr::Undefined("a".to_string())),
+    };
+    assert_eq!(
+        expr.evaluate(),
+        Err((
+            PreprocessorError::UnexpectedToken(TokenValue::Punct(Punct::Star)),
+            location
+        ))
+    );
+}
+
+#[test]
+fn spanned_errors() {
+    let input = "#define 1 2
+         a
+         #if 1 +
+         #endif
+         b @";
+    let span = |start: (u32, u32), end: (u32, u32)| Span {
+        start: Location {
+            line: start.0,
+            pos: start.1,
+        },
+        end: Location {
+            line: end.0,
+            pos: end.1,
+        },
+    };
+
+    let mut pp = Preprocessor::new(input);
+    let mut items = Vec::new();
+    while let Some(item) = pp.next_spanned() {
+        items.push(item);
+    }
+    let errors: Vec<_> = items.iter().filter_map(|item| item.clone().err()).collect();
+
+    // Test that directive errors span the whole directive, even when the error is before its end.
+    assert_eq!(errors[0].1, span((1, 0), (1, 11)));
+    assert_eq!(
+        errors[1],
+        (
+            PreprocessorError::UnexpectedEndOfInput,
+            span((3, 9), (3, 16))
+        )
+    );
+    assert_eq!(
+        errors[2],
+        (
+            PreprocessorError::EndifOutsideOfBlock,
+            span((4, 9), (4, 15))
+        )
+    );
+
+    // Test that other errors have an empty span at their location.
+    assert_eq!(
+        errors[3],
+        (
+            PreprocessorError::UnexpectedCharacter('@'),
+            span((5, 11), (5, 11))
+        )
+    );
+    assert_eq!(errors.len(), 4);
+
+    // Test that reading the rest of the directive ahead doesn't change the output.
+    let located: Vec<PreprocessorItem> = items
+        .into_iter()
+        .map(|item| item.map_err(|(error, span)| (error, span.start)))
+        .collect();
+    let expected: Vec<PreprocessorItem> = Preprocessor::new(input).collect();
+    assert_eq!(located.len(), expected.len());
+    for (a, b) in located.iter().zip(&expected) {
+        match (a, b) {
+            (Ok(a), Ok(b)) => assert_eq!(a.value, b.value),
+            (Err((a, _)), Err((b, _))) => assert_eq!(a, b),
+            _ => unreachable!(),
+        }
+    }
 }
@@ -1,9 +1,45 @@
-#[derive(Clone, Copy, PartialEq, Debug)]
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+#[cfg(feature = "json")]
+use core::fmt::Write;
+use core::{cmp::Ordering, convert::TryFrom, fmt, ops::Range};
+
+/// The default location is line 0, which is before the first line of the input, and is meant for
+/// tokens that don't come from the source.
+///
+/// Locations are ordered by line then position.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Default)]
 pub struct Location {
     pub line: u32,
     pub pos: u32,
 }
 
+impl Location {
+    /// Returns whether this is the default location used for generated tokens. Note that a
+    /// `#line 0` directive can make tokens of the source have such a location.
+    pub fn is_synthetic(&self) -> bool {
+        *self == Location::default()
+    }
+
+    /// Converts `pos`, which counts Unicode scalar values, to a number of UTF-16 code units like
+    /// LSP clients expect by default. `line` is the text of the line the location is in, as found
+    /// in the source.
+    pub fn utf16_pos(&self, line: &str) -> u32 {
+        line.chars()
+            .take(self.pos as usize)
+            .map(char::len_utf16)
+            .sum::<usize>() as u32
+    }
+}
+
+/// A range of the source, from the location of its first character to the location right after
+/// its last character.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Span {
+    pub start: Location,
+    pub end: Location,
+}
+
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub enum Punct {
     // Compound assignments
@@ -60,12 +96,145 @@ pub enum Punct {
     Question,
 }
 
+/// Whether a [`Punct`] opens or closes a delimited group.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DelimiterKind {
+    Open,
+    Close,
+}
+
+impl Punct {
+    /// Returns whether this is an opening or closing parenthesis, brace or bracket, `None` for
+    /// other punctuation.
+    pub fn open_close_kind(self) -> Option<DelimiterKind> {
+        match self {
+            Punct::LeftParen | Punct::LeftBrace | Punct::LeftBracket => Some(DelimiterKind::Open),
+            Punct::RightParen | Punct::RightBrace | Punct::RightBracket => {
+                Some(DelimiterKind::Close)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn is_open(self) -> bool {
+        self.open_close_kind() == Some(DelimiterKind::Open)
+    }
+
+    pub fn is_close(self) -> bool {
+        self.open_close_kind() == Some(DelimiterKind::Close)
+    }
+
+    /// Returns the precedence of this punctuation as a binary operator of GLSL, from 1 for `||` to
+    /// 11 for `*`, `/` and `%`, higher binds tighter. `None` for other punctuation, including the
+    /// assignments and `,` as they aren't allowed in constant expressions.
+    pub fn precedence(self) -> Option<u8> {
+        Some(match self {
+            Punct::LogicalOr => 1,
+            Punct::LogicalXor => 2,
+            Punct::LogicalAnd => 3,
+            Punct::Pipe => 4,
+            Punct::Caret => 5,
+            Punct::Ampersand => 6,
+            Punct::EqualEqual | Punct::NotEqual => 7,
+            Punct::LeftAngle | Punct::RightAngle | Punct::LessEqual | Punct::GreaterEqual => 8,
+            Punct::LeftShift | Punct::RightShift => 9,
+            Punct::Plus | Punct::Minus => 10,
+            Punct::Star | Punct::Slash | Punct::Percent => 11,
+            _ => return None,
+        })
+    }
+
+    /// Returns the delimiter pairing with this one, for example `RightParen` for `LeftParen`.
+    pub fn matching_delimiter(self) -> Option<Punct> {
+        match self {
+            Punct::LeftParen => Some(Punct::RightParen),
+            Punct::RightParen => Some(Punct::LeftParen),
+            Punct::LeftBrace => Some(Punct::RightBrace),
+            Punct::RightBrace => Some(Punct::LeftBrace),
+            Punct::LeftBracket => Some(Punct::RightBracket),
+            Punct::RightBracket => Some(Punct::LeftBracket),
+            _ => None,
+        }
+    }
+}
+
+/// Writes the punctuation as it appears in the source.
+impl fmt::Display for Punct {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self {
+            Punct::AddAssign => "+=",
+            Punct::SubAssign => "-=",
+            Punct::MulAssign => "*=",
+            Punct::DivAssign => "/=",
+            Punct::ModAssign => "%=",
+            Punct::LeftShiftAssign => "<<=",
+            Punct::RightShiftAssign => ">>=",
+            Punct::AndAssign => "&=",
+            Punct::XorAssign => "^=",
+            Punct::OrAssign => "|=",
+            Punct::Increment => "++",
+            Punct::Decrement => "--",
+            Punct::LogicalAnd => "&&",
+            Punct::LogicalOr => "||",
+            Punct::LogicalXor => "^^",
+            Punct::LessEqual => "<=",
+            Punct::GreaterEqual => ">=",
+            Punct::EqualEqual => "==",
+            Punct::NotEqual => "!=",
+            Punct::LeftShift => "<<",
+            Punct::RightShift => ">>",
+            Punct::LeftBrace => "{",
+            Punct::RightBrace => "}",
+            Punct::LeftParen => "(",
+            Punct::RightParen => ")",
+            Punct::LeftBracket => "[",
+            Punct::RightBracket => "]",
+            Punct::LeftAngle => "<",
+            Punct::RightAngle => ">",
+            Punct::Semicolon => ";",
+            Punct::Comma => ",",
+            Punct::Colon => ":",
+            Punct::Dot => ".",
+            Punct::Equal => "=",
+            Punct::Bang => "!",
+            Punct::Minus => "-",
+            Punct::Tilde => "~",
+            Punct::Plus => "+",
+            Punct::Star => "*",
+            Punct::Slash => "/",
+            Punct::Percent => "%",
+            Punct::Pipe => "|",
+            Punct::Caret => "^",
+            Punct::Ampersand => "&",
+            Punct::Question => "?",
+        };
+        write!(f, "{}", text)
+    }
+}
+
+/// What a directive expected to find, see [`PreprocessorError::Expected`].
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ExpectedKind {
+    Identifier,
+    EndOfLine,
+}
+
+impl fmt::Display for ExpectedKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExpectedKind::Identifier => write!(f, "an identifier"),
+            ExpectedKind::EndOfLine => write!(f, "the end of the line"),
+        }
+    }
+}
+
 #[derive(Clone, PartialEq, Debug)]
 // TODO location?
 pub enum PreprocessorError {
     IntegerOverflow,
     FloatParsingError,
-    UnexpectedCharacter,
+    UnexpectedCharacter(char),
+    UnexpectedNulCharacter,
     UnexpectedToken(TokenValue),
     UnexpectedHash,
     UnexpectedNewLine,
@@ -85,8 +254,214 @@ pub enum PreprocessorError {
     LineOverflow,
     NotSupported16BitLiteral,
     NotSupported64BitLiteral,
+    NotSupportedLineComment,
     MacroNotDefined,
     RecursionLimitReached,
+    ExpansionTooLarge,
+    TokenLimitExceeded,
+    ReservedMacroRedefinition(String),
+    DivideByZero,
+    ShiftOutOfRange,
+    Expected {
+        expected: ExpectedKind,
+        found: TokenValue,
+    },
+    ExtraTokensAfterDirective,
+    DuplicateIntegerSuffix,
+    FloatOverflow,
+    InvalidNumberSuffix,
+    InvalidIntegerWidth(i32),
+}
+
+impl fmt::Display for PreprocessorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PreprocessorError::IntegerOverflow => write!(f, "integer overflow"),
+            PreprocessorError::FloatParsingError => write!(f, "invalid float literal"),
+            PreprocessorError::UnexpectedCharacter('\\') => write!(
+                f,
+                "unexpected character '\\\\', a line continuation must be followed by a newline"
+            ),
+            PreprocessorError::UnexpectedCharacter(c) => {
+                write!(f, "unexpected character {:?}", c)
+            }
+            PreprocessorError::UnexpectedNulCharacter => {
+                write!(f, "NUL characters are only allowed in comments")
+            }
+            PreprocessorError::UnexpectedToken(token) => write!(f, "unexpected token {:?}", token),
+            PreprocessorError::UnexpectedHash => write!(f, "unexpected '#'"),
+            PreprocessorError::UnexpectedNewLine => write!(f, "unexpected end of line"),
+            PreprocessorError::UnexpectedEndOfInput => write!(f, "unexpected end of input"),
+            PreprocessorError::TooFewDefineArguments => {
+                write!(f, "too few arguments in macro invocation")
+            }
+            PreprocessorError::TooManyDefineArguments => {
+                write!(f, "too many arguments in macro invocation")
+            }
+            PreprocessorError::ErrorDirective => write!(f, "'#error' directive"),
+            PreprocessorError::DuplicateParameter => write!(f, "duplicate macro parameter name"),
+            PreprocessorError::UnknownDirective => write!(f, "unknown directive"),
+            PreprocessorError::DefineRedefined => write!(f, "macro redefined differently"),
+            PreprocessorError::ElifOutsideOfBlock => write!(f, "'#elif' without '#if'"),
+            PreprocessorError::ElseOutsideOfBlock => write!(f, "'#else' without '#if'"),
+            PreprocessorError::EndifOutsideOfBlock => write!(f, "'#endif' without '#if'"),
+            PreprocessorError::ElifAfterElse => write!(f, "'#elif' after '#else'"),
+            PreprocessorError::MoreThanOneElse => write!(f, "more than one '#else' in block"),
+            PreprocessorError::UnfinishedBlock => write!(f, "unterminated conditional directive"),
+            PreprocessorError::LineOverflow => write!(f, "line number overflow"),
+            PreprocessorError::NotSupported16BitLiteral => {
+                write!(f, "16-bit literals are not supported")
+            }
+            PreprocessorError::NotSupported64BitLiteral => {
+                write!(f, "64-bit literals are not supported")
+            }
+            PreprocessorError::NotSupportedLineComment => {
+                write!(f, "'//' comments are not supported")
+            }
+            PreprocessorError::MacroNotDefined => write!(f, "macro not defined"),
+            PreprocessorError::RecursionLimitReached => write!(f, "recursion limit reached"),
+            PreprocessorError::ExpansionTooLarge => write!(f, "macro expansion is too large"),
+            PreprocessorError::TokenLimitExceeded => write!(f, "too many tokens in the output"),
+            PreprocessorError::ReservedMacroRedefinition(name) => {
+                write!(f, "the reserved macro name '{}' can't be redefined", name)
+            }
+            PreprocessorError::Expected { expected, found } => {
+                write!(f, "expected {}, found {:?}", expected, found)
+            }
+            PreprocessorError::DivideByZero => write!(f, "division by zero"),
+            PreprocessorError::ShiftOutOfRange => {
+                write!(f, "shift amount is negative or not less than 64")
+            }
+            PreprocessorError::ExtraTokensAfterDirective => {
+                write!(f, "extra tokens at the end of the directive")
+            }
+            PreprocessorError::FloatOverflow => write!(f, "float literal is too large"),
+            PreprocessorError::DuplicateIntegerSuffix => {
+                write!(f, "integer literal suffix used more than once")
+            }
+            PreprocessorError::InvalidNumberSuffix => {
+                write!(f, "invalid suffix on numeric literal")
+            }
+            PreprocessorError::InvalidIntegerWidth(width) => {
+                write!(f, "integer width {} is not one of 16, 32 or 64", width)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for PreprocessorError {}
+
+impl PreprocessorError {
+    /// A stable identifier of the kind of error, the name of its variant in snake case like
+    /// `too_many_define_arguments`, for tools that match on errors.
+    pub fn code(&self) -> &'static str {
+        match self {
+            PreprocessorError::IntegerOverflow => "integer_overflow",
+            PreprocessorError::FloatParsingError => "float_parsing_error",
+            PreprocessorError::UnexpectedCharacter(_) => "unexpected_character",
+            PreprocessorError::UnexpectedNulCharacter => "unexpected_nul_character",
+            PreprocessorError::UnexpectedToken(_) => "unexpected_token",
+            PreprocessorError::UnexpectedHash => "unexpected_hash",
+            PreprocessorError::UnexpectedNewLine => "unexpected_new_line",
+            PreprocessorError::UnexpectedEndOfInput => "unexpected_end_of_input",
+            PreprocessorError::TooFewDefineArguments => "too_few_define_arguments",
+            PreprocessorError::TooManyDefineArguments => "too_many_define_arguments",
+            PreprocessorError::ErrorDirective => "error_directive",
+            PreprocessorError::DuplicateParameter => "duplicate_parameter",
+            PreprocessorError::UnknownDirective => "unknown_directive",
+            PreprocessorError::DefineRedefined => "define_redefined",
+            PreprocessorError::ElifOutsideOfBlock => "elif_outside_of_block",
+            PreprocessorError::ElseOutsideOfBlock => "else_outside_of_block",
+            PreprocessorError::EndifOutsideOfBlock => "endif_outside_of_block",
+            PreprocessorError::ElifAfterElse => "elif_after_else",
+            PreprocessorError::MoreThanOneElse => "more_than_one_else",
+            PreprocessorError::UnfinishedBlock => "unfinished_block",
+            PreprocessorError::LineOverflow => "line_overflow",
+            PreprocessorError::NotSupported16BitLiteral => "not_supported_16_bit_literal",
+            PreprocessorError::NotSupported64BitLiteral => "not_supported_64_bit_literal",
+            PreprocessorError::NotSupportedLineComment => "not_supported_line_comment",
+            PreprocessorError::MacroNotDefined => "macro_not_defined",
+            PreprocessorError::RecursionLimitReached => "recursion_limit_reached",
+            PreprocessorError::ExpansionTooLarge => "expansion_too_large",
+            PreprocessorError::TokenLimitExceeded => "token_limit_exceeded",
+            PreprocessorError::ReservedMacroRedefinition(_) => "reserved_macro_redefinition",
+            PreprocessorError::DivideByZero => "divide_by_zero",
+            PreprocessorError::ShiftOutOfRange => "shift_out_of_range",
+            PreprocessorError::Expected { .. } => "expected",
+            PreprocessorError::ExtraTokensAfterDirective => "extra_tokens_after_directive",
+            PreprocessorError::DuplicateIntegerSuffix => "duplicate_integer_suffix",
+            PreprocessorError::FloatOverflow => "float_overflow",
+            PreprocessorError::InvalidNumberSuffix => "invalid_number_suffix",
+            PreprocessorError::InvalidIntegerWidth(_) => "invalid_integer_width",
+        }
+    }
+}
+
+/// Writes the errors as a JSON array of objects with the `line` and 1-based `column` of their
+/// location, their `message` and their [`PreprocessorError::code`], for example
+/// `[{"line":2,"column":4,"message":"macro not defined","code":"macro_not_defined"}]`.
+///
+/// Only available with the `json` feature.
+#[cfg(feature = "json")]
+pub fn diagnostics_to_json(errors: &[(PreprocessorError, Location)]) -> String {
+    let mut output = String::from("[");
+    for (i, (error, location)) in errors.iter().enumerate() {
+        if i != 0 {
+            output.push(',');
+        }
+        // Writing to a String can't fail.
+        let _ = write!(
+            output,
+            "{{\"line\":{},\"column\":{},\"message\":",
+            location.line,
+            location.pos as u64 + 1
+        );
+        write_json_string(&mut output, &error.to_string());
+        output += ",\"code\":";
+        write_json_string(&mut output, error.code());
+        output.push('}');
+    }
+    output.push(']');
+    output
+}
+
+#[cfg(feature = "json")]
+fn write_json_string(output: &mut String, string: &str) {
+    output.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\n"),
+            '\t' => output.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(output, "\\u{:04x}", c as u32);
+            }
+            c => output.push(c),
+        }
+    }
+    output.push('"');
+}
+
+/// Non-fatal diagnostics of the preprocessor, see `pp::DiagnosticSink`.
+#[derive(Clone, PartialEq, Debug)]
+pub enum PreprocessorWarning {
+    /// A conditional block without content, see
+    /// `pp::PreprocessorOptions::warn_empty_conditionals`.
+    EmptyConditional,
+    /// A macro defined in the input that was never expanded or tested, see
+    /// `pp::PreprocessorOptions::warn_unused_macros`.
+    UnusedMacro(String),
+}
+
+impl fmt::Display for PreprocessorWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PreprocessorWarning::EmptyConditional => write!(f, "empty conditional block"),
+            PreprocessorWarning::UnusedMacro(name) => write!(f, "macro {} is never used", name),
+        }
+    }
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -102,11 +477,173 @@ pub struct Float {
     pub width: i32,
 }
 
+/// Writes the integer in decimal with its suffixes, for example `3u` or `4l`.
+impl fmt::Display for Integer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.value)?;
+        if !self.signed {
+            write!(f, "u")?;
+        }
+        match self.width {
+            16 => write!(f, "s"),
+            64 => write!(f, "l"),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Writes the float in a canonical form: the shortest representation that parses back to the same
+/// value followed by its suffix, so `1.000` and `1.0` are both written `1.0f`. Infinities are
+/// written as a literal that overflows to them like `1e39f`, and NaN, which has no literal, as the
+/// expression `(0.0f / 0.0f)`.
+impl fmt::Display for Float {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let suffix = match self.width {
+            16 => "hf",
+            64 => "lf",
+            _ => "f",
+        };
+        if self.value.is_nan() {
+            return write!(f, "(0.0{} / 0.0{})", suffix, suffix);
+        }
+        if self.value.is_infinite() {
+            // The smallest power of ten that is too large for a 32-bit float.
+            let sign = if self.value < 0.0 { "-" } else { "" };
+            write!(f, "{}1e39", sign)?;
+        } else {
+            // The Debug implementation of floats is the shortest round-trippable form that always
+            // includes a `.` or an exponent.
+            write!(f, "{:?}", self.value)?;
+        }
+        write!(f, "{}", suffix)
+    }
+}
+
 #[derive(Clone, PartialEq, Debug)]
 pub struct Version {
     pub tokens: Vec<Token>,
     pub is_first_directive: bool,
     pub has_comments_before: bool,
+    /// The location of the `#` of the directive.
+    pub hash_location: Location,
+    /// The location of the `version` name of the directive.
+    pub name_location: Location,
+}
+
+/// The profile of a `#version` directive, see [`Version::profile`].
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Profile {
+    Core,
+    Compatibility,
+    Es,
+    /// The `vulkan` profile accepted by Vulkan flavored GLSL front-ends.
+    Vulkan,
+}
+
+impl Version {
+    /// Parses the profile that follows the version number, `None` if there is none. Unknown
+    /// profiles produce an `UnexpectedToken` error.
+    ///
+    /// The tokens of the directive are otherwise left for the user to validate.
+    pub fn profile(&self) -> Result<Option<Profile>, (PreprocessorError, Location)> {
+        let token = match self.tokens.get(1) {
+            Some(token) => token,
+            None => return Ok(None),
+        };
+
+        let profile = match &token.value {
+            TokenValue::Ident(name) => match name.as_str() {
+                "core" => Some(Profile::Core),
+                "compatibility" => Some(Profile::Compatibility),
+                "es" => Some(Profile::Es),
+                "vulkan" => Some(Profile::Vulkan),
+                _ => None,
+            },
+            _ => None,
+        };
+
+        profile.map(Some).ok_or_else(|| {
+            (
+                PreprocessorError::UnexpectedToken(token.value.clone()),
+                token.location,
+            )
+        })
+    }
+
+    /// Rebuilds the text of the directive from its `#` to its last token, like `#version 450 core`,
+    /// with the spacing recovered from the locations of its tokens. Whitespace and comments
+    /// between tokens are written as spaces, and tokens on a later line than the previous one, for
+    /// example after a line continuation, are separated by a single space. Literals are written
+    /// as in the source when they kept their raw text.
+    pub fn reconstruct(&self) -> String {
+        let mut output = String::from("#");
+        let mut end = Location {
+            line: self.hash_location.line,
+            pos: self.hash_location.pos + 1,
+        };
+
+        let names = core::iter::once((self.name_location, "version".to_string()));
+        let tokens = self.tokens.iter().map(|token| {
+            let text = match &token.raw {
+                Some(raw) => raw.clone(),
+                None => token.value.to_string(),
+            };
+            (token.location, text)
+        });
+
+        for (location, text) in names.chain(tokens) {
+            if location.line == end.line && location.pos >= end.pos {
+                output += &" ".repeat((location.pos - end.pos) as usize);
+            } else {
+                output.push(' ');
+            }
+            output += &text;
+            end = Location {
+                line: location.line,
+                pos: location.pos + text.chars().count() as u32,
+            };
+        }
+
+        output
+    }
+}
+
+/// A shader stage selected by `#pragma shader_stage(stage)`, see
+/// `pp::Preprocessor::shader_stage`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ShaderStage {
+    Vertex,
+    Fragment,
+    TessControl,
+    TessEval,
+    Geometry,
+    Compute,
+}
+
+impl ShaderStage {
+    /// Returns the stage with the name shaderc uses in `#pragma shader_stage`, for example
+    /// `tesscontrol`.
+    pub fn from_name(name: &str) -> Option<ShaderStage> {
+        match name {
+            "vertex" => Some(ShaderStage::Vertex),
+            "fragment" => Some(ShaderStage::Fragment),
+            "tesscontrol" => Some(ShaderStage::TessControl),
+            "tesseval" => Some(ShaderStage::TessEval),
+            "geometry" => Some(ShaderStage::Geometry),
+            "compute" => Some(ShaderStage::Compute),
+            _ => None,
+        }
+    }
+}
+
+/// The pragmas defined by the GLSL specification, see
+/// `pp::PreprocessEvent::StdPragma`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StdPragma {
+    /// `#pragma optimize(on)` or `#pragma optimize(off)`.
+    Optimize(bool),
+    /// `#pragma debug(on)` or `#pragma debug(off)`.
+    Debug(bool),
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -115,6 +652,8 @@ pub struct Extension {
     pub has_non_directive_before: bool,
 }
 
+/// The tokens of a `#pragma` directive. Pragmas are output in the token stream at the position of
+/// the directive, so their order relative to the surrounding code is kept.
 #[derive(Clone, PartialEq, Debug)]
 pub struct Pragma {
     pub tokens: Vec<Token>,
@@ -133,9 +672,569 @@ pub enum TokenValue {
     Pragma(Pragma),
 }
 
+/// The kind of a [`TokenValue`], without its payload.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum TokenKind {
+    Ident,
+    Integer,
+    Float,
+    Punct,
+    Version,
+    Extension,
+    Pragma,
+}
+
+impl TokenValue {
+    pub fn kind(&self) -> TokenKind {
+        match self {
+            TokenValue::Ident(_) => TokenKind::Ident,
+            TokenValue::Integer(_) => TokenKind::Integer,
+            TokenValue::Float(_) => TokenKind::Float,
+            TokenValue::Punct(_) => TokenKind::Punct,
+            TokenValue::Version(_) => TokenKind::Version,
+            TokenValue::Extension(_) => TokenKind::Extension,
+            TokenValue::Pragma(_) => TokenKind::Pragma,
+        }
+    }
+}
+
+/// Writes the token as it would appear in the source, directives are written with their tokens
+/// separated by single spaces.
+impl fmt::Display for TokenValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (directive, tokens) = match self {
+            TokenValue::Ident(name) => return write!(f, "{}", name),
+            TokenValue::Integer(integer) => return write!(f, "{}", integer),
+            TokenValue::Float(float) => return write!(f, "{}", float),
+            TokenValue::Punct(punct) => return write!(f, "{}", punct),
+            TokenValue::Version(version) => ("version", &version.tokens),
+            TokenValue::Extension(extension) => ("extension", &extension.tokens),
+            TokenValue::Pragma(pragma) => ("pragma", &pragma.tokens),
+        };
+
+        write!(f, "#{}", directive)?;
+        for token in tokens {
+            write!(f, " {}", token.value)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Clone, PartialEq, Debug)]
 pub struct Token {
     pub value: TokenValue,
     pub location: Location,
+    /// The text of integer and float literals as written in the source, kept through macro
+    /// expansion. Only present with [`crate::lexer::LexerOptions::keep_raw_literals`].
+    pub raw: Option<String>,
+    /// The name and location of the top-level macro invocation in the source this token was
+    /// produced by, and `None` for tokens that don't come from a macro expansion. Only present with
+    /// `pp::PreprocessorOptions::record_invocations`.
+    pub invocation: Option<(String, Location)>,
     // TODO macro invocation stack?
 }
+
+impl Token {
+    /// Compares tokens by their location, for example to sort tokens with
+    /// `tokens.sort_by(Token::by_location)`. Tokens aren't otherwise ordered as their values
+    /// aren't.
+    pub fn by_location(a: &Token, b: &Token) -> Ordering {
+        a.location.cmp(&b.location)
+    }
+}
+
+// The role of a token for the spacing of format_canonical.
+#[derive(Clone, Copy, PartialEq)]
+enum SpacingKind {
+    Operand,
+    Prefix,
+    Postfix,
+    Binary,
+    // Opening parenthesis or bracket.
+    Open,
+    // Closing parenthesis or bracket.
+    Close,
+    Dot,
+    // Comma or semicolon.
+    Separator,
+    Brace,
+}
+
+// The sign a `+`, `-`, `++` or `--` token is made of.
+fn sign_char(punct: Punct) -> Option<char> {
+    match punct {
+        Punct::Plus | Punct::Increment => Some('+'),
+        Punct::Minus | Punct::Decrement => Some('-'),
+        _ => None,
+    }
+}
+
+/// Writes the tokens with a canonical spacing, so that sources that only differ in whitespace
+/// produce the same text: binary operators are surrounded by single spaces, unary operators are
+/// attached to their operand, and a newline is written between tokens on different lines. Tokens
+/// are otherwise separated by single spaces.
+pub fn format_canonical(tokens: &[Token]) -> String {
+    let mut output = String::new();
+    // The kind of the previous token if it is on the same line.
+    let mut previous_kind = None;
+
+    for (i, token) in tokens.iter().enumerate() {
+        if i != 0 && tokens[i - 1].location.line != token.location.line {
+            output.push('\n');
+            previous_kind = None;
+        }
+
+        let after_operand = matches!(
+            previous_kind,
+            Some(SpacingKind::Operand) | Some(SpacingKind::Close) | Some(SpacingKind::Postfix)
+        );
+
+        let kind = match &token.value {
+            TokenValue::Punct(punct) => match punct {
+                Punct::LeftParen | Punct::LeftBracket => SpacingKind::Open,
+                Punct::RightParen | Punct::RightBracket => SpacingKind::Close,
+                Punct::LeftBrace | Punct::RightBrace => SpacingKind::Brace,
+                Punct::Dot => SpacingKind::Dot,
+                Punct::Comma | Punct::Semicolon => SpacingKind::Separator,
+                Punct::Bang | Punct::Tilde => SpacingKind::Prefix,
+                Punct::Increment | Punct::Decrement if after_operand => SpacingKind::Postfix,
+                Punct::Increment | Punct::Decrement => SpacingKind::Prefix,
+                Punct::Plus | Punct::Minus if !after_operand => SpacingKind::Prefix,
+                _ => SpacingKind::Binary,
+            },
+            _ => SpacingKind::Operand,
+        };
+
+        if let Some(previous_kind) = previous_kind {
+            // Signs that would lex as `++` or `--` if they were attached, like in `- -x`.
+            let forms_sign_pair = match (&tokens[i - 1].value, &token.value) {
+                (TokenValue::Punct(previous), TokenValue::Punct(punct)) => {
+                    sign_char(*previous).is_some() && sign_char(*previous) == sign_char(*punct)
+                }
+                _ => false,
+            };
+            let attached = match (previous_kind, kind) {
+                (SpacingKind::Prefix, _) if forms_sign_pair => false,
+                (SpacingKind::Prefix, _) | (SpacingKind::Open, _) | (SpacingKind::Dot, _) => true,
+                (_, SpacingKind::Close)
+                | (_, SpacingKind::Dot)
+                | (_, SpacingKind::Separator)
+                | (_, SpacingKind::Postfix) => true,
+                // Function calls and array accesses.
+                (_, SpacingKind::Open) => after_operand,
+                _ => false,
+            };
+            if !attached {
+                output.push(' ');
+            }
+        }
+
+        output += &token.value.to_string();
+        previous_kind = Some(kind);
+    }
+
+    output
+}
+
+/// The difference between two token streams, see [`diff_tokens`].
+#[derive(Clone, PartialEq, Debug)]
+pub struct TokenDiff {
+    /// The number of tokens at the start of both streams that are the same.
+    pub common_prefix: usize,
+    /// The number of tokens at the end of both streams that are the same, not overlapping the
+    /// common prefix.
+    pub common_suffix: usize,
+    /// The range of the old stream that was replaced, between the common prefix and suffix.
+    pub old_changed: Range<usize>,
+    /// The range of the new stream that replaced it.
+    pub new_changed: Range<usize>,
+}
+
+/// Compares two token streams by their values, ignoring locations, and returns the single range
+/// of tokens that changed between them. The streams are equal if both changed ranges are empty.
+pub fn diff_tokens(old: &[Token], new: &[Token]) -> TokenDiff {
+    let same = |a: &Token, b: &Token| a.value == b.value;
+
+    let common_prefix = old.iter().zip(new).take_while(|&(a, b)| same(a, b)).count();
+    let common_suffix = old[common_prefix..]
+        .iter()
+        .rev()
+        .zip(new[common_prefix..].iter().rev())
+        .take_while(|&(a, b)| same(a, b))
+        .count();
+
+    TokenDiff {
+        common_prefix,
+        common_suffix,
+        old_changed: common_prefix..old.len() - common_suffix,
+        new_changed: common_prefix..new.len() - common_suffix,
+    }
+}
+
+/// Returns the value of an integer literal preceded by any number of `+` and `-` signs, like
+/// `-1` or `- -3`, as the lexer and preprocessor never fold the signs into literals. Returns
+/// `None` if the tokens are anything else, including float literals, or if the value doesn't fit
+/// in an `i64`.
+pub fn fold_signed_literal(tokens: &[Token]) -> Option<i64> {
+    let (literal, signs) = tokens.split_last()?;
+    let mut negative = false;
+    for sign in signs {
+        match sign.value {
+            TokenValue::Punct(Punct::Plus) => {}
+            TokenValue::Punct(Punct::Minus) => negative = !negative,
+            _ => return None,
+        }
+    }
+
+    match &literal.value {
+        TokenValue::Integer(integer) => {
+            let value = integer.value as i128;
+            i64::try_from(if negative { -value } else { value }).ok()
+        }
+        _ => None,
+    }
+}
+
+// All the punctuation in declaration order, which is their encoding in encode_tokens. New
+// variants must be added at the end to keep the encoding stable.
+const PUNCTS: [Punct; 45] = [
+    Punct::AddAssign,
+    Punct::SubAssign,
+    Punct::MulAssign,
+    Punct::DivAssign,
+    Punct::ModAssign,
+    Punct::LeftShiftAssign,
+    Punct::RightShiftAssign,
+    Punct::AndAssign,
+    Punct::XorAssign,
+    Punct::OrAssign,
+    Punct::Increment,
+    Punct::Decrement,
+    Punct::LogicalAnd,
+    Punct::LogicalOr,
+    Punct::LogicalXor,
+    Punct::LessEqual,
+    Punct::GreaterEqual,
+    Punct::EqualEqual,
+    Punct::NotEqual,
+    Punct::LeftShift,
+    Punct::RightShift,
+    Punct::LeftBrace,
+    Punct::RightBrace,
+    Punct::LeftParen,
+    Punct::RightParen,
+    Punct::LeftBracket,
+    Punct::RightBracket,
+    Punct::LeftAngle,
+    Punct::RightAngle,
+    Punct::Semicolon,
+    Punct::Comma,
+    Punct::Colon,
+    Punct::Dot,
+    Punct::Equal,
+    Punct::Bang,
+    Punct::Minus,
+    Punct::Tilde,
+    Punct::Plus,
+    Punct::Star,
+    Punct::Slash,
+    Punct::Percent,
+    Punct::Pipe,
+    Punct::Caret,
+    Punct::Ampersand,
+    Punct::Question,
+];
+
+const TAG_IDENT: u8 = 0;
+const TAG_INTEGER: u8 = 1;
+const TAG_FLOAT: u8 = 2;
+const TAG_PUNCT: u8 = 3;
+const TAG_VERSION: u8 = 4;
+const TAG_EXTENSION: u8 = 5;
+const TAG_PRAGMA: u8 = 6;
+
+/// The errors of [`decode_tokens`].
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DecodeError {
+    /// The input ends in the middle of a token.
+    UnexpectedEnd,
+    /// A byte that isn't a valid token value tag, punctuation or boolean.
+    InvalidTag(u8),
+    /// An identifier that isn't valid UTF-8.
+    InvalidUtf8,
+    /// An integer too large for its field.
+    Overflow,
+    /// There are bytes after the last token.
+    TrailingBytes,
+    /// A `#version`, `#extension` or `#pragma` token inside the tokens of another one.
+    NestedDirective,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::UnexpectedEnd => write!(f, "unexpected end of the encoded tokens"),
+            DecodeError::InvalidTag(tag) => write!(f, "invalid tag {}", tag),
+            DecodeError::InvalidUtf8 => write!(f, "identifier is not valid UTF-8"),
+            DecodeError::Overflow => write!(f, "integer overflow"),
+            DecodeError::TrailingBytes => write!(f, "trailing bytes after the encoded tokens"),
+            DecodeError::NestedDirective => write!(f, "directive token inside a directive token"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DecodeError {}
+
+/// Encodes the tokens in a compact binary format that [`decode_tokens`] reads back.
+///
+/// Unsigned integers are LEB128 variable length integers and integer and float widths are
+/// encoded as their `u32` bit pattern. The encoding is a count of tokens followed by the tokens,
+/// each made of:
+///
+///  - the line and position of its location,
+///  - a byte tag for the kind of its value, in the order of [`TokenValue`]'s variants,
+///  - the value: the length and UTF-8 bytes of identifiers, the value, signedness byte and width
+///    of integers, the 4 little-endian bytes of the bits and width of floats, the index of
+///    punctuation in the order of [`Punct`]'s variants, and for directives the encoding of their
+///    tokens followed by their boolean fields as bytes, then for versions the line and position of
+///    their `#` and name,
+///  - a byte for whether it has raw text, followed by the length and UTF-8 bytes of the text.
+///
+/// The [`Token::invocation`] of tokens isn't encoded.
+pub fn encode_tokens(tokens: &[Token]) -> Vec<u8> {
+    let mut output = Vec::new();
+    encode_token_list(&mut output, tokens);
+    output
+}
+
+fn encode_varint(output: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        output.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    output.push(value as u8);
+}
+
+fn encode_string(output: &mut Vec<u8>, string: &str) {
+    encode_varint(output, string.len() as u64);
+    output.extend_from_slice(string.as_bytes());
+}
+
+fn encode_location(output: &mut Vec<u8>, location: Location) {
+    encode_varint(output, location.line as u64);
+    encode_varint(output, location.pos as u64);
+}
+
+fn encode_token_list(output: &mut Vec<u8>, tokens: &[Token]) {
+    encode_varint(output, tokens.len() as u64);
+    for token in tokens {
+        encode_location(output, token.location);
+
+        match &token.value {
+            TokenValue::Ident(name) => {
+                output.push(TAG_IDENT);
+                encode_string(output, name);
+            }
+            TokenValue::Integer(integer) => {
+                output.push(TAG_INTEGER);
+                encode_varint(output, integer.value);
+                output.push(integer.signed as u8);
+                encode_varint(output, integer.width as u32 as u64);
+            }
+            TokenValue::Float(float) => {
+                output.push(TAG_FLOAT);
+                output.extend_from_slice(&float.value.to_bits().to_le_bytes());
+                encode_varint(output, float.width as u32 as u64);
+            }
+            TokenValue::Punct(punct) => {
+                output.push(TAG_PUNCT);
+                output.push(*punct as u8);
+            }
+            TokenValue::Version(version) => {
+                output.push(TAG_VERSION);
+                encode_token_list(output, &version.tokens);
+                output.push(version.is_first_directive as u8);
+                output.push(version.has_comments_before as u8);
+                encode_location(output, version.hash_location);
+                encode_location(output, version.name_location);
+            }
+            TokenValue::Extension(extension) => {
+                output.push(TAG_EXTENSION);
+                encode_token_list(output, &extension.tokens);
+                output.push(extension.has_non_directive_before as u8);
+            }
+            TokenValue::Pragma(pragma) => {
+                output.push(TAG_PRAGMA);
+                encode_token_list(output, &pragma.tokens);
+            }
+        }
+
+        output.push(token.raw.is_some() as u8);
+        if let Some(raw) = &token.raw {
+            encode_string(output, raw);
+        }
+    }
+}
+
+/// Decodes tokens encoded by [`encode_tokens`].
+pub fn decode_tokens(input: &[u8]) -> Result<Vec<Token>, DecodeError> {
+    let mut decoder = Decoder {
+        input,
+        in_directive: false,
+    };
+    let tokens = decoder.token_list()?;
+    if decoder.input.is_empty() {
+        Ok(tokens)
+    } else {
+        Err(DecodeError::TrailingBytes)
+    }
+}
+
+struct Decoder<'a> {
+    input: &'a [u8],
+    // Whether the tokens of a directive are being decoded, which can't contain directives. This
+    // also bounds the recursion on malicious input.
+    in_directive: bool,
+}
+
+impl<'a> Decoder<'a> {
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], DecodeError> {
+        if self.input.len() < count {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        let (bytes, rest) = self.input.split_at(count);
+        self.input = rest;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, DecodeError> {
+        match self.byte()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            tag => Err(DecodeError::InvalidTag(tag)),
+        }
+    }
+
+    fn varint(&mut self) -> Result<u64, DecodeError> {
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.byte()?;
+            let bits = (byte & 0x7f) as u64;
+            if shift >= 64 || (bits << shift) >> shift != bits {
+                return Err(DecodeError::Overflow);
+            }
+            value |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+        }
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        let value = self.varint()?;
+        if value > u32::MAX as u64 {
+            return Err(DecodeError::Overflow);
+        }
+        Ok(value as u32)
+    }
+
+    fn string(&mut self) -> Result<String, DecodeError> {
+        let length = self.varint()?;
+        if length > self.input.len() as u64 {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        let bytes = self.bytes(length as usize)?;
+        let string = core::str::from_utf8(bytes).map_err(|_| DecodeError::InvalidUtf8)?;
+        Ok(string.to_string())
+    }
+
+    fn token_list(&mut self) -> Result<Vec<Token>, DecodeError> {
+        // The count isn't used to reserve the vector as it might be garbage.
+        let count = self.varint()?;
+        let mut tokens = Vec::new();
+        for _ in 0..count {
+            tokens.push(self.token()?);
+        }
+        Ok(tokens)
+    }
+
+    fn location(&mut self) -> Result<Location, DecodeError> {
+        Ok(Location {
+            line: self.u32()?,
+            pos: self.u32()?,
+        })
+    }
+
+    fn directive_token_list(&mut self) -> Result<Vec<Token>, DecodeError> {
+        if self.in_directive {
+            return Err(DecodeError::NestedDirective);
+        }
+        self.in_directive = true;
+        let tokens = self.token_list();
+        self.in_directive = false;
+        tokens
+    }
+
+    fn token(&mut self) -> Result<Token, DecodeError> {
+        let location = self.location()?;
+
+        let value = match self.byte()? {
+            TAG_IDENT => TokenValue::Ident(self.string()?),
+            TAG_INTEGER => TokenValue::Integer(Integer {
+                value: self.varint()?,
+                signed: self.bool()?,
+                width: self.u32()? as i32,
+            }),
+            TAG_FLOAT => {
+                let mut bits = [0; 4];
+                bits.copy_from_slice(self.bytes(4)?);
+                TokenValue::Float(Float {
+                    value: f32::from_bits(u32::from_le_bytes(bits)),
+                    width: self.u32()? as i32,
+                })
+            }
+            TAG_PUNCT => {
+                let index = self.byte()?;
+                match PUNCTS.get(index as usize) {
+                    Some(punct) => TokenValue::Punct(*punct),
+                    None => return Err(DecodeError::InvalidTag(index)),
+                }
+            }
+            TAG_VERSION => TokenValue::Version(Version {
+                tokens: self.directive_token_list()?,
+                is_first_directive: self.bool()?,
+                has_comments_before: self.bool()?,
+                hash_location: self.location()?,
+                name_location: self.location()?,
+            }),
+            TAG_EXTENSION => TokenValue::Extension(Extension {
+                tokens: self.directive_token_list()?,
+                has_non_directive_before: self.bool()?,
+            }),
+            TAG_PRAGMA => TokenValue::Pragma(Pragma {
+                tokens: self.directive_token_list()?,
+            }),
+            tag => return Err(DecodeError::InvalidTag(tag)),
+        };
+
+        let raw = if self.bool()? {
+            Some(self.string()?)
+        } else {
+            None
+        };
+
+        Ok(Token {
+            value,
+            location,
+            raw,
+            invocation: None,
+        })
+    }
+}
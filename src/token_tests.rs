@@ -0,0 +1,490 @@
+use super::lexer::Lexer;
+use super::lexer::{TokenKind as LexerTokenKind, TokenValue as LexerTokenValue};
+use super::pp::{Dialect, Preprocessor};
+use super::token::{
+    decode_tokens, diff_tokens, encode_tokens, fold_signed_literal, format_canonical, DecodeError,
+    DelimiterKind, Float, Integer, Location, PreprocessorError, Punct, Token, TokenKind,
+    TokenValue,
+};
+
+#[test]
+fn punct_delimiters() {
+    // Test each pair of delimiters matches.
+    let pairs = [
+        (Punct::LeftParen, Punct::RightParen),
+        (Punct::LeftBrace, Punct::RightBrace),
+        (Punct::LeftBracket, Punct::RightBracket),
+    ];
+    for &(open, close) in &pairs {
+        assert_eq!(open.open_close_kind(), Some(DelimiterKind::Open));
+        assert_eq!(close.open_close_kind(), Some(DelimiterKind::Close));
+        assert!(open.is_open() && !open.is_close());
+        assert!(close.is_close() && !close.is_open());
+        assert_eq!(open.matching_delimiter(), Some(close));
+        assert_eq!(close.matching_delimiter(), Some(open));
+    }
+
+    // Test that other punctuation, including angle brackets, isn't a delimiter.
+    for &punct in &[
+        Punct::LeftAngle,
+        Punct::RightAngle,
+        Punct::Comma,
+        Punct::Plus,
+    ] {
+        assert_eq!(punct.open_close_kind(), None);
+        assert!(!punct.is_open() && !punct.is_close());
+        assert_eq!(punct.matching_delimiter(), None);
+    }
+}
+
+#[test]
+fn preprocessor_error_display() {
+    // Test a couple of the error messages.
+    assert_eq!(
+        PreprocessorError::ElifAfterElse.to_string(),
+        "'#elif' after '#else'"
+    );
+    assert_eq!(
+        PreprocessorError::UnexpectedEndOfInput.to_string(),
+        "unexpected end of input"
+    );
+    assert_eq!(
+        PreprocessorError::UnexpectedToken(TokenValue::Punct(Punct::Comma)).to_string(),
+        "unexpected token Punct(Comma)"
+    );
+    assert_eq!(
+        PreprocessorError::UnexpectedCharacter('@').to_string(),
+        "unexpected character '@'"
+    );
+
+    // Test that it can be used as an error trait object.
+    let error: Box<dyn std::error::Error> = Box::new(PreprocessorError::UnfinishedBlock);
+    assert_eq!(error.to_string(), "unterminated conditional directive");
+}
+
+#[test]
+fn synthetic_location() {
+    assert!(Location::default().is_synthetic());
+
+    // Test that lexed tokens, even at the start of the input, aren't synthetic.
+    let token = Lexer::new("a").next().unwrap().unwrap();
+    assert!(!token.location.is_synthetic());
+}
+
+#[test]
+fn utf16_pos() {
+    let input = "a\n/* \u{1F600} */ b";
+    let tokens: Vec<_> = Lexer::new(input).map(|item| item.unwrap()).collect();
+    let location = tokens[2].location;
+
+    // Test that characters outside of the BMP count as two UTF-16 code units.
+    assert_eq!(location, Location { line: 2, pos: 8 });
+    let line = input.lines().nth(location.line as usize - 1).unwrap();
+    assert_eq!(location.utf16_pos(line), 9);
+
+    // Test that it is the same as pos for lines without such characters.
+    assert_eq!(tokens[0].location.utf16_pos("a"), 0);
+    assert_eq!(Location { line: 1, pos: 3 }.utf16_pos("a\u{E9}b c"), 3);
+}
+
+#[test]
+fn literal_display() {
+    let literals = |input: &str| -> Vec<String> {
+        Lexer::new(input)
+            .filter_map(|item| match item.unwrap().value {
+                LexerTokenValue::Integer(integer) => Some(integer.to_string()),
+                LexerTokenValue::Float(float) => Some(float.to_string()),
+                _ => None,
+            })
+            .collect()
+    };
+
+    // Test that floats written differently but with the same value have the same canonical form.
+    assert_eq!(
+        literals("1.000f 1.0 1. 0.1 .100"),
+        vec!["1.0f", "1.0f", "1.0f", "0.1f", "0.1f"]
+    );
+    assert_eq!(
+        Float {
+            value: 1e20,
+            width: 32
+        }
+        .to_string(),
+        "1e20f"
+    );
+
+    // Test the suffixes.
+    assert_eq!(
+        Float {
+            value: 0.5,
+            width: 64
+        }
+        .to_string(),
+        "0.5lf"
+    );
+    assert_eq!(
+        Float {
+            value: 2.0,
+            width: 16
+        }
+        .to_string(),
+        "2.0hf"
+    );
+
+    // Test that infinities are written as a literal that lexes back to them.
+    assert_eq!(literals("1e50 1e39"), vec!["1e39f", "1e39f"]);
+    assert_eq!(literals(&literals("1e50")[0]), vec!["1e39f"]);
+    assert_eq!(
+        Float {
+            value: f32::NEG_INFINITY,
+            width: 32
+        }
+        .to_string(),
+        "-1e39f"
+    );
+
+    // Test that NaN is written as an expression, including in format_canonical.
+    let nan = Float {
+        value: f32::NAN,
+        width: 64,
+    };
+    assert_eq!(nan.to_string(), "(0.0lf / 0.0lf)");
+    let token = Token {
+        value: TokenValue::Float(nan),
+        location: Location::default(),
+        raw: None,
+        invocation: None,
+    };
+    assert_eq!(format_canonical(&[token]), "(0.0lf / 0.0lf)");
+
+    assert_eq!(literals("0x10 3u"), vec!["16", "3u"]);
+    assert_eq!(
+        Integer {
+            value: 4,
+            signed: false,
+            width: 64
+        }
+        .to_string(),
+        "4ul"
+    );
+}
+
+#[test]
+fn canonical_format() {
+    let format = |input| {
+        let tokens: Vec<_> = Preprocessor::new(input).map(|item| item.unwrap()).collect();
+        format_canonical(&tokens)
+    };
+    let tidy = "#version 450 core
+vec4 f(float x, int y) {
+a = -b * (c + d)[i++] - --e;
+return !x ? s.xy : ~y;
+}";
+
+    // Test that the spacing is normalized, keeping the newlines.
+    assert_eq!(format(tidy), tidy);
+    assert_eq!(
+        format(
+            "#   version   450 core
+             vec4 f (float x,int y){
+             a=- b*( c+d ) [ i ++]-  -- e ;
+             return ! x?s . xy: ~ y;
+             }"
+        ),
+        tidy
+    );
+
+    // Test that blank lines and comments are removed.
+    assert_eq!(format("a+b\n\n/* c */\nc"), "a + b\nc");
+
+    // Test that prefix signs aren't attached when that would make an increment or decrement.
+    assert_eq!(format("- -x;"), "- -x;");
+    assert_eq!(format("a = - -b;"), "a = - -b;");
+    assert_eq!(format("+ +x - - --y + ++z"), "+ +x - - --y + ++z");
+    assert_eq!(format("- +x + -y - !-z"), "-+x + -y - !-z");
+
+    // Test the source text of tokens.
+    assert_eq!(TokenValue::Punct(Punct::LeftShiftAssign).to_string(), "<<=");
+    assert_eq!(TokenValue::Ident("foo".into()).to_string(), "foo");
+}
+
+#[test]
+fn token_kinds() {
+    // Test the kinds of the preprocessor tokens.
+    let integer = Integer {
+        value: 1,
+        signed: true,
+        width: 32,
+    };
+    assert_eq!(
+        TokenValue::Integer(integer.clone()).kind(),
+        TokenKind::Integer
+    );
+    let kinds: Vec<TokenKind> = Preprocessor::new("#version 450\na 1.0 +")
+        .map(|item| item.unwrap().value.kind())
+        .collect();
+    assert_eq!(
+        kinds,
+        vec![
+            TokenKind::Version,
+            TokenKind::Ident,
+            TokenKind::Float,
+            TokenKind::Punct
+        ]
+    );
+
+    // Test the kinds of the lexer tokens.
+    assert_eq!(
+        LexerTokenValue::Integer(integer).kind(),
+        LexerTokenKind::Integer
+    );
+    let kinds: Vec<LexerTokenKind> = Lexer::new("#a")
+        .map(|item| item.unwrap().value.kind())
+        .collect();
+    assert_eq!(
+        kinds,
+        vec![
+            LexerTokenKind::Hash,
+            LexerTokenKind::Ident,
+            LexerTokenKind::NewLine
+        ]
+    );
+}
+
+#[test]
+fn binary_encoding() {
+    // Test that a mix of all kinds of tokens, including all the punctuation, round-trips.
+    let input = "#version 450 core
+                 #extension GL_foo : enable
+                 #pragma optimize(on)
+                 foo 0 1u 2s 3us 4l 0xFFFFFFFFFFFFFFFFul 1.5 1e38 2.0hf 3.0lf
+                 += -= *= /= %= <<= >>= &= ^= |= ++ -- && || ^^ <= >= == != << >>
+                 { } ( ) [ ] < > ; , : . = ! - ~ + * / % | ^ & ?";
+    let tokens: Vec<Token> = Preprocessor::with_options(input, Dialect::Vulkan.options())
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(tokens.len(), 59);
+    let encoded = encode_tokens(&tokens);
+    assert_eq!(decode_tokens(&encoded), Ok(tokens));
+
+    // Test that float bits are kept exactly, even for NaNs, as well as large locations and raw
+    // text.
+    let nan = Token {
+        value: TokenValue::Float(Float {
+            value: f32::from_bits(0x7fc0_1234),
+            width: 32,
+        }),
+        location: Location {
+            line: u32::MAX,
+            pos: 1 << 20,
+        },
+        raw: Some("nan".into()),
+        invocation: None,
+    };
+    let location = nan.location;
+    let decoded = decode_tokens(&encode_tokens(&[nan])).unwrap();
+    match &decoded[0].value {
+        TokenValue::Float(float) => assert_eq!(float.value.to_bits(), 0x7fc0_1234),
+        _ => unreachable!(),
+    }
+    assert_eq!(decoded[0].location, location);
+    assert_eq!(decoded[0].raw, Some("nan".into()));
+    assert_eq!(decode_tokens(&encode_tokens(&[])), Ok(vec![]));
+}
+
+#[test]
+fn binary_decoding_errors() {
+    let tokens = vec![
+        Token {
+            value: TokenValue::Ident("foo".into()),
+            location: Location { line: 1, pos: 0 },
+            raw: None,
+            invocation: None,
+        },
+        Token {
+            value: TokenValue::Punct(Punct::Question),
+            location: Location { line: 1, pos: 4 },
+            raw: None,
+            invocation: None,
+        },
+    ];
+    let encoded = encode_tokens(&tokens);
+
+    // Test that all truncations of the input are errors.
+    for length in 0..encoded.len() {
+        assert_eq!(
+            decode_tokens(&encoded[..length]),
+            Err(DecodeError::UnexpectedEnd)
+        );
+    }
+
+    // Test trailing bytes, invalid tags and invalid punctuation.
+    let mut trailing = encoded.clone();
+    trailing.push(0);
+    assert_eq!(decode_tokens(&trailing), Err(DecodeError::TrailingBytes));
+    assert_eq!(
+        decode_tokens(&[1, 0, 0, 42]),
+        Err(DecodeError::InvalidTag(42))
+    );
+    assert_eq!(
+        decode_tokens(&[1, 0, 0, 3, 200]),
+        Err(DecodeError::InvalidTag(200))
+    );
+
+    // Test invalid UTF-8 and varints that are too large.
+    assert_eq!(
+        decode_tokens(&[1, 0, 0, 0, 1, 0xff]),
+        Err(DecodeError::InvalidUtf8)
+    );
+    assert_eq!(
+        decode_tokens(&[1, 0x80, 0x80, 0x80, 0x80, 0x10, 0, 3, 0]),
+        Err(DecodeError::Overflow)
+    );
+
+    // Test that directives nested in directives are errors, even when deeply nested.
+    let pragma_count = 100_000;
+    let mut nested = Vec::new();
+    for _ in 0..pragma_count {
+        // One token at 0:0 that is a pragma.
+        nested.extend_from_slice(&[1, 0, 0, 6]);
+    }
+    // The empty token list of the innermost pragma, then a zero for each remaining field.
+    nested.resize(nested.len() + 1 + pragma_count, 0);
+    assert_eq!(decode_tokens(&nested), Err(DecodeError::NestedDirective));
+    assert_eq!(
+        decode_tokens(&[1, 0, 0, 6, 1, 0, 0, 6, 0, 0, 0]),
+        Err(DecodeError::NestedDirective)
+    );
+}
+
+#[test]
+fn token_diff() {
+    let tokens = |input| -> Vec<Token> { Preprocessor::new(input).map(Result::unwrap).collect() };
+
+    // Test that changing a single identifier gives a diff of just that token, ignoring the
+    // locations that changed after it.
+    let old = tokens("float a = b + c;\nvoid main() {}");
+    let new = tokens("float a = longer_name + c;\nvoid main() {}");
+    let diff = diff_tokens(&old, &new);
+    assert_eq!(diff.common_prefix, 3);
+    assert_eq!(diff.common_suffix, 9);
+    assert_eq!(diff.old_changed, 3..4);
+    assert_eq!(diff.new_changed, 3..4);
+
+    // Test insertions, deletions and equal streams.
+    let diff = diff_tokens(&tokens("a b"), &tokens("a x y b"));
+    assert_eq!((diff.old_changed, diff.new_changed), (1..1, 1..3));
+    let diff = diff_tokens(&tokens("a a a"), &tokens("a a"));
+    assert_eq!((diff.common_prefix, diff.common_suffix), (2, 0));
+    assert_eq!((diff.old_changed, diff.new_changed), (2..3, 2..2));
+    let diff = diff_tokens(&old, &old);
+    assert_eq!((diff.old_changed, diff.new_changed), (13..13, 13..13));
+}
+
+#[test]
+fn token_by_location() {
+    // Test that sorting shuffled tokens gives them back in source order, lines first.
+    let tokens: Vec<Token> = Preprocessor::new("a b\nc d e\nf")
+        .map(Result::unwrap)
+        .collect();
+    let mut shuffled: Vec<Token> = [3, 5, 0, 4, 1, 2]
+        .iter()
+        .map(|&i| tokens[i].clone())
+        .collect();
+    shuffled.sort_by(Token::by_location);
+    assert_eq!(shuffled, tokens);
+
+    // Test that sorting is stable for tokens at the same location.
+    let at_start = |name: &str| Token {
+        value: TokenValue::Ident(name.into()),
+        location: Location::default(),
+        raw: None,
+        invocation: None,
+    };
+    let mut same = vec![tokens[1].clone(), at_start("x"), at_start("y")];
+    same.sort_by(Token::by_location);
+    assert_eq!(same, vec![at_start("x"), at_start("y"), tokens[1].clone()]);
+}
+
+#[test]
+fn signed_literals() {
+    let tokens = |input| -> Vec<Token> {
+        Preprocessor::with_options(input, Dialect::GlslCore.options())
+            .map(Result::unwrap)
+            .collect()
+    };
+
+    // Test that signs are separate tokens.
+    let minus_one = tokens("-1");
+    assert_eq!(minus_one.len(), 2);
+    assert_eq!(minus_one[0].value, TokenValue::Punct(Punct::Minus));
+    assert_eq!(tokens("+2.0").len(), 2);
+
+    // Test folding the signs.
+    assert_eq!(fold_signed_literal(&minus_one), Some(-1));
+    assert_eq!(fold_signed_literal(&tokens("- -3")), Some(3));
+    assert_eq!(fold_signed_literal(&tokens("+-+4u")), Some(-4));
+    assert_eq!(fold_signed_literal(&tokens("5")), Some(5));
+    assert_eq!(
+        fold_signed_literal(&tokens("-9223372036854775808l")),
+        Some(i64::MIN)
+    );
+
+    // Test that anything else isn't folded.
+    assert_eq!(fold_signed_literal(&tokens("+2.0")), None);
+    assert_eq!(fold_signed_literal(&tokens("9223372036854775808l")), None);
+    assert_eq!(fold_signed_literal(&tokens("!1")), None);
+    assert_eq!(fold_signed_literal(&tokens("-1 2")), None);
+    assert_eq!(fold_signed_literal(&tokens("-")), None);
+    assert_eq!(fold_signed_literal(&[]), None);
+}
+
+#[test]
+fn error_codes() {
+    // Test the codes of a couple of errors.
+    assert_eq!(
+        PreprocessorError::MacroNotDefined.code(),
+        "macro_not_defined"
+    );
+    assert_eq!(
+        PreprocessorError::UnexpectedToken(TokenValue::Punct(Punct::Comma)).code(),
+        "unexpected_token"
+    );
+    assert_eq!(
+        PreprocessorError::NotSupported16BitLiteral.code(),
+        "not_supported_16_bit_literal"
+    );
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn diagnostics_json() {
+    use super::token::diagnostics_to_json;
+
+    // Test the shape of the JSON, with messages that need escaping.
+    let errors = [
+        (
+            PreprocessorError::MacroNotDefined,
+            Location { line: 2, pos: 3 },
+        ),
+        (
+            PreprocessorError::UnexpectedCharacter('"'),
+            Location { line: 4, pos: 0 },
+        ),
+        (
+            PreprocessorError::UnexpectedCharacter('\\'),
+            Location { line: 5, pos: 2 },
+        ),
+    ];
+    assert_eq!(
+        diagnostics_to_json(&errors),
+        concat!(
+            r#"[{"line":2,"column":4,"message":"macro not defined","code":"macro_not_defined"},"#,
+            r#"{"line":4,"column":1,"message":"unexpected character '\"'","#,
+            r#""code":"unexpected_character"},"#,
+            r#"{"line":5,"column":3,"message":"unexpected character '\\\\', a line "#,
+            r#"continuation must be followed by a newline","code":"unexpected_character"}]"#
+        )
+    );
+    assert_eq!(diagnostics_to_json(&[]), "[]");
+}